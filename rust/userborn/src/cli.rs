@@ -0,0 +1,621 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use log::LevelFilter;
+use userborn::{group, passwd, shadow};
+
+const DEFAULT_DIRECTORY: &str = "/etc";
+
+pub const USAGE: &str = "\
+Usage:
+  userborn [OPTIONS] <CONFIG> [DIRECTORY]
+  userborn [OPTIONS] --config-dir <CONFIG_DIR> [DIRECTORY]
+  userborn validate [OPTIONS] [DIRECTORY]
+  userborn export [OPTIONS] [DIRECTORY]
+  userborn schema
+
+Create and update the users and groups from CONFIG in the password databases
+in DIRECTORY (default: /etc). If CONFIG is -, the config is read from stdin.
+
+With --config-dir, all *.json files in CONFIG_DIR are read in lexical order and
+merged. Users and groups in later files override those with the same name in
+earlier files.
+
+The validate command only checks the password databases for inconsistencies
+and doesn't modify them.
+
+The export command prints all users and groups from the password databases as
+JSON in the same format as the config. Password hashes are redacted.
+
+The schema command prints a JSON Schema of the config that editors and CI
+pipelines can use to validate a config.
+
+Options:
+  --config-dir DIR      Read the config from the drop-in files in DIR
+  --root ROOT           Operate on the filesystem tree in ROOT (e.g. a mounted
+                        image). DIRECTORY, the database paths, the ledger and
+                        the nologin binary are resolved inside ROOT
+  --strict              Treat inconsistencies in the password databases and
+                        invalid names in the config as errors
+  --include-secrets     Include password hashes in the export
+  --no-shadow           Don't read or write the shadow and gshadow databases.
+                        Passwords are disabled in the passwd database
+                        instead and accounts are never locked
+  --reconcile           Resolve disagreements between the passwd and the shadow
+                        database before applying the config. passwd decides
+                        which users exist, shadow decides the password hashes
+  --rehash-all          Replace the hashes of plaintext passwords from the config
+                        with hashes with a new salt. Initial passwords are only
+                        rotated if they haven't been changed since
+  --check-shells        Warn about shells of users that don't exist or aren't
+                        executable. They are resolved inside ROOT
+  --dry-run             Report which password databases would be changed
+                        without writing them. Fails if the config requests a
+                        UID or GID that is already held by someone else
+  --check               Only check whether the password databases are up to date
+                        without writing them. Exits with 2 if they would change
+  --detailed-exit-code  Exit with 2 instead of 0 when the password databases
+                        were changed
+  --on-change COMMAND   Run COMMAND via sh -c after the password databases
+                        were changed (e.g. to invalidate the nscd cache)
+  --group PATH          Path to the group database (default: DIRECTORY/group)
+  --passwd PATH         Path to the passwd database (default: DIRECTORY/passwd)
+  --shadow PATH         Path to the shadow database (default: DIRECTORY/shadow)
+  --gshadow PATH        Path to the gshadow database
+                        (default: DIRECTORY/gshadow)
+  --subuid PATH         Path to the subordinate UID database
+                        (default: DIRECTORY/subuid)
+  --subgid PATH         Path to the subordinate GID database
+                        (default: DIRECTORY/subgid)
+  --group-mode MODE     Octal mode of a newly created group database
+                        (default: 0644)
+  --passwd-mode MODE    Octal mode of a newly created passwd database
+                        (default: 0644)
+  --shadow-mode MODE    Octal mode of a newly created shadow database
+                        (default: 0000, at most 0640)
+  -v, --verbose         Log more details (can be repeated)
+  -q, --quiet           Log less details (can be repeated)
+  -h, --help            Print this help
+
+Exit codes:
+  0  Success. With --detailed-exit-code: no changes were made
+  1  Failure
+  2  With --detailed-exit-code: success and the password databases were changed
+     (or would be changed with --dry-run). With --check: they would be changed
+
+The log level can also be set via the RUST_LOG environment variable. It
+overrides --verbose and --quiet.
+";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Create and update users and groups from a config.
+    Apply { config: String },
+    /// Create and update users and groups from a directory of drop-in configs.
+    ApplyDir { config_dir: String },
+    /// Check the databases for inconsistencies without modifying them.
+    Validate,
+    /// Print the users and groups from the databases as JSON.
+    Export,
+    /// Print the JSON Schema of the config.
+    Schema,
+    /// Print the usage.
+    Help,
+}
+
+/// Command line arguments.
+#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Args {
+    pub command: Command,
+    /// The root of the filesystem tree all paths are resolved in.
+    pub root: Option<String>,
+    /// The directory containing the password databases.
+    pub directory: String,
+    /// Path to the group database overriding the one in `directory`.
+    pub group: Option<String>,
+    /// Path to the passwd database overriding the one in `directory`.
+    pub passwd: Option<String>,
+    /// Path to the shadow database overriding the one in `directory`.
+    pub shadow: Option<String>,
+    /// Path to the gshadow database overriding the one in `directory`.
+    pub gshadow: Option<String>,
+    /// Path to the subordinate UID database overriding the one in `directory`.
+    pub subuid: Option<String>,
+    /// Path to the subordinate GID database overriding the one in `directory`.
+    pub subgid: Option<String>,
+    /// Mode of a newly created group database.
+    pub group_mode: u32,
+    /// Mode of a newly created passwd database.
+    pub passwd_mode: u32,
+    /// Mode of a newly created shadow database.
+    pub shadow_mode: u32,
+    /// Whether inconsistencies in the password databases are treated as errors.
+    pub strict: bool,
+    /// Whether to include password hashes in the export.
+    pub include_secrets: bool,
+    /// Whether the shadow database is not used at all.
+    pub no_shadow: bool,
+    /// Whether to resolve disagreements between the passwd and the shadow database.
+    pub reconcile: bool,
+    /// Whether to re-hash all plaintext passwords with a new salt.
+    pub rehash_all: bool,
+    /// Whether to warn about shells of users that don't exist.
+    pub check_shells: bool,
+    /// Whether to only report changes instead of writing the databases.
+    pub dry_run: bool,
+    /// Whether to only check if the databases would change.
+    pub check: bool,
+    /// Whether to signal changes to the databases via the exit code.
+    pub detailed_exit_code: bool,
+    /// Command that is run after the databases were changed.
+    pub on_change: Option<String>,
+    /// How much the log level is raised (positive) or lowered (negative) from `Info`.
+    pub verbosity: i8,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            command: Command::Help,
+            root: None,
+            directory: DEFAULT_DIRECTORY.into(),
+            group: None,
+            passwd: None,
+            shadow: None,
+            gshadow: None,
+            subuid: None,
+            subgid: None,
+            group_mode: group::DEFAULT_MODE,
+            passwd_mode: passwd::DEFAULT_MODE,
+            shadow_mode: shadow::DEFAULT_MODE,
+            strict: false,
+            include_secrets: false,
+            no_shadow: false,
+            reconcile: false,
+            rehash_all: false,
+            check_shells: false,
+            dry_run: false,
+            check: false,
+            detailed_exit_code: false,
+            on_change: None,
+            verbosity: 0,
+        }
+    }
+}
+
+impl Args {
+    /// Parse the command line arguments, excluding the name of the binary.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut positional = Vec::new();
+        let mut config_dir = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config-dir" => config_dir = Some(value(&arg, &mut args)?),
+                "--root" => parsed.root = Some(value(&arg, &mut args)?),
+                "--strict" => parsed.strict = true,
+                "--include-secrets" => parsed.include_secrets = true,
+                "--no-shadow" => parsed.no_shadow = true,
+                "--reconcile" => parsed.reconcile = true,
+                "--rehash-all" => parsed.rehash_all = true,
+                "--check-shells" => parsed.check_shells = true,
+                "--dry-run" => parsed.dry_run = true,
+                "--check" => parsed.check = true,
+                "--detailed-exit-code" => parsed.detailed_exit_code = true,
+                "--on-change" => parsed.on_change = Some(value(&arg, &mut args)?),
+                "--group" => parsed.group = Some(value(&arg, &mut args)?),
+                "--passwd" => parsed.passwd = Some(value(&arg, &mut args)?),
+                "--shadow" => parsed.shadow = Some(value(&arg, &mut args)?),
+                "--gshadow" => parsed.gshadow = Some(value(&arg, &mut args)?),
+                "--subuid" => parsed.subuid = Some(value(&arg, &mut args)?),
+                "--subgid" => parsed.subgid = Some(value(&arg, &mut args)?),
+                "--group-mode" => parsed.group_mode = mode(&arg, &mut args, 0o777)?,
+                "--passwd-mode" => parsed.passwd_mode = mode(&arg, &mut args, 0o777)?,
+                "--shadow-mode" => parsed.shadow_mode = mode(&arg, &mut args, shadow::MAX_MODE)?,
+                "--verbose" => parsed.verbosity = parsed.verbosity.saturating_add(1),
+                "--quiet" => parsed.verbosity = parsed.verbosity.saturating_sub(1),
+                // Allow combining short flags, e.g. `-vv`.
+                s if s.len() > 1 && s.starts_with('-') && s[1..].chars().all(|c| c == 'v') => {
+                    parsed.verbosity = parsed.verbosity.saturating_add(i8::try_from(s.len() - 1)?);
+                }
+                s if s.len() > 1 && s.starts_with('-') && s[1..].chars().all(|c| c == 'q') => {
+                    parsed.verbosity = parsed.verbosity.saturating_sub(i8::try_from(s.len() - 1)?);
+                }
+                "-h" | "--help" => return Ok(parsed),
+                // A single dash means reading the config from stdin.
+                "-" => positional.push(arg),
+                s if s.starts_with('-') => bail!("Unknown option {s}"),
+                _ => positional.push(arg),
+            }
+        }
+
+        if parsed.reconcile && parsed.no_shadow {
+            bail!("--reconcile cannot be combined with --no-shadow");
+        }
+        if parsed.rehash_all && parsed.no_shadow {
+            bail!("--rehash-all cannot be combined with --no-shadow");
+        }
+
+        let mut positional = positional.into_iter().peekable();
+        parsed.command = if let Some(config_dir) = config_dir {
+            if let Some(command @ ("validate" | "export" | "schema")) =
+                positional.peek().map(String::as_str)
+            {
+                bail!("The {command} command doesn't take --config-dir");
+            }
+            Command::ApplyDir { config_dir }
+        } else {
+            match positional.next().as_deref() {
+                Some("validate") => Command::Validate,
+                Some("export") => Command::Export,
+                Some("schema") => Command::Schema,
+                Some(config) => Command::Apply {
+                    config: config.into(),
+                },
+                None => return Err(anyhow!("No config provided")),
+            }
+        };
+        if parsed.command != Command::Schema {
+            if let Some(directory) = positional.next() {
+                parsed.directory = directory;
+            }
+        }
+
+        if let Some(arg) = positional.next() {
+            bail!("Unexpected argument {arg}");
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn group_path(&self) -> String {
+        self.database_path(self.group.as_ref(), "group")
+    }
+
+    pub fn passwd_path(&self) -> String {
+        self.database_path(self.passwd.as_ref(), "passwd")
+    }
+
+    pub fn shadow_path(&self) -> String {
+        self.database_path(self.shadow.as_ref(), "shadow")
+    }
+
+    pub fn gshadow_path(&self) -> String {
+        self.database_path(self.gshadow.as_ref(), "gshadow")
+    }
+
+    pub fn subuid_path(&self) -> String {
+        self.database_path(self.subuid.as_ref(), "subuid")
+    }
+
+    pub fn subgid_path(&self) -> String {
+        self.database_path(self.subgid.as_ref(), "subgid")
+    }
+
+    /// The explicitly provided path of a database or its default path inside `directory`.
+    fn database_path(&self, path: Option<&String>, name: &str) -> String {
+        let path = path
+            .cloned()
+            .unwrap_or_else(|| format!("{}/{name}", self.directory));
+        self.rooted(&path)
+    }
+
+    /// Resolve a path inside `root`.
+    ///
+    /// Without a root, the path is returned unchanged.
+    pub fn rooted(&self, path: &str) -> String {
+        match &self.root {
+            Some(root) => Path::new(root)
+                .join(path.trim_start_matches('/'))
+                .to_string_lossy()
+                .into_owned(),
+            None => path.into(),
+        }
+    }
+
+    /// The log level selected via `--verbose` and `--quiet`.
+    ///
+    /// With `--check`, only warnings and errors are logged by default because the result is
+    /// printed as a summary.
+    pub fn log_level(&self) -> LevelFilter {
+        match self.verbosity.saturating_sub(i8::from(self.check)) {
+            i8::MIN..=-2 => LevelFilter::Error,
+            -1 => LevelFilter::Warn,
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            2..=i8::MAX => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Take the value of an option from the next argument.
+fn value(option: &str, args: &mut impl Iterator<Item = String>) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("Option {option} requires a value"))
+}
+
+/// Take an octal file mode (e.g. `0640`) from the next argument.
+///
+/// Only permission bits are allowed. The password databases must not be writable by anyone but
+/// their owner. Modes with permissions beyond `max` (e.g. a world-readable shadow database) are
+/// rejected as well.
+fn mode(option: &str, args: &mut impl Iterator<Item = String>, max: u32) -> Result<u32> {
+    let value = value(option, args)?;
+    let mode = u32::from_str_radix(&value, 8)
+        .ok()
+        .filter(|_| !value.starts_with('+'))
+        .ok_or_else(|| anyhow!("Option {option} requires an octal mode, got {value}"))?;
+    if mode & !0o777 != 0 {
+        bail!("Option {option} only accepts permission bits (at most 0777), got {value}");
+    }
+    if mode & 0o022 != 0 {
+        bail!("Option {option} doesn't accept a mode writable by group or others, got {value}");
+    }
+    if mode & !max != 0 {
+        bail!("Option {option} doesn't accept a mode more permissive than {max:04o}, got {value}");
+    }
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    #[allow(clippy::too_many_lines)]
+    fn parse_args() -> Result<()> {
+        assert_eq!(
+            parse(&["config.json"])?,
+            Args {
+                command: Command::Apply {
+                    config: "config.json".into()
+                },
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            parse(&[
+                "--strict",
+                "config.json",
+                "/tmp/etc",
+                "--detailed-exit-code"
+            ])?,
+            Args {
+                command: Command::Apply {
+                    config: "config.json".into()
+                },
+                directory: "/tmp/etc".into(),
+                strict: true,
+                detailed_exit_code: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            parse(&["validate", "/tmp/etc"])?,
+            Args {
+                command: Command::Validate,
+                directory: "/tmp/etc".into(),
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            parse(&["schema"])?,
+            Args {
+                command: Command::Schema,
+                ..Args::default()
+            }
+        );
+        assert!(parse(&["schema", "/etc"]).is_err());
+        assert_eq!(
+            parse(&["export", "--include-secrets"])?,
+            Args {
+                command: Command::Export,
+                include_secrets: true,
+                ..Args::default()
+            }
+        );
+        assert!(parse(&["--reconcile", "--no-shadow", "config.json"]).is_err());
+        assert!(parse(&["--rehash-all", "--no-shadow", "config.json"]).is_err());
+        assert_eq!(
+            parse(&["--check-shells", "config.json"])?,
+            Args {
+                command: Command::Apply {
+                    config: "config.json".into()
+                },
+                check_shells: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            parse(&["--dry-run", "config.json"])?,
+            Args {
+                command: Command::Apply {
+                    config: "config.json".into()
+                },
+                dry_run: true,
+                ..Args::default()
+            }
+        );
+        let args = parse(&["--check", "config.json"])?;
+        assert!(args.check);
+        assert_eq!(args.log_level(), LevelFilter::Warn);
+        assert_eq!(
+            parse(&["--no-shadow", "config.json"])?,
+            Args {
+                command: Command::Apply {
+                    config: "config.json".into()
+                },
+                no_shadow: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            parse(&["--config-dir", "/etc/userborn.d", "/tmp/etc"])?,
+            Args {
+                command: Command::ApplyDir {
+                    config_dir: "/etc/userborn.d".into()
+                },
+                directory: "/tmp/etc".into(),
+                ..Args::default()
+            }
+        );
+        assert!(parse(&["validate", "--config-dir", "/etc/userborn.d"]).is_err());
+        assert_eq!(
+            parse(&["--on-change", "nscd -i passwd", "config.json"])?.on_change,
+            Some("nscd -i passwd".into())
+        );
+        assert!(parse(&[]).is_err());
+        assert_eq!(
+            parse(&["-", "/tmp/etc"])?,
+            Args {
+                command: Command::Apply { config: "-".into() },
+                directory: "/tmp/etc".into(),
+                ..Args::default()
+            }
+        );
+        assert!(parse(&["--unknown", "config.json"]).is_err());
+        assert!(parse(&["config.json", "/etc", "extra"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn modes() -> Result<()> {
+        let args = parse(&["config.json"])?;
+        assert_eq!(
+            (args.group_mode, args.passwd_mode, args.shadow_mode),
+            (0o644, 0o644, 0o000)
+        );
+
+        let args = parse(&[
+            "--group-mode",
+            "0640",
+            "--passwd-mode",
+            "600",
+            "--shadow-mode",
+            "0400",
+            "config.json",
+        ])?;
+        assert_eq!(
+            (args.group_mode, args.passwd_mode, args.shadow_mode),
+            (0o640, 0o600, 0o400)
+        );
+
+        for invalid in [
+            "",
+            "+644",
+            "0o644",
+            "0648",
+            "rw-r--r--",
+            "4755",
+            "0666",
+            "0620",
+        ] {
+            assert!(
+                parse(&["--passwd-mode", invalid, "config.json"]).is_err(),
+                "{invalid}"
+            );
+        }
+        assert!(parse(&["config.json", "--shadow-mode"]).is_err());
+        // The shadow database must never be readable by others.
+        for invalid in ["0644", "0604", "0700"] {
+            assert!(
+                parse(&["--shadow-mode", invalid, "config.json"]).is_err(),
+                "{invalid}"
+            );
+        }
+        assert_eq!(
+            parse(&["--shadow-mode", "0640", "config.json"])?.shadow_mode,
+            0o640
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn database_paths() -> Result<()> {
+        let args = parse(&["config.json", "/tmp/etc"])?;
+        assert_eq!(args.group_path(), "/tmp/etc/group");
+        assert_eq!(args.passwd_path(), "/tmp/etc/passwd");
+        assert_eq!(args.shadow_path(), "/tmp/etc/shadow");
+        assert_eq!(args.gshadow_path(), "/tmp/etc/gshadow");
+        assert_eq!(args.subuid_path(), "/tmp/etc/subuid");
+        assert_eq!(args.subgid_path(), "/tmp/etc/subgid");
+
+        let args = parse(&[
+            "--passwd",
+            "/tmp/passwd",
+            "config.json",
+            "--shadow",
+            "/secrets/shadow",
+            "--subgid",
+            "/tmp/subgid",
+        ])?;
+        assert_eq!(args.group_path(), "/etc/group");
+        assert_eq!(args.passwd_path(), "/tmp/passwd");
+        assert_eq!(args.shadow_path(), "/secrets/shadow");
+        assert_eq!(args.gshadow_path(), "/etc/gshadow");
+        assert_eq!(args.subuid_path(), "/etc/subuid");
+        assert_eq!(args.subgid_path(), "/tmp/subgid");
+
+        assert!(parse(&["config.json", "--group"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn root() -> Result<()> {
+        let args = parse(&["--root", "/mnt", "config.json"])?;
+        assert_eq!(args.group_path(), "/mnt/etc/group");
+        assert_eq!(args.rooted("/var/lib/userborn"), "/mnt/var/lib/userborn");
+
+        let args = parse(&[
+            "--root",
+            "/mnt/",
+            "--shadow",
+            "/secrets/shadow",
+            "config.json",
+            "/image/etc",
+        ])?;
+        assert_eq!(args.passwd_path(), "/mnt/image/etc/passwd");
+        assert_eq!(args.shadow_path(), "/mnt/secrets/shadow");
+
+        let args = parse(&["config.json", "etc"])?;
+        assert_eq!(args.passwd_path(), "etc/passwd");
+        Ok(())
+    }
+
+    #[test]
+    fn log_level() -> Result<()> {
+        assert_eq!(parse(&["config.json"])?.log_level(), LevelFilter::Info);
+        assert_eq!(
+            parse(&["-v", "config.json"])?.log_level(),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            parse(&["-vv", "config.json"])?.log_level(),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            parse(&["--verbose", "-v", "-v", "config.json"])?.log_level(),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            parse(&["--quiet", "config.json"])?.log_level(),
+            LevelFilter::Warn
+        );
+        assert_eq!(
+            parse(&["-qq", "config.json"])?.log_level(),
+            LevelFilter::Error
+        );
+        assert_eq!(
+            parse(&["-v", "-q", "config.json"])?.log_level(),
+            LevelFilter::Info
+        );
+        Ok(())
+    }
+}
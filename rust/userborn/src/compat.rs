@@ -0,0 +1,124 @@
+//! NIS compat lines in /etc/passwd and /etc/group.
+//!
+//! With `compat` in nsswitch.conf(5), lines like `+`, `+@netgroup` or `-user` include or exclude
+//! entries from a directory service. They are not entries themselves, so they are passed through
+//! unchanged at their original position relative to the entries around them.
+
+/// Where a compat line is written relative to the entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Position {
+    /// Before all entries.
+    Start,
+    /// After the entry with this name.
+    After(String),
+    /// After all entries.
+    End,
+}
+
+/// The compat lines of a database.
+#[derive(Debug, Default)]
+pub struct CompatLines {
+    lines: Vec<(Position, String)>,
+    /// Lines after the last entry read so far and the name of that entry.
+    pending: Vec<String>,
+    previous: Option<String>,
+}
+
+impl CompatLines {
+    /// Whether a line is a compat line.
+    pub fn is_compat_line(line: &str) -> bool {
+        line.starts_with('+') || line.starts_with('-')
+    }
+
+    /// Record a compat line read after all entries read so far.
+    pub fn push(&mut self, line: &str) {
+        self.pending.push(line.into());
+    }
+
+    /// Record that an entry was read.
+    ///
+    /// Compat lines read before it are anchored to the entry before it.
+    pub fn entry(&mut self, name: &str) {
+        let position = self.previous.as_ref().map_or(Position::Start, |previous| {
+            Position::After(previous.clone())
+        });
+        self.lines
+            .extend(self.pending.drain(..).map(|line| (position.clone(), line)));
+        self.previous = Some(name.into());
+    }
+
+    /// Record that all lines were read.
+    ///
+    /// Compat lines after the last entry stay after all entries, even when new entries are added.
+    pub fn finish(&mut self) {
+        self.lines
+            .extend(self.pending.drain(..).map(|line| (Position::End, line)));
+        self.previous = None;
+    }
+
+    /// Interleave the compat lines with the lines of the entries.
+    ///
+    /// `entries` yields the name of each entry together with its line. Lines anchored to an entry
+    /// that doesn't exist anymore are written after all entries.
+    pub fn interleave<'a>(
+        &'a self,
+        entries: impl IntoIterator<Item = (&'a str, String)>,
+    ) -> impl Iterator<Item = String> + 'a {
+        let lines_at = move |position: Position| {
+            self.lines
+                .iter()
+                .filter(move |(p, _)| *p == position)
+                .map(|(_, line)| line.clone())
+        };
+        let mut names = Vec::new();
+        let entries = entries
+            .into_iter()
+            .flat_map(|(name, line)| {
+                names.push(name);
+                std::iter::once(line).chain(lines_at(Position::After(name.into())))
+            })
+            .collect::<Vec<_>>();
+        let orphans = self
+            .lines
+            .iter()
+            .filter(
+                move |(p, _)| matches!(p, Position::After(name) if !names.contains(&name.as_str())),
+            )
+            .map(|(_, line)| line.clone());
+        lines_at(Position::Start)
+            .chain(entries)
+            .chain(orphans)
+            .chain(lines_at(Position::End))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_position() {
+        let mut compat = CompatLines::default();
+        compat.push("+@admins");
+        compat.entry("root");
+        compat.push("-gary");
+        compat.entry("normalo");
+        compat.push("+");
+        compat.finish();
+
+        let lines = compat
+            .interleave([
+                ("root", "root".to_string()),
+                ("new", "new".to_string()),
+                ("normalo", "normalo".to_string()),
+            ])
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["+@admins", "root", "-gary", "new", "normalo", "+"]);
+
+        // Lines anchored to a removed entry are kept.
+        let lines = compat
+            .interleave([("normalo", "normalo".to_string())])
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["+@admins", "normalo", "-gary", "+"]);
+    }
+}
@@ -1,44 +1,309 @@
-use std::collections::BTreeSet;
-use std::{fs::File, io::Read, path::Path};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
-use serde::Deserialize;
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
 
-#[derive(Deserialize, Debug)]
+use crate::{
+    error::{ConfigOrigin, Error, NameError},
+    id,
+    passwd::Gecos,
+    shadow,
+};
+
+#[derive(Deserialize, JsonSchema, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
+#[allow(clippy::struct_excessive_bools)]
 pub struct User {
     /// Whether the user is a "normal" or a "system" user
     #[serde(default)]
     pub is_normal: bool,
     /// The name of the user
     pub name: String,
+    /// Names the user previously had
+    ///
+    /// If the user doesn't exist yet but exists under one of these names, it is renamed and keeps
+    /// its UID, primary group and password.
+    #[serde(default)]
+    pub previous_names: Vec<String>,
     /// The UID of the user
     pub uid: Option<u32>,
     /// The primary group of the user.
     ///
     /// This can either be the name of the user or the GID.
     pub group: Option<String>,
+    /// The GID of the primary group of the user
+    ///
+    /// This is used when the primary group is created together with the user instead of re-using
+    /// the UID. If the primary group already exists, its GID has to match.
+    pub group_id: Option<u32>,
     /// The description of the user
+    ///
+    /// This is the raw GECOS field and takes precedence over `gecos`. If this is not set, the
+    /// existing description is kept. An empty string clears it.
     pub description: Option<String>,
+    /// The components of the GECOS field of the user
+    ///
+    /// Components that are not set keep their existing value. Empty components are cleared.
+    pub gecos: Option<Gecos>,
     /// The home directory of the user
+    ///
+    /// If this is not set, the existing home directory is kept. An empty string clears it.
     pub home: Option<String>,
     /// The shell of the user
+    ///
+    /// If this is not set, the existing shell is kept. An empty string clears it.
     pub shell: Option<String>,
+    /// Whether the account is locked
+    ///
+    /// A locked user cannot login with a password. The configured password is ignored.
+    #[serde(default)]
+    pub locked: bool,
+    /// Whether the password is replaced with an invalid one
+    ///
+    /// Unlike `locked`, this doesn't depend on `lockMode` and also applies to existing users whose
+    /// password would otherwise be kept. The configured password is ignored.
+    #[serde(default)]
+    pub lock_password: bool,
+    /// Whether an existing user is never updated
+    ///
+    /// The user is created like any other user but its entries in /etc/passwd and /etc/shadow are
+    /// not changed afterwards, even if the config differs. This protects critical accounts (e.g.
+    /// a break-glass admin) from accidental changes.
+    #[serde(default)]
+    pub immutable: bool,
+    /// Whether the user has to change their password at their next login
+    ///
+    /// This only applies when Userborn creates the user or changes their password.
+    #[serde(default)]
+    pub expire_password: bool,
+    /// The aging of the password of the user
+    ///
+    /// Fields that are not set are taken from the top-level `passwordPolicy`.
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    /// Whether the password of the user never ages
+    ///
+    /// This clears all aging fields of the /etc/shadow entry and overrides `passwordPolicy` as
+    /// well as the top-level `passwordPolicy`, e.g. to exempt service accounts from a global
+    /// policy.
+    #[serde(default)]
+    pub disable_password_aging: bool,
+    /// The reserved (last) field of the /etc/shadow entry of the user
+    ///
+    /// This is only set when Userborn creates the entry. The field of an existing entry is
+    /// preserved.
+    pub shadow_reserved: Option<String>,
+    /// Subordinate UIDs of the user in /etc/subuid
+    #[serde(default)]
+    pub sub_uid_ranges: Vec<SubIdRange>,
+    /// Subordinate GIDs of the user in /etc/subgid
+    #[serde(default)]
+    pub sub_gid_ranges: Vec<SubIdRange>,
+    /// The number of subordinate UIDs and GIDs to allocate automatically
+    ///
+    /// This is only done if the user doesn't have any subordinate IDs yet. 65536 is enough for a
+    /// full user namespace.
+    pub auto_sub_id_count: Option<u32>,
     #[serde(flatten)]
     pub password: Password,
 }
 
-#[derive(Deserialize, Debug)]
+/// A range of subordinate UIDs or GIDs.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubIdRange {
+    /// The first ID of the range
+    pub start: u32,
+    /// The number of IDs in the range
+    pub count: u32,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Password {
     pub password: Option<String>,
     pub hashed_password: Option<String>,
+    /// Path to a file containing the hashed password
+    ///
+    /// Environment variables like `$RUNTIME_DIRECTORY` or `${RUNTIME_DIRECTORY}` are expanded. A
+    /// literal `$` is written as `$$`.
     pub hashed_password_file: Option<String>,
+    /// Name of a systemd credential containing the hashed password
+    pub hashed_password_credential: Option<String>,
     pub initial_password: Option<String>,
     pub initial_hashed_password: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+impl Password {
+    /// The plaintext password that the hash of the user is derived from.
+    ///
+    /// This is `password` or `initialPassword` unless a password with a higher precedence is set.
+    pub fn plaintext(&self) -> Option<&str> {
+        if self.hashed_password_file.is_some()
+            || self.hashed_password_credential.is_some()
+            || self.hashed_password.is_some()
+        {
+            None
+        } else if let Some(password) = &self.password {
+            Some(password)
+        } else if self.initial_hashed_password.is_some() {
+            None
+        } else {
+            self.initial_password.as_deref()
+        }
+    }
+
+    /// Whether any kind of password is configured.
+    pub fn is_set(&self) -> bool {
+        self.password.is_some()
+            || self.hashed_password.is_some()
+            || self.hashed_password_file.is_some()
+            || self.hashed_password_credential.is_some()
+            || self.initial_password.is_some()
+            || self.initial_hashed_password.is_some()
+    }
+}
+
+/// The aging of a password as stored in /etc/shadow.
+///
+/// Fields that are not set keep their existing value.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordPolicy {
+    /// The minimum number of days between password changes
+    pub min_days: Option<u32>,
+    /// The maximum number of days a password is valid
+    pub max_days: Option<u32>,
+    /// The number of days before the password expires during which the user is warned
+    pub warn_days: Option<u32>,
+    /// The number of days after the password expired during which it is still accepted
+    pub inactive_days: Option<u32>,
+}
+
+impl PasswordPolicy {
+    /// Use the fields of `fallback` for all fields that are not set.
+    #[must_use]
+    pub fn or(&self, fallback: &Self) -> Self {
+        Self {
+            min_days: self.min_days.or(fallback.min_days),
+            max_days: self.max_days.or(fallback.max_days),
+            warn_days: self.warn_days.or(fallback.warn_days),
+            inactive_days: self.inactive_days.or(fallback.inactive_days),
+        }
+    }
+
+    /// Whether any field of the policy is set.
+    pub fn is_set(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+/// How an account is locked.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LockMode {
+    /// Replace the password with an invalid one
+    ///
+    /// The hashed password is lost and has to be provided again to unlock the account.
+    #[default]
+    Replace,
+    /// Prefix the hashed password with `!` like `usermod -L`
+    ///
+    /// The hashed password is kept and restored when the account is unlocked.
+    Prefix,
+    /// Set the account expiration date to the past
+    ///
+    /// The hashed password is kept. The account is unlocked by clearing the expiration date.
+    Expire,
+}
+
+/// What happens if the primary group of a user is a GID that no group has.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MissingPrimaryGroup {
+    /// Warn about it but use the GID anyway
+    #[default]
+    Warn,
+    /// Fail to create or update the user
+    Error,
+    /// Create a placeholder group with the GID and the name of the user
+    Create,
+}
+
+/// The password field in /etc/passwd of accounts that aren't locked on a system without
+/// /etc/shadow.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PasswdMarker {
+    /// `x`, which usually points to /etc/shadow
+    #[default]
+    #[serde(rename = "x")]
+    Shadow,
+    /// `*`, which some minimal environments expect when there is no /etc/shadow
+    #[serde(rename = "*")]
+    Asterisk,
+    /// `!`
+    #[serde(rename = "!")]
+    Exclamation,
+}
+
+impl PasswdMarker {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Shadow => "x",
+            Self::Asterisk => "*",
+            Self::Exclamation => "!",
+        }
+    }
+}
+
+/// The order of the entries in /etc/passwd and /etc/group.
+///
+/// /etc/shadow and /etc/gshadow follow the order of /etc/passwd and /etc/group respectively.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    /// Sort users by UID and groups by GID
+    #[default]
+    ByUid,
+    /// Sort users and groups by name
+    ///
+    /// This gives more readable files and stabler diffs when the IDs are sparse.
+    ByName,
+}
+
+/// The order of the members of a group in /etc/group and /etc/gshadow.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MemberOrder {
+    /// Sort the members by name
+    #[default]
+    Sorted,
+    /// Keep the members in the order they are declared in
+    ///
+    /// Members that are not declared in the config keep the order from the database.
+    Declared,
+}
+
+/// How the members of an existing group are updated.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum GroupMemberMode {
+    /// Replace the members with the ones from the config
+    #[default]
+    Replace,
+    /// Add the members from the config to the existing members
+    ///
+    /// Members that were added outside of the config (e.g. by a directory sync) are kept.
+    Merge,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, PartialEq, Eq)]
 pub struct Group {
     /// Whether the group is a "normal" or a "system" group
     #[serde(default)]
@@ -48,33 +313,793 @@ pub struct Group {
     /// The GID of the users primary group
     pub gid: Option<u32>,
     /// The members of this group
-    #[serde(default)]
-    pub members: BTreeSet<String>,
+    ///
+    /// This is either a list or a single string in which the members are separated by commas
+    /// and/or whitespace (e.g. `"alice, bob"`). Duplicates are removed. The order is only kept with
+    /// `memberOrder` set to `declared`.
+    #[serde(default, deserialize_with = "deserialize_members")]
+    #[schemars(with = "Members")]
+    pub members: Vec<String>,
+    /// Whether the members of all declarations of this group are merged
+    ///
+    /// Without this, a later declaration (e.g. from another drop-in file) replaces the members of
+    /// an earlier one.
+    #[serde(default, rename = "mergeMembers")]
+    pub merge_members: bool,
+    /// The administrators of this group
+    ///
+    /// If this is not set, the existing administrators are kept.
+    pub administrators: Option<BTreeSet<String>>,
+    /// The plaintext password of this group for `newgrp(1)`
+    ///
+    /// This is insecure and should only be used for testing purposes.
+    pub password: Option<String>,
+    /// The hashed password of this group for `newgrp(1)`
+    ///
+    /// This takes precedence over `password`. If neither is set, the existing password is kept.
+    #[serde(rename = "hashedPassword")]
+    pub hashed_password: Option<String>,
+}
+
+/// The members of a group as they can be written in the config.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Members {
+    List(Vec<String>),
+    Delimited(String),
+}
+
+/// Deserialize the members of a group from either a list or a delimited string.
+fn deserialize_members<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    Ok(match Members::deserialize(deserializer)? {
+        Members::List(members) => members,
+        Members::Delimited(members) => split_members(&members).map(String::from).collect(),
+    })
+}
+
+/// Split members that are separated by commas and/or whitespace.
+fn split_members(members: &str) -> impl Iterator<Item = &str> {
+    members
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|member| !member.is_empty())
+}
+
+/// An ID that must never be allocated automatically.
+///
+/// This can either be a single ID or an inclusive range of IDs written as `[start, end]`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ReservedId {
+    Single(u32),
+    Range(u32, u32),
+}
+
+impl ReservedId {
+    /// Whether the ID is covered by this reservation.
+    pub fn contains(&self, id: u32) -> bool {
+        match *self {
+            Self::Single(reserved) => reserved == id,
+            Self::Range(start, end) => (start..=end).contains(&id),
+        }
+    }
+}
+
+/// The order in which the IDs of a range are tried when allocating a new ID.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AllocationDirection {
+    /// From the start of the range to its end
+    Ascending,
+    /// From the end of the range to its start
+    Descending,
+}
+
+impl fmt::Display for AllocationDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ascending => write!(f, "ascending"),
+            Self::Descending => write!(f, "descending"),
+        }
+    }
+}
+
+/// The allocation direction of the system and the normal range.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AllocationDirections {
+    /// Defaults to descending, i.e. 999, 998, ...
+    pub system: AllocationDirection,
+    /// Defaults to ascending, i.e. 1000, 1001, ...
+    pub normal: AllocationDirection,
 }
 
-#[derive(Deserialize, Debug)]
+impl Default for AllocationDirections {
+    fn default() -> Self {
+        Self {
+            system: AllocationDirection::Descending,
+            normal: AllocationDirection::Ascending,
+        }
+    }
+}
+
+impl AllocationDirections {
+    /// The direction of the normal or the system range.
+    pub fn get(&self, is_normal: bool) -> AllocationDirection {
+        if is_normal {
+            self.normal
+        } else {
+            self.system
+        }
+    }
+}
+
+/// The users and groups that differ between two configs, identified by their names.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub added_users: BTreeSet<String>,
+    pub removed_users: BTreeSet<String>,
+    pub modified_users: BTreeSet<String>,
+    pub added_groups: BTreeSet<String>,
+    pub removed_groups: BTreeSet<String>,
+    pub modified_groups: BTreeSet<String>,
+}
+
+impl ConfigDiff {
+    /// Whether the configs declare the same users and groups.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The names of the added, removed and modified items between `old` and `new`.
+fn diff_by_name<'a, T: PartialEq>(
+    old: &'a [T],
+    new: &'a [T],
+    name: impl Fn(&T) -> &str,
+) -> (BTreeSet<String>, BTreeSet<String>, BTreeSet<String>) {
+    let old = old.iter().map(|i| (name(i), i)).collect::<BTreeMap<_, _>>();
+    let new = new.iter().map(|i| (name(i), i)).collect::<BTreeMap<_, _>>();
+
+    let added = new
+        .keys()
+        .filter(|n| !old.contains_key(*n))
+        .map(|n| (*n).to_string())
+        .collect();
+    let removed = old
+        .keys()
+        .filter(|n| !new.contains_key(*n))
+        .map(|n| (*n).to_string())
+        .collect();
+    let modified = new
+        .iter()
+        .filter(|(n, item)| old.get(*n).is_some_and(|old_item| old_item != *item))
+        .map(|(n, _)| (*n).to_string())
+        .collect();
+    (added, removed, modified)
+}
+
+/// The config path that denotes reading the config from stdin.
+const STDIN_PATH: &str = "-";
+/// The first bytes of a gzip-compressed file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Deserialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
     #[serde(default)]
     pub users: Vec<User>,
     #[serde(default)]
     pub groups: Vec<Group>,
+    /// UIDs and GIDs that are skipped when allocating new IDs
+    ///
+    /// Defaults to the ID of `nobody` (65534).
+    #[serde(default = "default_reserved_ids")]
+    pub reserved_ids: Vec<ReservedId>,
+    /// The order in which IDs are allocated in the system and the normal range
+    #[serde(default)]
+    pub allocation_directions: AllocationDirections,
+    /// The lowest ID that is allocated automatically to system users and groups
+    ///
+    /// This leaves headroom below it, e.g. for static IDs. It must be in the system range (1 to
+    /// 999).
+    pub system_id_floor: Option<u32>,
+    /// The highest ID that is allocated automatically to normal users and groups
+    ///
+    /// This leaves headroom above it. It must be in the normal range (1000 to 29999).
+    pub normal_id_ceiling: Option<u32>,
+    /// Path to the ledger recording previously assigned UIDs and GIDs
+    ///
+    /// If this is not set, no ledger is used.
+    pub ledger_path: Option<String>,
+    /// The shell of newly created users that don't specify one
+    ///
+    /// If this is not set, the nologin binary is used.
+    pub default_shell: Option<String>,
+    /// The aging of the passwords of all users in the config
+    ///
+    /// Users can override individual fields via their own `passwordPolicy`.
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    /// Whether the group that is created for a user without a primary group re-uses the UID as GID
+    ///
+    /// If this is disabled or the GID is already in use, a new GID is allocated. This has no
+    /// effect on users with an explicit primary group.
+    #[serde(default = "default_true")]
+    pub user_group_reuses_uid: bool,
+    /// How accounts are locked
+    ///
+    /// With `prefix` and `expire`, accounts that are not locked in the config anymore are unlocked
+    /// again.
+    #[serde(default)]
+    pub lock_mode: LockMode,
+    /// What happens if the primary group of a user is a GID that no group has
+    #[serde(default)]
+    pub missing_primary_group: MissingPrimaryGroup,
+    /// The order of the entries in the password databases
+    #[serde(default)]
+    pub sort_order: SortOrder,
+    /// The order of the members of a group
+    #[serde(default)]
+    pub member_order: MemberOrder,
+    /// How the members of an existing group are updated
+    #[serde(default)]
+    pub group_member_mode: GroupMemberMode,
+    /// Whether locking an account that is not in the config anymore also sets its shell to nologin
+    ///
+    /// Root is never locked and thus keeps its shell. The shell is not restored when the user
+    /// returns to the config unless `shell` is set.
+    #[serde(default)]
+    pub lock_sets_nologin: bool,
+    /// Whether plaintext passwords are hashed again if their hash uses an insecure scheme
+    ///
+    /// Usually, the existing hash is kept if the password hasn't changed. With this, a hash using
+    /// an insecure scheme (e.g. sha512crypt) is replaced by a yescrypt hash of the same password.
+    #[serde(default)]
+    pub rehash_insecure: bool,
+    /// The prefixes of the password hashing schemes that are deemed secure (e.g. "y" for yescrypt)
+    ///
+    /// Userborn warns about passwords hashed with other schemes. If this is not set, yescrypt,
+    /// gost-yescrypt, scrypt and bcrypt are deemed secure.
+    #[serde(default = "shadow::default_secure_hash_schemes")]
+    pub secure_hash_schemes: Vec<String>,
+    /// Users that are removed from the databases if they exist
+    ///
+    /// Unlike users that are merely missing from the config, these are removed instead of locked.
+    /// Root is never removed.
+    #[serde(default)]
+    pub absent_users: Vec<String>,
+    /// Groups that are removed from the databases if they exist
+    ///
+    /// The root group and groups that are still the primary group of a user are never removed.
+    #[serde(default)]
+    pub absent_groups: Vec<String>,
+    /// Users that are managed by other tools
+    ///
+    /// These are never locked for missing from the config and never removed via `absentUsers`.
+    #[serde(default)]
+    pub unmanaged_users: Vec<String>,
+    /// Path to the nologin binary
+    ///
+    /// This overrides the path from the environment variable `USERBORN_NO_LOGIN_PATH`.
+    pub nologin_path: Option<String>,
+    /// Whether the shadow database is not used at all
+    ///
+    /// Set via `--no-shadow`. Passwords from the config are ignored and the password field in
+    /// /etc/passwd disables password authentication instead.
+    #[serde(skip)]
+    pub no_shadow: bool,
+    /// The password field in /etc/passwd of accounts that aren't locked with `--no-shadow`
+    ///
+    /// Locked accounts always get `*`. With the shadow database, the field is always `x` because
+    /// otherwise the password is not looked up there.
+    #[serde(default)]
+    pub passwd_marker: PasswdMarker,
+    /// The filesystem tree in which the shells of users are checked for existence
+    ///
+    /// Set via `--check-shells`. Without it, shells are not checked.
+    #[serde(skip)]
+    pub shell_root: Option<PathBuf>,
+    /// Whether hashes of plaintext passwords are replaced by hashes with a new salt
+    ///
+    /// Set via `--rehash-all`. Hashes that are provided directly (e.g. via `hashedPassword`) are
+    /// left alone.
+    #[serde(skip)]
+    pub rehash_all: bool,
 }
 
 impl Config {
-    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let file = File::open(path)?;
-        Self::from_reader(file)
+    /// Read the config from a file.
+    ///
+    /// The format is detected from the file extension (`.json`, `.toml`, or `.ndjson` and
+    /// `.jsonl` for newline-delimited JSON). For unknown extensions, the config is first parsed as
+    /// JSON and then as TOML or newline-delimited JSON.
+    ///
+    /// If the path is `-`, the config is read from stdin. Its format is detected like for a file
+    /// with an unknown extension.
+    ///
+    /// A gzip-compressed config (detected by a `.gz` extension or its magic bytes) is decompressed
+    /// first. The format is then detected from the extension before `.gz` (e.g. `config.json.gz`).
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        if path == Path::new(STDIN_PATH) {
+            let mut content = Vec::new();
+            io::stdin()
+                .read_to_end(&mut content)
+                .map_err(|source| Error::ReadConfig {
+                    origin: ConfigOrigin::Stdin,
+                    source,
+                })?;
+            let content = decode(content, false, || ConfigOrigin::Stdin)?;
+            let config = Self::from_unknown_format(&content).map_err(|e| Error::ParseConfig {
+                origin: ConfigOrigin::Stdin,
+                source: e.into(),
+            })?;
+            config.validate_id_limits()?;
+            return Ok(config);
+        }
+
+        let origin = || ConfigOrigin::Path(path.to_path_buf());
+        let content = fs::read(path).map_err(|source| Error::ReadConfig {
+            origin: origin(),
+            source,
+        })?;
+        let is_gzip = path.extension().is_some_and(|e| e == "gz");
+        let content = decode(content, is_gzip, origin)?;
+
+        let path = if is_gzip {
+            path.with_extension("")
+        } else {
+            path.to_path_buf()
+        };
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&content),
+            Some("toml") => Self::from_toml(&content),
+            Some("ndjson" | "jsonl") => Self::from_ndjson(&content),
+            _ => Self::from_unknown_format(&content),
+        }
+        .map_err(|e| Error::ParseConfig {
+            origin: origin(),
+            source: e.into(),
+        })?;
+        config.validate_id_limits()?;
+        Ok(config)
+    }
+
+    /// The members of a group.
+    ///
+    /// If any declaration of the group in this config has `mergeMembers` set, the members of all
+    /// its declarations are unioned. Otherwise, only the members of `group` are used.
+    pub fn group_members(&self, group: &Group) -> Vec<String> {
+        let declarations = self.groups.iter().filter(|g| g.name == group.name);
+        if !declarations.clone().any(|g| g.merge_members) {
+            return group.members.clone();
+        }
+        declarations
+            .flat_map(|g| g.members.iter().cloned())
+            .collect()
+    }
+
+    /// The JSON Schema of the config.
+    ///
+    /// It is derived from the same types the config is deserialized into and thus always matches
+    /// what userborn accepts.
+    pub fn json_schema() -> serde_json::Value {
+        schemars::schema_for!(Config).to_value()
+    }
+
+    /// Read the config from a directory of drop-in files.
+    ///
+    /// All `*.json` files in the directory are read in lexical order and merged via
+    /// [`merge_json`]. Other files are ignored. Fails if there are no `*.json` files because an
+    /// empty config would lock all existing users.
+    pub fn from_dir(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let origin = || ConfigOrigin::Path(path.to_path_buf());
+        let read_error = |source| Error::ReadConfig {
+            origin: origin(),
+            source,
+        };
+
+        let mut files = fs::read_dir(path)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .map_err(read_error)?;
+        files.retain(|file| file.extension().is_some_and(|e| e == "json") && file.is_file());
+        files.sort();
+        if files.is_empty() {
+            return Err(Error::NoDropIns {
+                path: path.to_path_buf(),
+            });
+        }
+
+        let mut merged = serde_json::Value::Object(serde_json::Map::new());
+        for file in files {
+            log::debug!("Reading drop-in config {file:?}...");
+            let origin = ConfigOrigin::Path(file.clone());
+            let content = fs::read_to_string(&file).map_err(|source| Error::ReadConfig {
+                origin: origin.clone(),
+                source,
+            })?;
+            // Validate each file on its own so that errors name the file they occur in.
+            let value = serde_json::from_str::<serde_json::Value>(&content)
+                .and_then(|value| Self::deserialize(&value).map(|_| value))
+                .map_err(|e| Error::ParseConfig {
+                    origin,
+                    source: e.into(),
+                })?;
+            merge_json(&mut merged, value);
+        }
+
+        let config = Self::deserialize(&merged).map_err(|e| Error::ParseConfig {
+            origin: origin(),
+            source: e.into(),
+        })?;
+        config.validate_id_limits()?;
+        Ok(config)
+    }
+
+    /// Compute the users and groups that are added, removed or modified in `other`.
+    ///
+    /// This only compares the configs and doesn't take the password databases into account.
+    pub fn diff(&self, other: &Config) -> ConfigDiff {
+        let (added_users, removed_users, modified_users) =
+            diff_by_name(&self.users, &other.users, |u| &u.name);
+        let (added_groups, removed_groups, modified_groups) =
+            diff_by_name(&self.groups, &other.groups, |g| &g.name);
+        ConfigDiff {
+            added_users,
+            removed_users,
+            modified_users,
+            added_groups,
+            removed_groups,
+            modified_groups,
+        }
+    }
+
+    /// The limit of automatically allocated IDs in the normal or the system range.
+    ///
+    /// This is `normalIdCeiling` for normal and `systemIdFloor` for system users and groups.
+    pub fn allocation_limit(&self, is_normal: bool) -> Option<u32> {
+        if is_normal {
+            self.normal_id_ceiling
+        } else {
+            self.system_id_floor
+        }
+    }
+
+    /// Check that `systemIdFloor` and `normalIdCeiling` are within the ranges they limit.
+    ///
+    /// This is done when the config is read via [`Config::from_file`] or [`Config::from_dir`] so
+    /// that a limit that would leave (almost) no IDs to allocate isn't silently clamped.
+    pub fn validate_id_limits(&self) -> Result<(), Error> {
+        for (option, is_normal) in [("systemIdFloor", false), ("normalIdCeiling", true)] {
+            let (_, range) = id::range(is_normal);
+            let invalid = self
+                .allocation_limit(is_normal)
+                .filter(|limit| !range.contains(limit));
+            if let Some(limit) = invalid {
+                return Err(Error::InvalidIdLimit {
+                    option,
+                    limit,
+                    range,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the names of all users and groups are valid.
+    ///
+    /// Fails on the first invalid name.
+    pub fn validate_names(&self) -> Result<(), Error> {
+        let names = self
+            .users
+            .iter()
+            .map(|user| ("user", &user.name))
+            .chain(self.groups.iter().map(|group| ("group", &group.name)));
+        for (kind, name) in names {
+            validate_name(kind, name)?;
+        }
+        Ok(())
+    }
+
+    /// Parse the config first as JSON and then as TOML.
+    ///
+    /// Content starting with `{` is parsed as newline-delimited JSON instead of TOML because a
+    /// TOML document cannot start with an inline table. NDJSON is detected before JSON because a
+    /// single NDJSON line is also a valid JSON config that, as unknown fields are ignored, doesn't
+    /// contain any users or groups.
+    fn from_unknown_format(s: &str) -> Result<Self> {
+        if is_ndjson(s) {
+            return Self::from_ndjson(s);
+        }
+        Self::from_json(s).or_else(|json_err| {
+            if s.trim_start().starts_with('{') {
+                Self::from_ndjson(s).map_err(|ndjson_err| anyhow!("{json_err:#}. {ndjson_err:#}"))
+            } else {
+                Self::from_toml(s).map_err(|toml_err| anyhow!("{json_err:#}. {toml_err:#}"))
+            }
+        })
+    }
+
+    /// Parse the config from newline-delimited JSON.
+    ///
+    /// Each non-empty line is either a user or an object tagging a user or a group, i.e.
+    /// `{ "user": { ... } }` or `{ "group": { ... } }`. All other options keep their defaults.
+    fn from_ndjson(s: &str) -> Result<Self> {
+        let mut config = Self::from_json("{}")?;
+        for (i, line) in s.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str::<serde_json::Value>(line)
+                .and_then(|value| match &value {
+                    serde_json::Value::Object(object)
+                        if object.len() == 1
+                            && (object.contains_key("user") || object.contains_key("group")) =>
+                    {
+                        serde_json::from_value(value)
+                    }
+                    _ => serde_json::from_value(value).map(NdjsonEntry::User),
+                })
+                .with_context(|| format!("Failed to parse line {} of config as NDJSON", i + 1))?;
+            match entry {
+                NdjsonEntry::User(user) => config.users.push(*user),
+                NdjsonEntry::Group(group) => config.groups.push(group),
+            }
+        }
+        Ok(config)
+    }
+
+    fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).context("Failed to parse config as JSON")
+    }
+
+    fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).context("Failed to parse config as TOML")
+    }
+}
+
+/// A line of a config in newline-delimited JSON.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum NdjsonEntry {
+    User(Box<User>),
+    Group(Group),
+}
+
+/// Whether the content is newline-delimited JSON.
+///
+/// This is the case when the first non-empty line on its own is an object describing a user or
+/// tagging a user or a group. A config never has a `name`, `user`, or `group` field.
+fn is_ndjson(s: &str) -> bool {
+    s.lines()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .is_some_and(|value| {
+            ["name", "user", "group"]
+                .iter()
+                .any(|k| value.get(k).is_some())
+        })
+}
+
+/// Merge the JSON of a config into the JSON of the configs read before it.
+///
+/// Users and groups are merged by name: An entry replaces the entry with the same name as a whole
+/// and is appended otherwise. Only the members of groups with `mergeMembers` are unioned. All
+/// other options replace the option from the earlier configs.
+fn merge_json(merged: &mut serde_json::Value, config: serde_json::Value) {
+    let (serde_json::Value::Object(merged), serde_json::Value::Object(config)) = (merged, config)
+    else {
+        return;
+    };
+    for (key, value) in config {
+        match (merged.get_mut(&key), value) {
+            (Some(serde_json::Value::Array(entries)), serde_json::Value::Array(new_entries))
+                if key == "users" || key == "groups" =>
+            {
+                for new_entry in new_entries {
+                    let name = new_entry.get("name");
+                    match entries
+                        .iter_mut()
+                        .find(|e| name.is_some() && e.get("name") == name)
+                    {
+                        Some(entry) if key == "groups" => *entry = merge_group(entry, new_entry),
+                        Some(entry) => *entry = new_entry,
+                        None => entries.push(new_entry),
+                    }
+                }
+            }
+            (_, value) => {
+                merged.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Merge the JSON of a group into the JSON of an earlier declaration of the same group.
+///
+/// The later declaration replaces the earlier one. If either declaration has `mergeMembers` set,
+/// the members are unioned instead and the merged group keeps `mergeMembers`.
+fn merge_group(entry: &serde_json::Value, mut new_entry: serde_json::Value) -> serde_json::Value {
+    let is_merging = |entry: &serde_json::Value| {
+        entry.get("mergeMembers") == Some(&serde_json::Value::Bool(true))
+    };
+    if !is_merging(entry) && !is_merging(&new_entry) {
+        return new_entry;
+    }
+    let members = |entry: &serde_json::Value| match entry.get("members") {
+        Some(serde_json::Value::Array(members)) => members.clone(),
+        Some(serde_json::Value::String(members)) => split_members(members)
+            .map(serde_json::Value::from)
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut merged_members = members(entry);
+    for member in members(&new_entry) {
+        if !merged_members.contains(&member) {
+            merged_members.push(member);
+        }
+    }
+    if let serde_json::Value::Object(new_entry) = &mut new_entry {
+        new_entry.insert("members".into(), merged_members.into());
+        new_entry.insert("mergeMembers".into(), true.into());
+    }
+    new_entry
+}
+
+/// Decode the raw content of a config.
+///
+/// The content is decompressed first if `is_gzip` is set or it starts with the gzip magic bytes.
+fn decode(
+    content: Vec<u8>,
+    is_gzip: bool,
+    origin: impl Fn() -> ConfigOrigin,
+) -> Result<String, Error> {
+    if is_gzip || content.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = String::new();
+        GzDecoder::new(content.as_slice())
+            .read_to_string(&mut decompressed)
+            .map_err(|source| Error::DecompressConfig {
+                origin: origin(),
+                source,
+            })?;
+        return Ok(decompressed);
     }
+    String::from_utf8(content).map_err(|e| Error::ReadConfig {
+        origin: origin(),
+        source: io::Error::new(io::ErrorKind::InvalidData, e),
+    })
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_reserved_ids() -> Vec<ReservedId> {
+    vec![ReservedId::Single(id::NOBODY_ID)]
+}
+
+/// Check that a user or group name can safely be written to the password databases.
+///
+/// This follows the rules that `useradd(8)` enforces: A name must not be empty, must not start
+/// with a dash and must not contain colons, commas, whitespace or control characters.
+///
+/// `kind` is either "user" or "group" and only used in the error.
+pub fn validate_name(kind: &'static str, name: &str) -> Result<(), Error> {
+    let reason = if name.is_empty() {
+        NameError::Empty
+    } else if name.starts_with('-') {
+        NameError::LeadingDash
+    } else if let Some(c) = name
+        .chars()
+        .find(|c| matches!(c, ':' | ',') || c.is_whitespace() || c.is_control())
+    {
+        NameError::InvalidCharacter(c)
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidName {
+        kind,
+        name: name.into(),
+        source: reason,
+    })
+}
+
+/// Expand references to environment variables in a path from the config.
+///
+/// Both `$VAR` and `${VAR}` are expanded. A literal `$` is written as `$$`. Fails if a referenced
+/// variable is not set so that a path is never silently read from the wrong location.
+pub fn expand_env_vars(path: &str) -> Result<String, Error> {
+    expand_vars(path, |name| std::env::var(name).ok())
+}
 
-    fn from_reader(reader: impl Read) -> Result<Self> {
-        serde_json::from_reader(reader).context("Failed to parse config")
+fn expand_vars(path: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        let name = if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| Error::UnterminatedVariable { path: path.into() })?;
+            rest = &after[end + 1..];
+            &after[..end]
+        } else {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(is_name_char)
+        {
+            return Err(Error::InvalidVariable { path: path.into() });
+        }
+        let value = lookup(name).ok_or_else(|| Error::UnsetVariable {
+            name: name.into(),
+            path: path.into(),
+        })?;
+        expanded.push_str(&value);
     }
+    expanded.push_str(rest);
+    Ok(expanded)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use expect_test::expect;
+    use indoc::indoc;
+
+    use crate::fs::TestDir;
+
+    #[test]
+    fn expand_vars_in_path() -> Result<()> {
+        let lookup = |name: &str| (name == "RUNTIME_DIRECTORY").then(|| "/run/secrets".to_owned());
+
+        assert_eq!(expand_vars("/etc/hash", lookup)?, "/etc/hash");
+        assert_eq!(
+            expand_vars("$RUNTIME_DIRECTORY/root", lookup)?,
+            "/run/secrets/root"
+        );
+        assert_eq!(
+            expand_vars("${RUNTIME_DIRECTORY}_root", lookup)?,
+            "/run/secrets_root"
+        );
+        assert_eq!(expand_vars("/etc/$$hash$$", lookup)?, "/etc/$hash$");
+
+        let error = |path| expand_vars(path, lookup).map_err(|e| e.to_string());
+        assert_eq!(
+            error("$UNSET/root"),
+            Err(r#"Environment variable UNSET referenced in "$UNSET/root" is not set"#.into())
+        );
+        assert_eq!(
+            error("${RUNTIME_DIRECTORY"),
+            Err(r#"Unterminated variable reference in "${RUNTIME_DIRECTORY""#.into())
+        );
+        for path in ["/etc/$", "$/root", "${}", "$1", "${a-b}"] {
+            assert!(error(path).is_err(), "{path}");
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn config() -> Result<()> {
         let value = serde_json::json!({
@@ -110,4 +1135,461 @@ mod tests {
         serde_json::from_value::<Config>(value)?;
         Ok(())
     }
+
+    #[test]
+    fn reserved_ids() -> Result<()> {
+        let config = Config::from_json(r#"{ "reservedIds": [ 65534, [ 500, 510 ] ] }"#)?;
+        assert_eq!(
+            config.reserved_ids,
+            vec![ReservedId::Single(65534), ReservedId::Range(500, 510)]
+        );
+        assert!(config.reserved_ids[1].contains(500));
+        assert!(config.reserved_ids[1].contains(510));
+        assert!(!config.reserved_ids[1].contains(511));
+
+        // The ID of nobody is reserved unless the reserved IDs are set explicitly.
+        let config = Config::from_json("{}")?;
+        assert_eq!(config.reserved_ids, vec![ReservedId::Single(id::NOBODY_ID)]);
+        let config = Config::from_json(r#"{ "reservedIds": [] }"#)?;
+        assert!(config.reserved_ids.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn allocation_directions() -> Result<()> {
+        let config = Config::from_json("{}")?;
+        assert_eq!(
+            config.allocation_directions.get(false),
+            AllocationDirection::Descending
+        );
+        assert_eq!(
+            config.allocation_directions.get(true),
+            AllocationDirection::Ascending
+        );
+
+        let config = Config::from_json(r#"{ "allocationDirections": { "system": "ascending" } }"#)?;
+        assert_eq!(
+            config.allocation_directions,
+            AllocationDirections {
+                system: AllocationDirection::Ascending,
+                normal: AllocationDirection::Ascending,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diff() -> Result<()> {
+        let old = Config::from_json(
+            r#"{
+                "users": [
+                    { "name": "kept" },
+                    { "name": "changed", "shell": "/bin/bash" },
+                    { "name": "removed" }
+                ],
+                "groups": [ { "name": "wheel", "members": [ "kept" ] } ]
+            }"#,
+        )?;
+        let new = Config::from_json(
+            r#"{
+                "users": [
+                    { "name": "added" },
+                    { "name": "changed", "shell": "/bin/zsh" },
+                    { "name": "kept" }
+                ],
+                "groups": [ { "name": "wheel", "members": [ "kept", "added" ] } ]
+            }"#,
+        )?;
+
+        let set = |names: &[&str]| names.iter().map(|n| (*n).to_string()).collect();
+        assert_eq!(
+            old.diff(&new),
+            ConfigDiff {
+                added_users: set(&["added"]),
+                removed_users: set(&["removed"]),
+                modified_users: set(&["changed"]),
+                modified_groups: set(&["wheel"]),
+                ..ConfigDiff::default()
+            }
+        );
+        assert!(new.diff(&new).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn config_toml() -> Result<()> {
+        let config = Config::from_toml(indoc! {r#"
+            [[users]]
+            isNormal = true
+            name = "normalo"
+            home = "/home/normalo"
+            hashedPassword = "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4"
+
+            [[users]]
+            name = "barebones"
+
+            [[groups]]
+            name = "wheel"
+            members = [ "normalo", "barebones" ]
+        "#})?;
+
+        assert_eq!(config.users.len(), 2);
+        assert!(config.users[0].is_normal);
+        assert!(config.users[0].password.hashed_password.is_some());
+        assert_eq!(config.groups[0].members.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors_mention_format() {
+        let Err(err) = Config::from_json("users = []") else {
+            panic!("Parsing TOML as JSON should fail");
+        };
+        assert!(err.to_string().contains("JSON"));
+
+        let Err(err) = Config::from_toml("{ \"users\": [] }") else {
+            panic!("Parsing JSON as TOML should fail");
+        };
+        assert!(err.to_string().contains("TOML"));
+
+        let Err(err) = Config::from_unknown_format("users = ") else {
+            panic!("Parsing invalid TOML should fail");
+        };
+        assert!(err.to_string().contains("JSON"));
+        assert!(err.to_string().contains("TOML"));
+    }
+
+    #[test]
+    fn validate_names() -> Result<()> {
+        validate_name("user", "normalo")?;
+        validate_name("user", "systemd-network")?;
+        validate_name("user", "machine$")?;
+
+        assert!(validate_name("user", "").is_err());
+        assert!(validate_name("user", "-normalo").is_err());
+        assert!(validate_name("user", "normalo:x:0:0").is_err());
+        assert!(validate_name("user", "normalo,peter").is_err());
+        assert!(validate_name("user", "nor malo").is_err());
+        assert!(validate_name("user", "normalo\nroot::0:0::/root:/bin/sh").is_err());
+        assert!(validate_name("user", "normalo\u{7f}").is_err());
+        assert!(matches!(
+            validate_name("user", "-normalo"),
+            Err(Error::InvalidName {
+                source: NameError::LeadingDash,
+                ..
+            })
+        ));
+
+        let config = Config::from_json(r#"{ "groups": [ { "name": "wheel:x" } ] }"#)?;
+        let Err(err) = config.validate_names() else {
+            panic!("Validating an invalid group name should fail");
+        };
+        assert!(
+            matches!(&err, Error::InvalidName { kind: "group", name, .. } if name == "wheel:x")
+        );
+        // The reason is kept as the source of the error.
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(err)),
+            r#"Invalid name of group "wheel:x": Name contains the invalid character ':'"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn json_schema() {
+        let schema = Config::json_schema();
+        let properties = |value: &serde_json::Value| {
+            value["properties"]
+                .as_object()
+                .map(|properties| properties.keys().cloned().collect::<Vec<_>>().join(" "))
+                .unwrap_or_default()
+        };
+
+        // Options that are only set via the command line are not part of the schema.
+        expect!["absentGroups absentUsers allocationDirections defaultShell groupMemberMode groups ledgerPath lockMode lockSetsNologin memberOrder missingPrimaryGroup nologinPath normalIdCeiling passwdMarker passwordPolicy rehashInsecure reservedIds secureHashSchemes sortOrder systemIdFloor unmanagedUsers userGroupReusesUid users"]
+            .assert_eq(&properties(&schema));
+        expect!["administrators gid hashedPassword is_normal members mergeMembers name password"]
+            .assert_eq(&properties(&schema["$defs"]["Group"]));
+        // The password options of a user are flattened into the user.
+        assert!(properties(&schema["$defs"]["User"]).contains("hashedPasswordCredential"));
+    }
+
+    #[test]
+    fn gzip_compressed() -> Result<()> {
+        use std::io::Write;
+
+        use flate2::{write::GzEncoder, Compression};
+
+        let test_dir = TestDir::new("gzip-config")?;
+        let directory = test_dir.path();
+        let compress = |content: &str| -> Result<Vec<u8>> {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content.as_bytes())?;
+            Ok(encoder.finish()?)
+        };
+
+        let json = directory.join("config.json.gz");
+        fs::write(
+            &json,
+            compress(r#"{ "users": [ { "name": "normalo" } ] }"#)?,
+        )?;
+        assert_eq!(Config::from_file(&json)?.users[0].name, "normalo");
+
+        // The format is detected from the extension before `.gz`.
+        let toml = directory.join("config.toml.gz");
+        fs::write(&toml, compress("[[users]]\nname = \"normalo\"\n")?)?;
+        assert_eq!(Config::from_file(&toml)?.users[0].name, "normalo");
+
+        // Without a `.gz` extension, gzip is detected from the magic bytes.
+        let unknown = directory.join("config");
+        fs::write(
+            &unknown,
+            compress(r#"{ "users": [ { "name": "normalo" } ] }"#)?,
+        )?;
+        assert_eq!(Config::from_file(&unknown)?.users[0].name, "normalo");
+
+        // Decompression and parse failures are distinct.
+        fs::write(&json, "not gzip")?;
+        assert!(matches!(
+            Config::from_file(&json),
+            Err(Error::DecompressConfig { .. })
+        ));
+        fs::write(&json, compress(r#"{ "users": 1 }"#)?)?;
+        assert!(matches!(
+            Config::from_file(&json),
+            Err(Error::ParseConfig { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn id_limits() -> Result<()> {
+        let test_dir = TestDir::new("config-id-limits")?;
+        let path = test_dir.path().join("config.json");
+
+        fs::write(
+            &path,
+            r#"{ "systemIdFloor": 500, "normalIdCeiling": 1999 }"#,
+        )?;
+        let config = Config::from_file(&path)?;
+        assert_eq!(config.allocation_limit(false), Some(500));
+        assert_eq!(config.allocation_limit(true), Some(1999));
+
+        // Limits that are outside of the range they limit are rejected instead of clamped.
+        for (config, option, limit) in [
+            (r#"{ "systemIdFloor": 0 }"#, "systemIdFloor", 0),
+            (r#"{ "systemIdFloor": 5000 }"#, "systemIdFloor", 5000),
+            (r#"{ "normalIdCeiling": 500 }"#, "normalIdCeiling", 500),
+            (r#"{ "normalIdCeiling": 65540 }"#, "normalIdCeiling", 65540),
+        ] {
+            fs::write(&path, config)?;
+            let Err(Error::InvalidIdLimit {
+                option: actual_option,
+                limit: actual_limit,
+                ..
+            }) = Config::from_file(&path)
+            else {
+                panic!("{config} should be rejected");
+            };
+            assert_eq!((actual_option, actual_limit), (option, limit));
+        }
+        assert_eq!(
+            Config::from_file(&path).err().map(|e| e.to_string()),
+            Some("normalIdCeiling 65540 is outside of the normal range 1000..30000".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_in_directory() -> Result<()> {
+        let test_dir = TestDir::new("config-dir")?;
+        let directory = test_dir.path();
+
+        // A directory without drop-ins is not read as an empty config.
+        fs::write(directory.join("README"), "Not a config")?;
+        assert!(matches!(
+            Config::from_dir(directory),
+            Err(Error::NoDropIns { .. })
+        ));
+
+        fs::write(
+            directory.join("10-base.json"),
+            r#"{
+                "users": [ { "name": "normalo", "uid": 1000 }, { "name": "sshd" } ],
+                "groups": [ { "name": "wheel", "members": [ "normalo" ] } ],
+                "lockMode": "prefix"
+            }"#,
+        )?;
+        fs::write(
+            directory.join("20-override.json"),
+            r#"{
+                "users": [ { "name": "normalo", "uid": 1001 }, { "name": "nginx" } ],
+                "lockMode": "replace"
+            }"#,
+        )?;
+
+        let config = Config::from_dir(directory)?;
+        let users = config.users.iter().map(|u| (u.name.as_str(), u.uid));
+        assert_eq!(
+            users.collect::<Vec<_>>(),
+            [("normalo", Some(1001)), ("sshd", None), ("nginx", None)]
+        );
+        assert_eq!(config.groups[0].name, "wheel");
+        assert_eq!(config.lock_mode, LockMode::Replace);
+
+        // Errors name the file they occur in.
+        fs::write(directory.join("30-broken.json"), r#"{ "users": 1 }"#)?;
+        let Err(err) = Config::from_dir(directory) else {
+            panic!("Reading a broken drop-in should fail");
+        };
+        assert!(err.to_string().contains("30-broken.json"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_members() -> Result<()> {
+        let test_dir = TestDir::new("merge-members")?;
+        let directory = test_dir.path();
+
+        fs::write(
+            directory.join("10-admins.json"),
+            r#"{
+                "groups": [
+                    { "name": "wheel", "members": [ "alice" ], "mergeMembers": true },
+                    { "name": "audio", "members": [ "alice" ] }
+                ]
+            }"#,
+        )?;
+        fs::write(
+            directory.join("20-more-admins.json"),
+            r#"{
+                "groups": [
+                    { "name": "wheel", "members": [ "bob", "alice" ] },
+                    { "name": "audio", "members": [ "bob" ] }
+                ]
+            }"#,
+        )?;
+
+        let config = Config::from_dir(directory)?;
+        let members = |name: &str| {
+            config
+                .groups
+                .iter()
+                .find(|g| g.name == name)
+                .map(|g| config.group_members(g))
+        };
+        assert_eq!(members("wheel"), Some(vec!["alice".into(), "bob".into()]));
+        // Groups without mergeMembers keep replace semantics.
+        assert_eq!(members("audio"), Some(vec!["bob".into()]));
+
+        // Members written as a delimited string are merged with members written as a list.
+        fs::write(
+            directory.join("30-video.json"),
+            r#"{ "groups": [ { "name": "video", "members": "alice, carol", "mergeMembers": true } ] }"#,
+        )?;
+        fs::write(
+            directory.join("40-video.json"),
+            r#"{ "groups": [ { "name": "video", "members": [ "bob", "carol" ] } ] }"#,
+        )?;
+        fs::write(
+            directory.join("50-video.json"),
+            r#"{ "groups": [ { "name": "video", "members": "dave bob" } ] }"#,
+        )?;
+        let config = Config::from_dir(directory)?;
+        let video = config.groups.iter().find(|g| g.name == "video");
+        assert_eq!(
+            video.map(|g| config.group_members(g)),
+            Some(["alice", "carol", "bob", "dave"].map(String::from).to_vec())
+        );
+
+        // Declarations of the same group in a single config are merged as well.
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "groups": [
+                { "name": "wheel", "members": [ "alice" ], "mergeMembers": true },
+                { "name": "wheel", "members": [ "bob" ] },
+            ],
+        }))?;
+        assert_eq!(
+            config.group_members(&config.groups[1]),
+            ["alice", "bob"].map(String::from)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_format() -> Result<()> {
+        let config = Config::from_unknown_format(r#"{ "users": [ { "name": "normalo" } ] }"#)?;
+        assert_eq!(config.users[0].name, "normalo");
+
+        let config = Config::from_unknown_format(indoc! {r#"
+            [[users]]
+            name = "normalo"
+        "#})?;
+        assert_eq!(config.users[0].name, "normalo");
+        Ok(())
+    }
+
+    #[test]
+    fn members_syntax() -> Result<()> {
+        let members = |members: serde_json::Value| -> Result<Vec<String>> {
+            let group: Group =
+                serde_json::from_value(serde_json::json!({ "name": "wheel", "members": members }))?;
+            Ok(group.members)
+        };
+
+        assert_eq!(
+            members(serde_json::json!(["alice", "bob"]))?,
+            ["alice", "bob"]
+        );
+        assert_eq!(members(serde_json::json!("alice,bob"))?, ["alice", "bob"]);
+        assert_eq!(
+            members(serde_json::json!(" alice, bob\tcarol\n\ndave,,"))?,
+            ["alice", "bob", "carol", "dave"]
+        );
+        assert_eq!(members(serde_json::json!(""))?, Vec::<String>::new());
+        assert!(members(serde_json::json!(1)).is_err());
+
+        let group: Group = serde_json::from_value(serde_json::json!({ "name": "wheel" }))?;
+        assert!(group.members.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn ndjson() -> Result<()> {
+        let ndjson = indoc! {r#"
+            { "name": "normalo", "isNormal": true }
+
+            { "user": { "name": "sshd" } }
+            { "group": { "name": "wheel", "members": [ "normalo" ] } }
+        "#};
+        for config in [
+            Config::from_ndjson(ndjson)?,
+            Config::from_unknown_format(ndjson)?,
+        ] {
+            let users = config.users.iter().map(|u| (u.name.as_str(), u.is_normal));
+            assert_eq!(
+                users.collect::<Vec<_>>(),
+                [("normalo", true), ("sshd", false)]
+            );
+            assert_eq!(config.groups[0].members, ["normalo"]);
+            assert_eq!(config.lock_mode, LockMode::Replace);
+        }
+
+        // A single line is not mistaken for an empty JSON config.
+        let config = Config::from_unknown_format(r#"{ "name": "normalo", "uid": 1000 }"#)?;
+        assert_eq!(config.users.len(), 1);
+        assert_eq!(config.users[0].uid, Some(1000));
+        let config = Config::from_unknown_format(r#"{ "group": { "name": "wheel" } }"#)?;
+        assert_eq!(config.groups[0].name, "wheel");
+
+        let Err(err) = Config::from_ndjson("{ \"name\": \"normalo\" }\n{ \"uid\": 1 }\n") else {
+            panic!("A line without a name should fail");
+        };
+        assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+        Ok(())
+    }
 }
@@ -0,0 +1,196 @@
+//! Errors of the public API.
+//!
+//! Internally, `anyhow` is used where the kind of error doesn't matter to the caller. All public
+//! functions that can fail (e.g. parsing the config, reading the databases, allocating IDs and
+//! writing files) return [`Error`] so that embedders can distinguish these kinds programmatically.
+//! It converts into `anyhow::Error` without losing its sources.
+
+use std::{fmt, io, ops::Range, path::PathBuf};
+
+use crate::id::{self, IdKind};
+
+/// Errors that are boxed because their concrete type is an implementation detail.
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// An error of the public API of the library.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The config could not be read.
+    #[error("Failed to read config {origin}")]
+    ReadConfig {
+        origin: ConfigOrigin,
+        #[source]
+        source: io::Error,
+    },
+    /// The config could not be decompressed.
+    #[error("Failed to decompress config {origin}")]
+    DecompressConfig {
+        origin: ConfigOrigin,
+        #[source]
+        source: io::Error,
+    },
+    /// The config could not be parsed.
+    #[error("Failed to parse config {origin}")]
+    ParseConfig {
+        origin: ConfigOrigin,
+        #[source]
+        source: BoxError,
+    },
+    /// A directory of drop-in configs doesn't contain any.
+    #[error("No drop-in configs (*.json) found in {path:?}")]
+    NoDropIns { path: PathBuf },
+    /// The name of a user or group is invalid.
+    #[error("Invalid name of {kind} {name:?}")]
+    InvalidName {
+        /// Either "user" or "group"
+        kind: &'static str,
+        name: String,
+        #[source]
+        source: NameError,
+    },
+    /// A path from the config references an environment variable that is not set.
+    #[error("Environment variable {name} referenced in {path:?} is not set")]
+    UnsetVariable { name: String, path: String },
+    /// A path from the config contains a malformed variable reference.
+    #[error("Invalid variable reference in {path:?}. Use $$ for a literal $")]
+    InvalidVariable { path: String },
+    /// A path from the config contains a `${` without a closing `}`.
+    #[error("Unterminated variable reference in {path:?}")]
+    UnterminatedVariable { path: String },
+    /// `systemIdFloor` or `normalIdCeiling` is outside of the range it limits.
+    #[error(
+        "{option} {limit} is outside of the {} range {}..{}",
+        id::range_name(range),
+        range.start,
+        range.end
+    )]
+    InvalidIdLimit {
+        option: &'static str,
+        limit: u32,
+        range: Range<u32>,
+    },
+    /// All IDs in the range are in use or reserved.
+    #[error(
+        "Failed to allocate new {kind} in {} range {}..{} (all {} IDs are in use or reserved)",
+        id::range_name(range),
+        range.start,
+        range.end,
+        range.len()
+    )]
+    AllocationExhausted { kind: IdKind, range: Range<u32> },
+    /// There is no free block of IDs large enough in the range.
+    #[error("Failed to allocate a block of {count} IDs in range {}..{}", range.start, range.end)]
+    BlockAllocationExhausted { count: u32, range: Range<u32> },
+    /// A block of zero IDs was requested.
+    #[error("Cannot allocate an empty block of IDs")]
+    EmptyBlock,
+    /// A temporary file was created with a more permissive mode than requested.
+    #[error("Temporary file {path:?} has mode {mode:04o} instead of {expected:04o}")]
+    InsecureMode {
+        path: PathBuf,
+        mode: u32,
+        expected: u32,
+    },
+    /// A password database could not be read.
+    #[error("Failed to read {path:?}")]
+    ReadDatabase {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The ledger could not be parsed or serialized.
+    #[error("Failed to {action} ledger")]
+    Ledger {
+        /// Either "parse" or "serialize"
+        action: &'static str,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// An entry with the same name already exists in a database.
+    #[error("{name} already exists in the {database} database")]
+    NameTaken {
+        database: &'static str,
+        name: String,
+    },
+    /// An entry with the same ID already exists in a database.
+    #[error("{kind} {id} already exists in the {database} database")]
+    IdTaken {
+        database: &'static str,
+        kind: IdKind,
+        id: u32,
+    },
+    /// An entry doesn't exist in a database.
+    #[error("{name} doesn't exist in the {database} database")]
+    NoSuchEntry {
+        database: &'static str,
+        name: String,
+    },
+    /// A field contains a character that would corrupt the database.
+    #[error("{field} contains the invalid character {character:?}")]
+    InvalidCharacter {
+        field: &'static str,
+        character: char,
+    },
+    /// The password databases are inconsistent and inconsistencies are treated as errors.
+    #[error("Found {count} inconsistencies in the password databases")]
+    Inconsistent { count: usize },
+    /// The path to the nologin binary is not absolute.
+    #[error("Path to the nologin binary {path} is not absolute")]
+    RelativeNoLoginPath { path: String },
+    /// A [`Hasher`](crate::Hasher) failed to hash a password or to generate a salt.
+    #[error("Password hasher failed")]
+    Hash {
+        #[source]
+        source: BoxError,
+    },
+    /// A file could not be written.
+    #[error("{context}")]
+    Io {
+        /// What failed, e.g. "Failed to write to \"/etc/passwd.tmp0\""
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Why the name of a user or group is invalid.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NameError {
+    #[error("Name is empty")]
+    Empty,
+    #[error("Name starts with a dash")]
+    LeadingDash,
+    /// The name contains a colon, comma, whitespace or control character.
+    #[error("Name contains the invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+/// Where the config was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigOrigin {
+    Path(PathBuf),
+    Stdin,
+}
+
+impl fmt::Display for ConfigOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{path:?}"),
+            Self::Stdin => write!(f, "from stdin"),
+        }
+    }
+}
+
+/// Attach a context to an IO error, like `anyhow::Context` does for any error.
+pub(crate) trait IoContext<T> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T, Error>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,219 @@
+use serde::Serialize;
+
+use crate::{
+    group::Group,
+    id,
+    passwd::Passwd,
+    shadow::{self, Shadow},
+};
+
+/// All users and groups from the password databases.
+///
+/// This uses the same format as the config, so an export can be read again as a config.
+#[derive(Serialize, Debug)]
+pub struct Export {
+    users: Vec<ExportedUser>,
+    groups: Vec<ExportedGroup>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ExportedUser {
+    is_normal: bool,
+    name: String,
+    uid: u32,
+    /// The name of the primary group or its GID if the group doesn't exist.
+    group: String,
+    description: String,
+    home: String,
+    shell: String,
+    /// Only set if secrets are included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hashed_password: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    locked: bool,
+}
+
+// The group config doesn't use camelCase, so neither does the export.
+#[derive(Serialize, Debug)]
+struct ExportedGroup {
+    is_normal: bool,
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+impl Export {
+    /// Export the users and groups from the password databases.
+    ///
+    /// The password hashes are only included if `include_secrets` is set.
+    pub fn new(
+        group_db: &Group,
+        passwd_db: &Passwd,
+        shadow_db: &Shadow,
+        include_secrets: bool,
+    ) -> Self {
+        let groups = group_db
+            .entries()
+            .into_iter()
+            .map(|entry| ExportedGroup {
+                is_normal: id::is_normal(entry.gid()),
+                name: entry.name().into(),
+                gid: entry.gid(),
+                members: entry.members().to_vec(),
+            })
+            .collect();
+
+        let users = passwd_db
+            .entries()
+            .into_iter()
+            .map(|entry| {
+                let group = group_db.get_by_gid(entry.gid()).map_or_else(
+                    || entry.gid().to_string(),
+                    |group_entry| group_entry.name().into(),
+                );
+                let shadow_entry = shadow_db.get(entry.name());
+                let locked = shadow_entry.is_some_and(shadow::Entry::is_locked);
+
+                ExportedUser {
+                    is_normal: id::is_normal(entry.uid()),
+                    name: entry.name().into(),
+                    uid: entry.uid(),
+                    group,
+                    description: entry.description().into(),
+                    home: entry.home().into(),
+                    shell: entry.shell().into(),
+                    hashed_password: shadow_entry
+                        .filter(|_| include_secrets && !locked)
+                        .map(|shadow_entry| shadow_entry.password().into()),
+                    locked,
+                }
+            })
+            .collect();
+
+        Self { users, groups }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+    use expect_test::expect;
+    use indoc::indoc;
+
+    use crate::{config::Config, ledger::Ledger, update_users_and_groups, XcryptHasher};
+
+    fn databases() -> (Group, Passwd, Shadow) {
+        let group_db = Group::from_buffer(indoc! {"
+            root:x:0:
+            wheel:x:1:normalo
+            normalo:x:1000:normalo
+        "});
+        let passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0:System administrator:/root:/bin/bash
+            normalo:x:1000:1000:Normal User,,,:/home/normalo:/bin/zsh
+            orphan:x:1001:1234::/var/empty:/run/current-system/sw/bin/nologin
+        "});
+        let shadow_db = Shadow::from_buffer(indoc! {"
+            root:!*:1::::::
+            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "});
+        (group_db, passwd_db, shadow_db)
+    }
+
+    #[test]
+    fn redact_secrets() -> Result<()> {
+        let (group_db, passwd_db, shadow_db) = databases();
+
+        let export = Export::new(&group_db, &passwd_db, &shadow_db, false);
+
+        let expected = expect![[r#"
+            {
+              "users": [
+                {
+                  "isNormal": false,
+                  "name": "root",
+                  "uid": 0,
+                  "group": "root",
+                  "description": "System administrator",
+                  "home": "/root",
+                  "shell": "/bin/bash",
+                  "locked": true
+                },
+                {
+                  "isNormal": true,
+                  "name": "normalo",
+                  "uid": 1000,
+                  "group": "normalo",
+                  "description": "Normal User,,,",
+                  "home": "/home/normalo",
+                  "shell": "/bin/zsh"
+                },
+                {
+                  "isNormal": true,
+                  "name": "orphan",
+                  "uid": 1001,
+                  "group": "1234",
+                  "description": "",
+                  "home": "/var/empty",
+                  "shell": "/run/current-system/sw/bin/nologin"
+                }
+              ],
+              "groups": [
+                {
+                  "is_normal": false,
+                  "name": "root",
+                  "gid": 0,
+                  "members": []
+                },
+                {
+                  "is_normal": false,
+                  "name": "wheel",
+                  "gid": 1,
+                  "members": [
+                    "normalo"
+                  ]
+                },
+                {
+                  "is_normal": true,
+                  "name": "normalo",
+                  "gid": 1000,
+                  "members": [
+                    "normalo"
+                  ]
+                }
+              ]
+            }"#]];
+        expected.assert_eq(&serde_json::to_string_pretty(&export)?);
+
+        let export = Export::new(&group_db, &passwd_db, &shadow_db, true);
+        assert!(serde_json::to_string(&export)?.contains("$y$j9T$BOO"));
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let (group_db, passwd_db, shadow_db) = databases();
+
+        let export = Export::new(&group_db, &passwd_db, &shadow_db, true);
+        let config: Config = serde_json::from_str(&serde_json::to_string(&export)?)?;
+
+        let mut recreated_group_db = Group::default();
+        let mut recreated_passwd_db = Passwd::default();
+        let mut recreated_shadow_db = Shadow::default();
+        update_users_and_groups(
+            &config,
+            &mut recreated_group_db,
+            &mut recreated_passwd_db,
+            &mut recreated_shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        assert_eq!(recreated_group_db.to_buffer(), group_db.to_buffer());
+        assert_eq!(recreated_passwd_db.to_buffer(), passwd_db.to_buffer());
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
-use std::{fs, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::unix::fs::{fchown, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
 
-use anyhow::{Context, Result};
+use crate::error::{Error, IoContext};
 
 /// Atomicaly write a buffer into a file.
 ///
@@ -8,10 +13,38 @@ use anyhow::{Context, Result};
 /// it's actual path.
 ///
 /// This increases the atomicity of the write.
-pub fn atomic_write(path: impl AsRef<Path>, buffer: impl AsRef<[u8]>, mode: u32) -> Result<()> {
+///
+/// If the file already exists, its mode and ownership are preserved. `mode` is only used for new
+/// files. The write fails if the temporary file ends up with a more permissive mode, so that e.g.
+/// the content of /etc/shadow is never exposed before the rename.
+///
+/// A missing parent directory is created with mode `0755`.
+pub fn atomic_write(
+    path: impl AsRef<Path>,
+    buffer: impl AsRef<[u8]>,
+    mode: u32,
+) -> Result<(), Error> {
+    atomic_write_with(path, mode, |writer| writer.write_all(buffer.as_ref()))
+}
+
+/// Atomically write into a file via a closure that writes into a buffered writer.
+///
+/// This behaves like [`atomic_write`] but doesn't require the whole content in memory.
+pub fn atomic_write_with(
+    path: impl AsRef<Path>,
+    mode: u32,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<(), Error> {
+    let existing_metadata = fs::metadata(path.as_ref()).ok();
+    let mode = existing_metadata
+        .as_ref()
+        .map_or(mode, |metadata| metadata.permissions().mode() & 0o7777);
+
+    create_parent_directory(path.as_ref())?;
+
     let mut i = 0;
 
-    let (mut file, tmp_path) = loop {
+    let (file, tmp_path) = loop {
         let mut tmp_path = path.as_ref().as_os_str().to_os_string();
         tmp_path.push(format!(".tmp{i}"));
 
@@ -25,20 +58,312 @@ pub fn atomic_write(path: impl AsRef<Path>, buffer: impl AsRef<[u8]>, mode: u32)
             Ok(file) => break (file, tmp_path),
             Err(err) => {
                 if err.kind() != std::io::ErrorKind::AlreadyExists {
-                    return Err(err).context(format!("Failed to open temporary file {tmp_path:?}"));
+                    return Err(err)
+                        .io_context(|| format!("Failed to open temporary file {tmp_path:?}"));
                 }
             }
         }
         i += 1;
     };
 
-    file.write_all(buffer.as_ref())
-        .with_context(|| format!("Failed to write to {tmp_path:?}"))?;
+    if let Some(metadata) = existing_metadata {
+        // The mode passed to `open()` is restricted by the umask, so set it explicitly.
+        file.set_permissions(fs::Permissions::from_mode(mode))
+            .io_context(|| format!("Failed to set mode of {tmp_path:?}"))?;
+        fchown(&file, Some(metadata.uid()), Some(metadata.gid()))
+            .io_context(|| format!("Failed to set owner of {tmp_path:?}"))?;
+    }
+
+    // Check the mode of the opened file itself so that nothing can swap it out in between.
+    let actual_mode = file
+        .metadata()
+        .io_context(|| format!("Failed to read the mode of {tmp_path:?}"))?
+        .permissions()
+        .mode()
+        & 0o7777;
+    if actual_mode & !mode != 0 {
+        drop(file);
+        let _ = fs::remove_file(&tmp_path);
+        return Err(Error::InsecureMode {
+            path: tmp_path.into(),
+            mode: actual_mode,
+            expected: mode,
+        });
+    }
+
+    let mut writer = BufWriter::new(file);
+    write(&mut writer).io_context(|| format!("Failed to write to {tmp_path:?}"))?;
+    let file = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+        .io_context(|| format!("Failed to write to {tmp_path:?}"))?;
     file.sync_all()
-        .with_context(|| format!("Failed to sync the temporary file {tmp_path:?}"))?;
+        .io_context(|| format!("Failed to sync the temporary file {tmp_path:?}"))?;
 
     fs::rename(&tmp_path, &path)
-        .with_context(|| format!("Failed to rename {tmp_path:?} to {:?}", path.as_ref()))?;
+        .io_context(|| format!("Failed to rename {tmp_path:?} to {:?}", path.as_ref()))?;
 
     Ok(())
 }
+
+/// Create the parent directory of `path` (and its ancestors) if it doesn't exist yet.
+///
+/// This is for example the case for fresh image builds where `/etc` doesn't exist yet.
+fn create_parent_directory(path: &Path) -> Result<(), Error> {
+    let Some(directory) = path.parent().filter(|p| !p.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    if directory.is_dir() {
+        return Ok(());
+    }
+    log::info!("Creating directory {directory:?}...");
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(directory)
+        .io_context(|| format!("Failed to create directory {directory:?}"))
+}
+
+/// Atomically write into a file unless the file already has exactly this content.
+///
+/// The content is written via a closure so that it never has to be held in memory as a whole.
+/// The closure is called once to compare the content with the file and, if they differ, once more
+/// to write the file.
+///
+/// Returns whether the file was written.
+pub fn write_if_changed(
+    path: impl AsRef<Path>,
+    mode: u32,
+    write: impl Fn(&mut dyn Write) -> io::Result<()>,
+) -> Result<bool, Error> {
+    if has_content(path.as_ref(), &write) {
+        log::debug!("Skipping {:?} because it hasn't changed.", path.as_ref());
+        return Ok(false);
+    }
+    atomic_write_with(path, mode, write)?;
+    Ok(true)
+}
+
+/// The mode of the file at `path` if it grants any permissions beyond `max_mode`.
+pub fn excess_mode(path: &Path, max_mode: u32) -> Option<u32> {
+    let mode = fs::metadata(path).ok()?.permissions().mode() & 0o7777;
+    (mode & !max_mode != 0).then_some(mode)
+}
+
+/// Remove all permissions beyond `max_mode` from the file at `path`.
+///
+/// All other permissions are kept, so a mode is only ever restricted and never widened. A missing
+/// file is left alone.
+///
+/// Returns whether the mode was changed.
+pub fn restrict_mode(path: &Path, max_mode: u32) -> Result<bool, Error> {
+    let Some(mode) = excess_mode(path, max_mode) else {
+        return Ok(false);
+    };
+    let restricted = mode & max_mode;
+    log::info!("Restricting mode of {path:?} from {mode:04o} to {restricted:04o}...");
+    fs::set_permissions(path, fs::Permissions::from_mode(restricted))
+        .io_context(|| format!("Failed to set mode of {path:?}"))?;
+    Ok(true)
+}
+
+/// Whether the file at `path` has exactly the content written by `write`.
+pub fn has_content(path: &Path, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let mut comparison = Comparison {
+        reader: BufReader::new(file),
+        equal: true,
+    };
+    if write(&mut comparison).is_err() {
+        return false;
+    }
+    // The file must not contain anything beyond the written content.
+    comparison.equal && comparison.reader.fill_buf().is_ok_and(<[u8]>::is_empty)
+}
+
+/// A writer that compares everything written to it with the content of a reader.
+struct Comparison<R> {
+    reader: R,
+    equal: bool,
+}
+
+impl<R: BufRead> Write for Comparison<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.equal {
+            let mut existing = vec![0; buf.len()];
+            self.equal = self.reader.read_exact(&mut existing).is_ok() && existing == buf;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The number of bytes written by `write` without keeping them in memory.
+pub fn written_bytes(write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> usize {
+    let mut counter = ByteCounter(0);
+    // Counting never fails, so neither does `write`.
+    let _ = write(&mut counter);
+    counter.0
+}
+
+/// A writer that only counts the bytes written to it.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A temporary directory for tests that is removed again when it is dropped.
+///
+/// The name is suffixed with the process ID so that concurrent test runs don't collide.
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> io::Result<Self> {
+        let path =
+            std::env::temp_dir().join(format!("userborn-test-{name}-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    #[test]
+    fn only_write_changed_files() -> Result<()> {
+        let test_dir = TestDir::new("write-if-changed")?;
+        let directory = test_dir.path();
+        let path = directory.join("passwd");
+
+        let write =
+            |content: &'static str| move |w: &mut dyn Write| w.write_all(content.as_bytes());
+
+        assert!(write_if_changed(&path, 0o644, write("root:x:0:0:::\n"))?);
+        assert!(!write_if_changed(&path, 0o644, write("root:x:0:0:::\n"))?);
+        assert!(write_if_changed(
+            &path,
+            0o644,
+            write("root:x:0:0::/root:\n")
+        )?);
+        assert_eq!(fs::read_to_string(&path)?, "root:x:0:0::/root:\n");
+        // Neither a prefix nor an extension of the content counts as unchanged.
+        assert!(write_if_changed(&path, 0o644, write("root:x:0:0::/root:"))?);
+        assert!(write_if_changed(
+            &path,
+            0o644,
+            write("root:x:0:0::/root:\n\n")
+        )?);
+        assert!(!write_if_changed(
+            &path,
+            0o644,
+            write("root:x:0:0::/root:\n\n")
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_mode_of_existing_file() -> Result<()> {
+        let test_dir = TestDir::new("preserve-mode")?;
+        let directory = test_dir.path();
+        let path = directory.join("shadow");
+
+        atomic_write(&path, "root:!*:1::::::\n", 0o600)?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        atomic_write(&path, "root:!:1::::::\n", 0o600)?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o640);
+        Ok(())
+    }
+
+    #[test]
+    fn restrict_permissive_mode() -> Result<()> {
+        let test_dir = TestDir::new("restrict-mode")?;
+        let directory = test_dir.path();
+        let path = directory.join("shadow");
+
+        assert!(!restrict_mode(&path, 0o640)?);
+
+        fs::write(&path, "root:!*:1::::::\n")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        assert_eq!(excess_mode(&path, 0o640), Some(0o644));
+        assert!(restrict_mode(&path, 0o640)?);
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o640);
+
+        // The restricted mode is kept when the file is written.
+        atomic_write(&path, "root:!:1::::::\n", 0o000)?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o640);
+
+        // A more restrictive mode is never widened.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        assert_eq!(excess_mode(&path, 0o640), None);
+        assert!(!restrict_mode(&path, 0o640)?);
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o600);
+        Ok(())
+    }
+
+    #[test]
+    fn restrictive_mode_of_temporary_file() -> Result<()> {
+        let test_dir = TestDir::new("temporary-file-mode")?;
+        let directory = test_dir.path();
+        let path = directory.join("shadow");
+        let tmp_path = directory.join("shadow.tmp0");
+
+        // The temporary file already has its final mode while the content is written.
+        let mut tmp_mode = None;
+        atomic_write_with(&path, 0o000, |w| {
+            tmp_mode = Some(fs::metadata(&tmp_path)?.permissions().mode() & 0o7777);
+            w.write_all(b"root:!*:1::::::\n")
+        })?;
+        assert_eq!(tmp_mode, Some(0o000));
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o7777, 0o000);
+        assert!(!tmp_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_missing_directory() -> Result<()> {
+        let test_dir = TestDir::new("missing-directory")?;
+        let base = test_dir.path();
+        let path = base.join("etc").join("passwd");
+
+        assert!(write_if_changed(&path, 0o644, |w| w.write_all(b"root:x:0:0:::\n"))?);
+        assert_eq!(fs::read_to_string(&path)?, "root:x:0:0:::\n");
+        assert!(base.join("etc").is_dir());
+
+        Ok(())
+    }
+}
@@ -1,24 +1,36 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     fs,
+    io::{self, Write},
     path::Path,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::Result;
 
-use crate::{fs::atomic_write, id};
+use crate::{
+    compat::CompatLines,
+    config::{AllocationDirection, GroupMemberMode, MemberOrder, ReservedId, SortOrder},
+    error::Error,
+    fs::write_if_changed,
+    id::{self, IdKind},
+};
+
+/// Mode of a newly created /etc/group unless another one is provided.
+pub const DEFAULT_MODE: u32 = 0o644;
 
 #[derive(Clone)]
 pub struct Entry {
     name: String,
     password: String,
     gid: u32,
-    user_list: BTreeSet<String>,
+    user_list: Vec<String>,
 }
 
 impl Entry {
     /// Create a new /etc/group entry.
-    pub fn new(name: String, gid: u32, user_list: BTreeSet<String>) -> Self {
+    ///
+    /// The members must already be free of duplicates (see [`arrange_members`]).
+    pub fn new(name: String, gid: u32, user_list: Vec<String>) -> Self {
         Self {
             name,
             password: "x".into(),
@@ -28,7 +40,17 @@ impl Entry {
     }
 
     /// Update an /etc/group entry.
-    pub fn update(&mut self, user_list: BTreeSet<String>) {
+    ///
+    /// With [`GroupMemberMode::Merge`], the existing members are kept and members from
+    /// `user_list` that are missing are added after them.
+    pub fn update(&mut self, user_list: Vec<String>, mode: GroupMemberMode) {
+        let user_list = match mode {
+            GroupMemberMode::Replace => user_list,
+            GroupMemberMode::Merge => arrange_members(
+                self.user_list.iter().cloned().chain(user_list),
+                MemberOrder::Declared,
+            ),
+        };
         if self.user_list != user_list {
             log::info!(
                 "Updating members of group {} from {:?} to {user_list:?}...",
@@ -46,6 +68,8 @@ impl Entry {
         if line.starts_with('#') {
             return None;
         }
+        // Only strip a trailing carriage return. Other whitespace may be part of the last field.
+        let line = line.strip_suffix('\r').unwrap_or(line);
         let mut fields = line.splitn(7, ':');
         Some(Self {
             name: fields.next()?.into(),
@@ -55,12 +79,12 @@ impl Entry {
         })
     }
 
-    fn to_line(&self) -> String {
+    fn to_line(&self, member_order: MemberOrder) -> String {
         [
             self.name.as_str(),
             self.password.as_str(),
             self.gid.to_string().as_str(),
-            join_group_members(&self.user_list).as_str(),
+            join_arranged_members(&self.user_list, member_order).as_str(),
         ]
         .join(":")
     }
@@ -73,19 +97,77 @@ impl Entry {
     pub fn gid(&self) -> u32 {
         self.gid
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.user_list
+    }
 }
 
 /// Split a string containing group members separated by `,` into a list.
-fn split_group_members(s: &str) -> BTreeSet<String> {
-    if s.is_empty() {
-        return BTreeSet::new();
+///
+/// Empty and duplicate members (e.g. from `,,foo,foo`) are skipped. The order is kept.
+pub fn split_group_members(s: &str) -> Vec<String> {
+    arrange_members(
+        s.split(',')
+            .filter(|member| !member.is_empty())
+            .map(ToString::to_string),
+        MemberOrder::Declared,
+    )
+}
+
+/// Remove duplicate members and bring them into the requested order.
+///
+/// Of duplicate members, the first one is kept.
+pub fn arrange_members(
+    members: impl IntoIterator<Item = String>,
+    member_order: MemberOrder,
+) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    let mut members = members
+        .into_iter()
+        .filter(|member| seen.insert(member.clone()))
+        .collect::<Vec<_>>();
+    if member_order == MemberOrder::Sorted {
+        members.sort();
     }
-    s.split(',').map(ToString::to_string).collect()
+    members
 }
 
 /// Join a list of group members into a string separating each group name with a `,`.
-fn join_group_members(v: &BTreeSet<String>) -> String {
-    v.clone().into_iter().collect::<Vec<_>>().join(",")
+///
+/// This joins the members directly into the string because groups like `nixbld` can have
+/// thousands of members.
+pub fn join_group_members<S: AsRef<str>>(v: impl IntoIterator<Item = S> + Copy) -> String {
+    let len = v
+        .into_iter()
+        .map(|member| member.as_ref().len() + 1)
+        .sum::<usize>();
+    let mut s = String::with_capacity(len);
+    for (i, member) in v.into_iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        s.push_str(member.as_ref());
+    }
+    s
+}
+
+/// Join members that are already free of duplicates in the requested order.
+///
+/// Sorting only sorts references so that the members aren't cloned on every write.
+pub fn join_arranged_members(members: &[String], member_order: MemberOrder) -> String {
+    match member_order {
+        MemberOrder::Declared => join_group_members(members),
+        MemberOrder::Sorted => {
+            let mut sorted = members.iter().collect::<Vec<_>>();
+            sorted.sort_unstable();
+            join_group_members(&sorted)
+        }
+    }
 }
 
 #[derive(Default)]
@@ -94,41 +176,122 @@ pub struct Group {
     entries: BTreeMap<u32, Entry>,
     /// A mapping from names to GIDs.
     gids: BTreeMap<String, u32>,
+    /// Names of groups that share a GID in the file this database was read from.
+    duplicate_gids: BTreeMap<u32, BTreeSet<String>>,
+    /// The order in which the entries are written.
+    sort_order: SortOrder,
+    /// The order in which the members of each group are written.
+    member_order: MemberOrder,
+    /// NIS compat lines that are passed through unchanged.
+    compat_lines: CompatLines,
 }
 
 impl Group {
-    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let file = fs::read_to_string(path.as_ref())
-            .with_context(|| format!("Failed to read {:?}.", path.as_ref()))?;
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = fs::read_to_string(path.as_ref()).map_err(|source| Error::ReadDatabase {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
 
         Ok(Self::from_buffer(&file))
     }
 
-    fn from_buffer(s: &str) -> Self {
-        let mut entries = BTreeMap::new();
-        let mut gids = BTreeMap::new();
+    /// Read the database from a string buffer.
+    ///
+    /// If multiple groups share a GID, only the last one is kept. Duplicates are recorded and can
+    /// be retrieved via `duplicate_gids()`.
+    ///
+    /// NIS compat lines (e.g. `+` or `-group`) are kept and written at their original position.
+    pub fn from_buffer(s: &str) -> Self {
+        let mut group = Self::default();
         for line in s.lines() {
-            if let Some(e) = Entry::from_line(line) {
-                entries.insert(e.gid, e.clone());
-                gids.insert(e.name.clone(), e.gid);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if CompatLines::is_compat_line(line) {
+                group.compat_lines.push(line);
+            } else if let Some(e) = Entry::from_line(line) {
+                group.compat_lines.entry(&e.name);
+                group.insert_parsed(e);
             } else {
                 log::warn!("Skipping group line because it cannot be parsed: {line}.");
             }
         }
-        Self { entries, gids }
+        group.compat_lines.finish();
+        group
     }
 
-    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<()> {
-        atomic_write(path, self.to_buffer(), 0o644)
+    /// Insert an entry read from a file, recording duplicate GIDs.
+    fn insert_parsed(&mut self, e: Entry) {
+        if let Some(existing_entry) = self.entries.get(&e.gid) {
+            let existing_name = existing_entry.name.clone();
+            log::warn!(
+                "Groups {existing_name} and {} share GID {}. Only keeping {}.",
+                e.name,
+                e.gid,
+                e.name
+            );
+            self.duplicate_gids
+                .entry(e.gid)
+                .or_default()
+                .extend([existing_name.clone(), e.name.clone()]);
+            // The dropped group must not resolve to the entry that replaced it.
+            if self.gids.get(&existing_name) == Some(&e.gid) {
+                self.gids.remove(&existing_name);
+            }
+        }
+
+        self.gids.insert(e.name.clone(), e.gid);
+        self.entries.insert(e.gid, e);
+    }
+
+    /// Write the group database to a file.
+    ///
+    /// `mode` is only used if the file doesn't exist yet. Returns whether the file has changed.
+    pub fn to_file(&self, path: impl AsRef<Path>, mode: u32) -> Result<bool, Error> {
+        write_if_changed(path, mode, |writer| self.to_writer(writer))
+    }
+
+    /// Write the database line by line into a writer.
+    pub fn to_writer(&self, mut writer: impl Write) -> io::Result<()> {
+        for line in self.lines() {
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
     }
 
     pub fn to_buffer(&self) -> String {
-        let mut s = String::new();
-        for entry in self.entries.values() {
-            s.push_str(&entry.to_line());
-            s.push('\n');
+        self.lines().map(|line| line + "\n").collect()
+    }
+
+    fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.compat_lines.interleave(
+            self.sorted_entries()
+                .into_iter()
+                .map(|entry| (entry.name.as_str(), entry.to_line(self.member_order))),
+        )
+    }
+
+    /// Set the order in which the entries are written.
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    /// Set the order in which the members of each group are written.
+    pub fn set_member_order(&mut self, member_order: MemberOrder) {
+        self.member_order = member_order;
+    }
+
+    /// The order in which the members of each group are written.
+    pub fn member_order(&self) -> MemberOrder {
+        self.member_order
+    }
+
+    /// The entries in the order in which they are written.
+    pub fn sorted_entries(&self) -> Vec<&Entry> {
+        let mut entries = self.entries();
+        if self.sort_order == SortOrder::ByName {
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
         }
-        s
+        entries
     }
 
     pub fn get(&self, name: &str) -> Option<&Entry> {
@@ -136,36 +299,142 @@ impl Group {
         gid.and_then(|gid| self.entries.get(gid))
     }
 
+    /// The group owning a GID.
+    pub fn get_by_gid(&self, gid: u32) -> Option<&Entry> {
+        self.entries.get(&gid)
+    }
+
     pub fn get_mut(&mut self, name: &str) -> Option<&mut Entry> {
         let gid = self.gids.get(name);
         gid.and_then(|gid| self.entries.get_mut(gid))
     }
 
-    pub fn insert(&mut self, entry: &Entry) -> Result<()> {
+    pub fn insert(&mut self, entry: Entry) -> Result<(), Error> {
         if self.entries.contains_key(&entry.gid) {
-            bail!("Group with GID {} already exists", entry.gid);
+            return Err(Error::IdTaken {
+                database: "group",
+                kind: IdKind::Gid,
+                id: entry.gid,
+            });
         }
 
         if self.gids.contains_key(&entry.name) {
-            bail!("Group {} already exists", entry.name);
+            return Err(Error::NameTaken {
+                database: "group",
+                name: entry.name,
+            });
         }
 
-        self.entries.entry(entry.gid).or_insert(entry.clone());
         self.gids.insert(entry.name.clone(), entry.gid);
+        self.entries.insert(entry.gid, entry);
 
         Ok(())
     }
 
+    /// Remove a group.
+    ///
+    /// Returns the removed entry.
+    pub fn remove(&mut self, name: &str) -> Option<Entry> {
+        let gid = self.gids.remove(name)?;
+        self.entries.remove(&gid)
+    }
+
+    /// Rename a group, keeping its GID.
+    ///
+    /// This will fail if a group with the new name already exists.
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), Error> {
+        if self.is_name_taken(new_name) {
+            return Err(Error::NameTaken {
+                database: "group",
+                name: new_name.into(),
+            });
+        }
+        let gid = self.gids.remove(name).ok_or_else(|| Error::NoSuchEntry {
+            database: "group",
+            name: name.into(),
+        })?;
+        if let Some(entry) = self.entries.get_mut(&gid) {
+            entry.name = new_name.into();
+        }
+        self.gids.insert(new_name.into(), gid);
+        Ok(())
+    }
+
+    /// Rename a member in all groups.
+    pub fn rename_member(&mut self, name: &str, new_name: &str) {
+        for entry in self.entries.values_mut() {
+            if entry.user_list.iter().any(|member| member == name) {
+                entry.user_list = arrange_members(
+                    entry.user_list.drain(..).map(|member| {
+                        if member == name {
+                            new_name.into()
+                        } else {
+                            member
+                        }
+                    }),
+                    MemberOrder::Declared,
+                );
+            }
+        }
+    }
+
+    /// Remove a member from all groups.
+    pub fn remove_member(&mut self, name: &str) {
+        for entry in self.entries.values_mut() {
+            let len = entry.user_list.len();
+            entry.user_list.retain(|member| member != name);
+            if entry.user_list.len() != len {
+                log::info!("Removed user {name} from group {}.", entry.name);
+            }
+        }
+    }
+
+    /// Groups sharing a GID in the file this database was read from.
+    pub fn duplicate_gids(&self) -> &BTreeMap<u32, BTreeSet<String>> {
+        &self.duplicate_gids
+    }
+
     /// Allocate a new (i.e. unused) GID.
     ///
     /// Returns `Err` if it cannot allocate a new GID because all in the range are already used.
-    pub fn allocate_gid(&self, is_normal: bool) -> Result<u32> {
+    pub fn allocate_gid(
+        &self,
+        reserved_ids: &[ReservedId],
+        is_normal: bool,
+        limit: Option<u32>,
+        direction: AllocationDirection,
+    ) -> Result<u32, Error> {
         let allocated_gids = self.entries.keys().copied().collect::<BTreeSet<u32>>();
-        id::allocate(&allocated_gids, is_normal)
+        id::allocate(
+            &allocated_gids,
+            reserved_ids,
+            is_normal,
+            limit,
+            direction,
+            IdKind::Gid,
+        )
     }
 
-    pub fn contains_gid(&self, gid: u32) -> bool {
-        self.entries.contains_key(&gid)
+    /// Whether no group has this GID.
+    pub fn is_gid_free(&self, gid: u32) -> bool {
+        !self.entries.contains_key(&gid)
+    }
+
+    /// Whether a group with this name exists.
+    pub fn is_name_taken(&self, name: &str) -> bool {
+        self.gids.contains_key(name)
+    }
+
+    pub fn entries(&self) -> Vec<&Entry> {
+        self.entries.values().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 }
 
@@ -176,6 +445,35 @@ mod tests {
     use expect_test::expect;
     use indoc::indoc;
 
+    #[test]
+    fn large_group() {
+        let members = arrange_members(
+            (0..30_000).map(|i| format!("nixbld{i}")),
+            MemberOrder::Sorted,
+        );
+
+        let joined = join_group_members(&members);
+        assert_eq!(joined.matches(',').count(), 29_999);
+        assert!(joined.starts_with("nixbld0,nixbld1,nixbld10,"));
+        assert_eq!(split_group_members(&joined), members);
+
+        let group = Group::from_buffer(&format!("nixbld:x:30000:{joined}\n"));
+        assert_eq!(group.get("nixbld").map(|e| e.members().len()), Some(30_000));
+        assert_eq!(group.to_buffer(), format!("nixbld:x:30000:{joined}\n"));
+
+        assert_eq!(join_group_members(&Vec::<String>::new()), "");
+
+        let declared = ["nixbld1", "nixbld0"].map(String::from);
+        assert_eq!(
+            join_arranged_members(&declared, MemberOrder::Sorted),
+            "nixbld0,nixbld1"
+        );
+        assert_eq!(
+            join_arranged_members(&declared, MemberOrder::Declared),
+            "nixbld1,nixbld0"
+        );
+    }
+
     #[test]
     fn sort() {
         let buffer = indoc! {"
@@ -194,6 +492,25 @@ mod tests {
         expected.assert_eq(&recreated_buffer);
     }
 
+    #[test]
+    fn sort_by_name() {
+        let mut group = Group::from_buffer(indoc! {"
+            wheel:x:1:peter
+            messagebus:x:4:
+            nixbld:x:30000:
+            audio:x:17:peter
+        "});
+        group.set_sort_order(SortOrder::ByName);
+
+        let expected = expect![[r#"
+            audio:x:17:peter
+            messagebus:x:4:
+            nixbld:x:30000:
+            wheel:x:1:peter
+        "#]];
+        expected.assert_eq(&group.to_buffer());
+    }
+
     #[test]
     fn skip_comments_and_broken_lines() {
         let buffer = indoc! {"
@@ -209,4 +526,102 @@ mod tests {
         "]];
         expected.assert_eq(&recreated_buffer);
     }
+
+    #[test]
+    fn detect_duplicates() {
+        let buffer = indoc! {"
+            wheel:x:1:peter
+            admins:x:1:gary
+            users:x:100:
+        "};
+        let group = Group::from_buffer(buffer);
+
+        assert_eq!(
+            group.duplicate_gids(),
+            &BTreeMap::from([(1, BTreeSet::from(["admins".into(), "wheel".into()]))])
+        );
+        assert!(group.get("wheel").is_none());
+        assert_eq!(group.get("admins").map(Entry::gid), Some(1));
+        assert_eq!(group.get_by_gid(1).map(Entry::name), Some("admins"));
+        assert!(group.get_by_gid(2).is_none());
+
+        let expected = expect![[r"
+            admins:x:1:gary
+            users:x:100:
+        "]];
+        expected.assert_eq(&group.to_buffer());
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let buffer = "wheel:x:1:peter,gary\r\nmessagebus:x:4:\r";
+        let group = Group::from_buffer(buffer);
+        let recreated_buffer = group.to_buffer();
+
+        let expected = expect![[r"
+            wheel:x:1:gary,peter
+            messagebus:x:4:
+        "]];
+        expected.assert_eq(&recreated_buffer);
+        assert_eq!(
+            Group::from_buffer(&recreated_buffer).to_buffer(),
+            recreated_buffer
+        );
+    }
+
+    #[test]
+    fn normalize_members() {
+        let buffer = indoc! {"
+            wheel:x:1:,,peter,,gary,peter,
+            messagebus:x:4:,
+        "};
+        let group = Group::from_buffer(buffer);
+        let recreated_buffer = group.to_buffer();
+
+        let expected = expect![[r"
+            wheel:x:1:gary,peter
+            messagebus:x:4:
+        "]];
+        expected.assert_eq(&recreated_buffer);
+        assert_eq!(split_group_members(",,foo,"), vec!["foo".to_string()]);
+    }
+
+    #[test]
+    fn nis_compat_lines() {
+        let buffer = indoc! {"
+            +@admins:::
+            root:x:0:
+            wheel:x:1:gary
+            +:::
+        "};
+        let group = Group::from_buffer(buffer);
+        assert_eq!(group.to_buffer(), buffer);
+        assert_eq!(group.len(), 2);
+    }
+
+    #[test]
+    fn declared_member_order() {
+        let buffer = indoc! {"
+            wheel:x:1:,,peter,,gary,peter,
+            messagebus:x:4:
+        "};
+        let mut group = Group::from_buffer(buffer);
+        group.set_member_order(MemberOrder::Declared);
+        let recreated_buffer = group.to_buffer();
+
+        let expected = expect![[r"
+            wheel:x:1:peter,gary
+            messagebus:x:4:
+        "]];
+        expected.assert_eq(&recreated_buffer);
+        assert_eq!(
+            Group::from_buffer(&recreated_buffer)
+                .get("wheel")
+                .map(Entry::members),
+            Some(["peter".to_string(), "gary".to_string()].as_slice())
+        );
+
+        group.rename_member("peter", "gary");
+        assert_eq!(group.to_buffer(), "wheel:x:1:gary\nmessagebus:x:4:\n");
+    }
 }
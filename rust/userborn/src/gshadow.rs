@@ -0,0 +1,271 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+
+use crate::{
+    config::MemberOrder,
+    error::Error,
+    fs::write_if_changed,
+    group::{join_arranged_members, join_group_members, split_group_members, Group},
+};
+
+/// A locked group password.
+///
+/// This prevents users that are not members of the group from joining it via `newgrp(1)`.
+const PASSWORD_LOCKED: &str = "!";
+
+#[derive(Clone)]
+pub struct Entry {
+    name: String,
+    /// The hashed password for the group.
+    password: String,
+    /// Users that can change the password and the members of the group via `gpasswd(1)`.
+    administrators: BTreeSet<String>,
+    members: Vec<String>,
+}
+
+impl Entry {
+    /// Create a new /etc/gshadow entry.
+    ///
+    /// Without a hashed password, the password is locked.
+    pub fn new(
+        name: String,
+        hashed_password: Option<String>,
+        administrators: BTreeSet<String>,
+        members: Vec<String>,
+    ) -> Self {
+        Self {
+            name,
+            password: hashed_password.unwrap_or(PASSWORD_LOCKED.into()),
+            administrators,
+            members,
+        }
+    }
+
+    /// Update the administrators of an /etc/gshadow entry.
+    pub fn update_administrators(&mut self, administrators: BTreeSet<String>) {
+        if self.administrators != administrators {
+            log::info!(
+                "Updating administrators of group {} from {:?} to {administrators:?}...",
+                self.name,
+                self.administrators,
+            );
+            self.administrators = administrators;
+        }
+    }
+
+    /// Update the hashed password of an /etc/gshadow entry.
+    pub fn update_password(&mut self, password: String) {
+        if self.password != password {
+            log::info!("Updating password of group {}...", self.name);
+            self.password = password;
+        }
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Update the members of an /etc/gshadow entry.
+    ///
+    /// The members are always kept in sync with /etc/group, so this doesn't log anything.
+    pub fn update_members(&mut self, members: Vec<String>) {
+        self.members = members;
+    }
+
+    /// Read an entry from a single line from /etc/gshadow.
+    ///
+    /// Whenever a field in this line doesn't exist or cannot be parsed, returns `None`.
+    fn from_line(line: &str) -> Option<Self> {
+        if line.starts_with('#') {
+            return None;
+        }
+        // Only strip a trailing carriage return. Other whitespace may be part of the last field.
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let mut fields = line.splitn(4, ':');
+        Some(Self {
+            name: fields.next()?.into(),
+            password: fields.next()?.into(),
+            administrators: split_group_members(fields.next()?).into_iter().collect(),
+            members: split_group_members(fields.next()?),
+        })
+    }
+
+    fn to_line(&self, member_order: MemberOrder) -> String {
+        [
+            self.name.as_str(),
+            self.password.as_str(),
+            join_group_members(&self.administrators).as_str(),
+            join_arranged_members(&self.members, member_order).as_str(),
+        ]
+        .join(":")
+    }
+}
+
+#[derive(Default)]
+pub struct GShadow(BTreeMap<String, Entry>);
+
+impl GShadow {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = fs::read_to_string(path.as_ref()).map_err(|source| Error::ReadDatabase {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
+
+        Ok(Self::from_buffer(&file))
+    }
+
+    pub fn from_buffer(s: &str) -> Self {
+        let mut entries = BTreeMap::new();
+        for line in s.lines() {
+            if let Some(e) = Entry::from_line(line) {
+                entries.insert(e.name.clone(), e);
+            } else {
+                log::warn!("Skipping gshadow line because it cannot be parsed: {line}.");
+            }
+        }
+        Self(entries)
+    }
+
+    /// Write the gshadow database to a file.
+    ///
+    /// Sort the entries like the group database. Returns whether the file has changed.
+    pub fn to_file_sorted(&self, group: &Group, path: impl AsRef<Path>) -> Result<bool, Error> {
+        write_if_changed(path, 0o000, |writer| self.to_writer_sorted(group, writer))
+    }
+
+    /// Write the gshadow database line by line into a writer.
+    ///
+    /// Sort the entries like the group database.
+    pub fn to_writer_sorted(&self, group: &Group, mut writer: impl Write) -> io::Result<()> {
+        for line in self.lines_sorted(group) {
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
+    }
+
+    /// Write the gshadow database to a string buffer.
+    ///
+    /// Sort the entries like the group database.
+    pub fn to_buffer_sorted(&self, group: &Group) -> String {
+        self.lines_sorted(group).map(|line| line + "\n").collect()
+    }
+
+    fn lines_sorted<'a>(&'a self, group: &'a Group) -> impl Iterator<Item = String> + 'a {
+        group
+            .sorted_entries()
+            .into_iter()
+            .filter_map(|group_entry| {
+                let name = group_entry.name();
+                let gshadow_entry = self.get(name);
+                if gshadow_entry.is_none() {
+                    // This should only happen if the DB was somehow manually tampered with.
+                    log::warn!("Group DB contains entry for {name} that is not in GShadow DB");
+                }
+                gshadow_entry.map(|entry| entry.to_line(group.member_order()))
+            })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        self.0.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Entry> {
+        self.0.get_mut(name)
+    }
+
+    /// Remove the entry of a group.
+    pub fn remove(&mut self, name: &str) -> Option<Entry> {
+        self.0.remove(name)
+    }
+
+    /// Remove a user from the administrators and members of all groups.
+    pub fn remove_user(&mut self, name: &str) {
+        for entry in self.0.values_mut() {
+            entry.administrators.remove(name);
+            entry.members.retain(|member| member != name);
+        }
+    }
+
+    pub fn insert(&mut self, entry: Entry) -> Result<(), Error> {
+        if self.0.contains_key(&entry.name) {
+            return Err(Error::NameTaken {
+                database: "gshadow",
+                name: entry.name,
+            });
+        }
+
+        self.0.insert(entry.name.clone(), entry);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use expect_test::expect;
+    use indoc::indoc;
+
+    #[test]
+    fn sort() {
+        let group = Group::from_buffer(indoc! {"
+            wheel:x:1:peter
+            messagebus:x:4:
+            nixbld:x:30000:nixbld2,nixbld1
+        "});
+
+        let buffer = indoc! {"
+            nixbld:!::nixbld2,nixbld1
+            messagebus:!::
+            wheel:!:root:peter
+        "};
+        let gshadow = GShadow::from_buffer(buffer);
+        let recreated_buffer = gshadow.to_buffer_sorted(&group);
+
+        let expected = expect![[r#"
+            wheel:!:root:peter
+            messagebus:!::
+            nixbld:!::nixbld1,nixbld2
+        "#]];
+        expected.assert_eq(&recreated_buffer);
+    }
+
+    #[test]
+    fn skip_comments_and_broken_lines() {
+        let group = Group::from_buffer("wheel:x:1:peter");
+
+        let buffer = indoc! {"
+            # Comment
+            piel:!
+            wheel:!::peter
+        "};
+        let gshadow = GShadow::from_buffer(buffer);
+        let recreated_buffer = gshadow.to_buffer_sorted(&group);
+
+        let expected = expect![[r"
+            wheel:!::peter
+        "]];
+        expected.assert_eq(&recreated_buffer);
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let group = Group::from_buffer("wheel:x:1:peter\n");
+
+        let buffer = "wheel:!:root:peter\r";
+        let gshadow = GShadow::from_buffer(buffer);
+        let recreated_buffer = gshadow.to_buffer_sorted(&group);
+
+        let expected = expect![[r"
+            wheel:!:root:peter
+        "]];
+        expected.assert_eq(&recreated_buffer);
+    }
+}
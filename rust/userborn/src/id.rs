@@ -1,6 +1,62 @@
-use std::collections::BTreeSet;
+use std::{collections::BTreeSet, fmt, ops::Range};
 
-use anyhow::{bail, Result};
+use crate::{
+    config::{AllocationDirection, ReservedId},
+    error::Error,
+};
+
+/// IDs of system users/groups.
+const SYSTEM_RANGE: Range<u32> = 1..1000;
+/// IDs of normal users/groups.
+const NORMAL_RANGE: Range<u32> = 1000..30000;
+/// Subordinate UIDs/GIDs that are allocated automatically.
+///
+/// This matches the defaults of `SUB_UID_MIN` and `SUB_UID_MAX` in `login.defs(5)`.
+pub const SUB_ID_RANGE: Range<u32> = 100_000..600_100_001;
+/// The ID of the `nobody` user and the `nogroup`/`nobody` group.
+pub const NOBODY_ID: u32 = 65534;
+/// Fewer free IDs than this in a range after an allocation are logged as a warning.
+const LOW_IDS_THRESHOLD: usize = 20;
+
+/// The kind of ID that is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdKind {
+    Uid,
+    Gid,
+}
+
+impl fmt::Display for IdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uid => write!(f, "UID"),
+            Self::Gid => write!(f, "GID"),
+        }
+    }
+}
+
+/// Whether an ID belongs to a normal user/group.
+pub fn is_normal(id: u32) -> bool {
+    NORMAL_RANGE.contains(&id)
+}
+
+/// Whether an ID belongs to a system user/group.
+pub fn is_system(id: u32) -> bool {
+    SYSTEM_RANGE.contains(&id)
+}
+
+/// Whether an ID is contained in `reserved_ids`.
+pub fn is_reserved(id: u32, reserved_ids: &[ReservedId]) -> bool {
+    reserved_ids.iter().any(|r| r.contains(id))
+}
+
+/// The ID that users and groups with a well-known name should get instead of an allocated one.
+pub fn well_known(name: &str) -> Option<u32> {
+    match name {
+        "root" => Some(0),
+        "nobody" | "nogroup" => Some(NOBODY_ID),
+        _ => None,
+    }
+}
 
 /// Allocate a new UID/GID.
 ///
@@ -8,35 +64,164 @@ use anyhow::{bail, Result};
 ///
 /// System users/groups get an ID in the range from 1 to 999 (inclusive).
 ///
+/// The range is narrowed by `limit` (see [`allocation_range`]) to leave headroom. The first free
+/// ID in `direction` is chosen. IDs contained in `reserved_ids` are never allocated.
+///
+/// Warns if fewer than 20 free IDs remain in the range after the allocation.
+///
 /// Fails if there are no unused IDs in the respective ranges.
-pub fn allocate(already_allocated_ids: &BTreeSet<u32>, is_normal: bool) -> Result<u32> {
-    if is_normal {
-        for candidate in 1000u32..30000 {
-            if !already_allocated_ids.contains(&candidate) {
-                return Ok(candidate);
-            }
+pub fn allocate(
+    already_allocated_ids: &BTreeSet<u32>,
+    reserved_ids: &[ReservedId],
+    is_normal: bool,
+    limit: Option<u32>,
+    direction: AllocationDirection,
+    kind: IdKind,
+) -> Result<u32, Error> {
+    let (range_name, range) = allocation_range(is_normal, limit);
+    let is_free = |id: u32| !already_allocated_ids.contains(&id) && !is_reserved(id, reserved_ids);
+
+    let candidate = match direction {
+        AllocationDirection::Ascending => range.clone().find(|id| is_free(*id)),
+        AllocationDirection::Descending => range.clone().rev().find(|id| is_free(*id)),
+    };
+
+    let Some(candidate) = candidate else {
+        return Err(Error::AllocationExhausted { kind, range });
+    };
+    // Stop counting once there are enough so that a large range isn't scanned on every allocation.
+    let remaining = range
+        .clone()
+        .filter(|id| *id != candidate && is_free(*id))
+        .take(LOW_IDS_THRESHOLD)
+        .count();
+    if remaining < LOW_IDS_THRESHOLD {
+        log::warn!(
+            "Fewer than {LOW_IDS_THRESHOLD} {range_name} {kind}s remaining ({remaining} left in {}..{}).",
+            range.start,
+            range.end
+        );
+    }
+    Ok(candidate)
+}
+
+/// Describe how [`allocate`] picks an ID, e.g. "first free in system range 1..1000, descending".
+pub fn describe_allocation(
+    is_normal: bool,
+    limit: Option<u32>,
+    direction: AllocationDirection,
+) -> String {
+    let (range_name, range) = allocation_range(is_normal, limit);
+    format!(
+        "first free in {range_name} range {}..{}, {direction}",
+        range.start, range.end
+    )
+}
+
+/// The name and the range of IDs that are allocated to normal or system users/groups.
+///
+/// For system users/groups, `limit` is the lowest ID that is allocated (`systemIdFloor`). For
+/// normal users/groups, it is the highest one (`normalIdCeiling`). Limits are validated when the
+/// config is loaded. A limit outside of the range (e.g. from a config that was deserialized
+/// directly) is still clamped so that the range keeps at least its first ID.
+fn allocation_range(is_normal: bool, limit: Option<u32>) -> (&'static str, Range<u32>) {
+    let (range_name, range) = range(is_normal);
+    let range = match limit {
+        None => range,
+        Some(ceiling) if is_normal => {
+            range.start..ceiling.saturating_add(1).clamp(range.start + 1, range.end)
         }
+        Some(floor) => floor.clamp(range.start, range.end - 1)..range.end,
+    };
+    (range_name, range)
+}
+
+/// The name and the range of IDs of normal or system users/groups.
+pub(crate) fn range(is_normal: bool) -> (&'static str, Range<u32>) {
+    if is_normal {
+        ("normal", NORMAL_RANGE)
     } else {
-        for candidate in (1u32..1000).rev() {
-            if !already_allocated_ids.contains(&candidate) {
-                return Ok(candidate);
-            }
+        ("system", SYSTEM_RANGE)
+    }
+}
+
+/// The name of a range returned by [`allocation_range`].
+pub(crate) fn range_name(range: &Range<u32>) -> &'static str {
+    if range.start >= NORMAL_RANGE.start {
+        "normal"
+    } else {
+        "system"
+    }
+}
+
+/// The name of the range a static ID belongs to if it's the range of the other kind of users/groups.
+///
+/// E.g. a normal user with a UID in the system range. IDs outside of both ranges (like root's) are
+/// never unexpected.
+pub fn unexpected_range(id: u32, is_normal: bool) -> Option<&'static str> {
+    let (other_name, other_range) = range(!is_normal);
+    other_range.contains(&id).then_some(other_name)
+}
+
+/// Allocate a contiguous block of `count` IDs in `range` that doesn't overlap any `occupied` block.
+///
+/// Returns the start of the first block that is large enough.
+///
+/// Fails if there is no such block in the range.
+pub fn allocate_block(
+    occupied: &[Range<u32>],
+    count: u32,
+    range: Range<u32>,
+) -> Result<u32, Error> {
+    if count == 0 {
+        return Err(Error::EmptyBlock);
+    }
+
+    let mut occupied = occupied
+        .iter()
+        .filter(|block| block.start < range.end && block.end > range.start)
+        .collect::<Vec<_>>();
+    occupied.sort_by_key(|block| block.start);
+
+    let mut start = range.start;
+    for block in occupied {
+        if block.start.saturating_sub(start) >= count {
+            return Ok(start);
         }
-    };
-    bail!("Failed to allocated new UID")
+        start = start.max(block.end);
+    }
+    if range.end.saturating_sub(start) >= count {
+        return Ok(start);
+    }
+
+    Err(Error::BlockAllocationExhausted { count, range })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use anyhow::Result;
+
+    use crate::config::AllocationDirections;
+
     fn check_allocate_id(
         already_allocated_ids: impl IntoIterator<Item = u32>,
         is_normal: bool,
         expected: u32,
+    ) -> Result<()> {
+        check_allocate_id_reserved(already_allocated_ids, &[], is_normal, expected)
+    }
+
+    fn check_allocate_id_reserved(
+        already_allocated_ids: impl IntoIterator<Item = u32>,
+        reserved_ids: &[ReservedId],
+        is_normal: bool,
+        expected: u32,
     ) -> Result<()> {
         let uids = already_allocated_ids.into_iter().collect::<BTreeSet<u32>>();
-        let allocated = allocate(&uids, is_normal)?;
+        let direction = AllocationDirections::default().get(is_normal);
+        let allocated = allocate(&uids, reserved_ids, is_normal, None, direction, IdKind::Uid)?;
         assert_eq!(allocated, expected);
         Ok(())
     }
@@ -56,4 +241,170 @@ mod tests {
         assert!(check_allocate_id(999..30000, true, 1).is_err());
         Ok(())
     }
+
+    #[test]
+    fn allocate_skips_reserved_ids() -> Result<()> {
+        let reserved = [ReservedId::Single(1001), ReservedId::Range(1003, 1005)];
+        check_allocate_id_reserved([1000], &reserved, true, 1002)?;
+        check_allocate_id_reserved([1000, 1002], &reserved, true, 1006)?;
+
+        let reserved = [ReservedId::Range(500, 998)];
+        check_allocate_id_reserved([999], &reserved, false, 499)?;
+        Ok(())
+    }
+
+    #[test]
+    fn allocate_reserved_ids_exhausted() {
+        let reserved = [ReservedId::Single(1), ReservedId::Range(10, 999)];
+        assert!(check_allocate_id_reserved(2..10, &reserved, false, 1).is_err());
+
+        let reserved = [ReservedId::Range(1000, 29999)];
+        assert!(check_allocate_id_reserved([], &reserved, true, 1000).is_err());
+    }
+
+    #[test]
+    fn allocate_error_message() {
+        let gids = (1..1000).collect::<BTreeSet<u32>>();
+        let Err(err) = allocate(
+            &gids,
+            &[],
+            false,
+            None,
+            AllocationDirection::Descending,
+            IdKind::Gid,
+        ) else {
+            panic!("Allocation should fail");
+        };
+        assert!(matches!(
+            &err,
+            Error::AllocationExhausted { kind: IdKind::Gid, range } if *range == SYSTEM_RANGE
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Failed to allocate new GID in system range 1..1000 (all 999 IDs are in use or reserved)"
+        );
+    }
+
+    #[test]
+    fn allocate_in_both_directions() -> Result<()> {
+        let allocated = BTreeSet::from([1, 2, 4, 7, 997, 999, 1000, 1001, 1003, 29997, 29999]);
+        let allocate = |is_normal, direction| {
+            allocate(&allocated, &[], is_normal, None, direction, IdKind::Uid)
+        };
+
+        assert_eq!(allocate(false, AllocationDirection::Descending)?, 998);
+        assert_eq!(allocate(false, AllocationDirection::Ascending)?, 3);
+        assert_eq!(allocate(true, AllocationDirection::Ascending)?, 1002);
+        assert_eq!(allocate(true, AllocationDirection::Descending)?, 29998);
+        Ok(())
+    }
+
+    #[test]
+    fn allocate_within_limits() -> Result<()> {
+        let allocate = |allocated: &BTreeSet<u32>, is_normal, limit| {
+            let direction = AllocationDirections::default().get(is_normal);
+            allocate(allocated, &[], is_normal, limit, direction, IdKind::Uid)
+        };
+
+        // The system range is descending and stops at the floor.
+        let allocated = (501..1000).collect::<BTreeSet<u32>>();
+        assert_eq!(allocate(&allocated, false, Some(500))?, 500);
+        let allocated = (500..1000).collect::<BTreeSet<u32>>();
+        let Err(err) = allocate(&allocated, false, Some(500)) else {
+            panic!("Allocation below the floor should fail");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Failed to allocate new UID in system range 500..1000 (all 500 IDs are in use or reserved)"
+        );
+
+        // The normal range is ascending and stops at the ceiling.
+        let allocated = (1000..1999).collect::<BTreeSet<u32>>();
+        assert_eq!(allocate(&allocated, true, Some(1999))?, 1999);
+        let allocated = (1000..2000).collect::<BTreeSet<u32>>();
+        assert!(allocate(&allocated, true, Some(1999)).is_err());
+
+        // Limits outside of the ranges are clamped if the config wasn't validated.
+        let allocated = (1000..29999).collect::<BTreeSet<u32>>();
+        assert_eq!(allocate(&allocated, true, Some(40000))?, 29999);
+        let allocated = (1000..30000).collect::<BTreeSet<u32>>();
+        assert!(allocate(&allocated, true, Some(40000)).is_err());
+        assert_eq!(allocate(&BTreeSet::new(), false, Some(5000))?, 999);
+        assert_eq!(allocate(&BTreeSet::new(), true, Some(0))?, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn describe_allocations() {
+        assert_eq!(
+            describe_allocation(false, None, AllocationDirection::Descending),
+            "first free in system range 1..1000, descending"
+        );
+        assert_eq!(
+            describe_allocation(true, None, AllocationDirection::Ascending),
+            "first free in normal range 1000..30000, ascending"
+        );
+        assert_eq!(
+            describe_allocation(false, Some(500), AllocationDirection::Descending),
+            "first free in system range 500..1000, descending"
+        );
+    }
+
+    #[test]
+    fn detect_unexpected_range() {
+        assert_eq!(unexpected_range(500, true), Some("system"));
+        assert_eq!(unexpected_range(1000, false), Some("normal"));
+        assert_eq!(unexpected_range(1000, true), None);
+        assert_eq!(unexpected_range(0, false), None);
+        assert_eq!(unexpected_range(0, true), None);
+        assert_eq!(unexpected_range(NOBODY_ID, true), None);
+    }
+
+    /// Occupied blocks of single IDs.
+    fn single_ids(ids: &[u32]) -> Vec<Range<u32>> {
+        ids.iter().map(|&id| id..id + 1).collect()
+    }
+
+    #[test]
+    fn allocate_block_first_fit() -> Result<()> {
+        let occupied = single_ids(&[100, 101, 103, 104, 105, 110, 111]);
+
+        assert_eq!(allocate_block(&occupied, 1, 100..200)?, 102);
+        assert_eq!(allocate_block(&occupied, 3, 100..200)?, 106);
+        assert_eq!(allocate_block(&occupied, 4, 100..200)?, 106);
+        assert_eq!(allocate_block(&occupied, 5, 100..200)?, 112);
+        assert_eq!(allocate_block(&occupied, 88, 100..200)?, 112);
+        assert_eq!(allocate_block(&occupied, 2, 90..200)?, 90);
+        Ok(())
+    }
+
+    #[test]
+    fn allocate_block_exhausted() {
+        let occupied = single_ids(&[100, 101, 103, 104, 105, 110, 111]);
+
+        assert!(allocate_block(&occupied, 89, 100..200).is_err());
+        assert!(allocate_block(&occupied, 5, 100..112).is_err());
+        assert!(matches!(
+            allocate_block(&occupied, 0, 100..200),
+            Err(Error::EmptyBlock)
+        ));
+        assert!(allocate_block(&[], 1, 100..100).is_err());
+    }
+
+    #[test]
+    fn allocate_block_between_blocks() -> Result<()> {
+        // Overlapping and unsorted blocks.
+        let occupied = [200_000..265_536, 100_000..165_536, 150_000..170_000];
+
+        assert_eq!(
+            allocate_block(&occupied, 30_000, 100_000..400_000)?,
+            170_000
+        );
+        assert_eq!(
+            allocate_block(&occupied, 65_536, 100_000..400_000)?,
+            265_536
+        );
+        assert!(allocate_block(&occupied, 65_536, 100_000..300_000).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,116 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ReservedId,
+    error::{Error, IoContext},
+    fs::atomic_write,
+};
+
+/// A persistent record of the IDs that were assigned to users and groups.
+///
+/// When a user or group is created again after it has been removed from the databases, the ledger
+/// is consulted first so that they get their previous ID back.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Ledger {
+    /// UIDs keyed by user name.
+    #[serde(default)]
+    users: BTreeMap<String, u32>,
+    /// GIDs keyed by group name.
+    #[serde(default)]
+    groups: BTreeMap<String, u32>,
+}
+
+impl Ledger {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = fs::read_to_string(path.as_ref()).map_err(|source| Error::ReadDatabase {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
+
+        Self::from_buffer(&file)
+    }
+
+    fn from_buffer(s: &str) -> Result<Self, Error> {
+        serde_json::from_str(s).map_err(|source| Error::Ledger {
+            action: "parse",
+            source,
+        })
+    }
+
+    /// Write the ledger to a file.
+    ///
+    /// Creates the parent directory if it doesn't exist yet.
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)
+                .io_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        atomic_write(path, self.to_buffer()?, 0o644)?;
+        Ok(())
+    }
+
+    fn to_buffer(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|source| Error::Ledger {
+            action: "serialize",
+            source,
+        })
+    }
+
+    /// The UID previously assigned to the user.
+    pub fn uid(&self, name: &str) -> Option<u32> {
+        self.users.get(name).copied()
+    }
+
+    /// The GID previously assigned to the group.
+    pub fn gid(&self, name: &str) -> Option<u32> {
+        self.groups.get(name).copied()
+    }
+
+    /// All recorded UIDs as reserved IDs so that they are not allocated to other users.
+    pub fn reserved_uids(&self) -> Vec<ReservedId> {
+        self.users
+            .values()
+            .copied()
+            .map(ReservedId::Single)
+            .collect()
+    }
+
+    /// All recorded GIDs as reserved IDs so that they are not allocated to other groups.
+    pub fn reserved_gids(&self) -> Vec<ReservedId> {
+        self.groups
+            .values()
+            .copied()
+            .map(ReservedId::Single)
+            .collect()
+    }
+
+    pub fn record_uid(&mut self, name: &str, uid: u32) {
+        self.users.insert(name.into(), uid);
+    }
+
+    pub fn record_gid(&mut self, name: &str, gid: u32) {
+        self.groups.insert(name.into(), gid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let mut ledger = Ledger::default();
+        ledger.record_uid("normalo", 1000);
+        ledger.record_gid("wheel", 999);
+
+        let recreated_ledger = Ledger::from_buffer(&ledger.to_buffer()?)?;
+
+        assert_eq!(recreated_ledger, ledger);
+        assert_eq!(recreated_ledger.uid("normalo"), Some(1000));
+        assert_eq!(recreated_ledger.gid("wheel"), Some(999));
+        assert_eq!(recreated_ledger.uid("wheel"), None);
+        Ok(())
+    }
+}
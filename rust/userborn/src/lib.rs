@@ -0,0 +1,3779 @@
+//! Declaratively bear (manage) Linux users and groups.
+//!
+//! This is the engine behind the `userborn` binary. It reads and writes the password databases
+//! and creates and updates users and groups from a [`Config`].
+
+mod compat;
+pub mod config;
+pub mod error;
+pub mod export;
+mod fs;
+pub mod group;
+pub mod gshadow;
+pub mod id;
+pub mod ledger;
+pub mod passwd;
+mod password;
+pub mod shadow;
+pub mod subid;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+pub use error::Error;
+pub use fs::{excess_mode, has_content, restrict_mode};
+pub use password::{Hasher, XcryptHasher};
+
+use config::{Config, LockMode, MissingPrimaryGroup, PasswdMarker, ReservedId};
+use group::Group;
+use gshadow::GShadow;
+use id::IdKind;
+use ledger::Ledger;
+use passwd::Passwd;
+use password::{HashedPassword, PasswordRequest, ResolvedPasswords};
+use shadow::Shadow;
+use subid::SubId;
+
+/// Fallback path to the nologin binary.
+///
+/// This is used when `USERBORN_NO_LOGIN_PATH` is not set during runtime and
+/// `USERBORN_NO_LOGIN_DEFAULT_PATH` hasn't been set during compilation.
+const NO_LOGIN_FALLBACK: &str = "/run/current-system/sw/bin/nologin";
+/// Default path to the nolign binary.
+///
+/// This can be configured via a compile-time environment variable.
+const NO_LOGIN_DEFAULT: Option<&'static str> = option_env!("USERBORN_NO_LOGIN_DEFAULT_PATH");
+/// Names longer than this are not handled by all tools, e.g. `useradd(8)`.
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Check the databases read from disk for inconsistencies.
+///
+/// Duplicates are already logged when the databases are read. Fails if `strict` is set and
+/// there are any inconsistencies.
+///
+/// Without a shadow database (i.e. `--no-shadow`), users are not checked for shadow entries.
+pub fn check_consistency(
+    group_db: &Group,
+    passwd_db: &Passwd,
+    shadow_db: Option<&Shadow>,
+    strict: bool,
+) -> Result<(), Error> {
+    let mut count = group_db.duplicate_gids().len()
+        + passwd_db.duplicate_uids().len()
+        + passwd_db.duplicate_names().len();
+
+    if let Some(shadow_db) = shadow_db {
+        for name in shadow_db.find_orphans(passwd_db) {
+            log::warn!("User {name} is in the shadow database but not in the passwd database.");
+            count += 1;
+        }
+        for name in passwd_db.find_orphans(shadow_db) {
+            log::warn!("User {name} is in the passwd database but not in the shadow database.");
+            count += 1;
+        }
+    }
+    for name in passwd_db.users_with_missing_primary_group(group_db) {
+        let gid = passwd_db
+            .get(&name)
+            .map(passwd::Entry::gid)
+            .unwrap_or_default();
+        log::warn!("The primary group with GID {gid} of user {name} is not in the group database.");
+        count += 1;
+    }
+
+    // The following are only suspicious, so they are not counted as inconsistencies. E.g. the
+    // group of a system user is often declared without members.
+    for name in passwd_db.users_missing_from_own_group(group_db) {
+        log::warn!("User {name} is not a member of its own primary group {name}.");
+    }
+    for (name, uid, gid) in passwd_db.users_with_mismatched_group_id(group_db) {
+        log::warn!("User {name} has UID {uid} but the group {name} has GID {gid}.");
+    }
+    for (home, names) in passwd_db.duplicate_homes() {
+        log::warn!(
+            "Users {} share the home directory {home}.",
+            names.join(", ")
+        );
+    }
+
+    if count == 0 {
+        return Ok(());
+    }
+    if strict {
+        return Err(Error::Inconsistent { count });
+    }
+    log::warn!("Found {count} inconsistencies in the password databases.");
+    Ok(())
+}
+
+/// Resolve disagreements between the passwd and the shadow database.
+///
+/// The passwd database is authoritative for which users exist and the shadow database for the
+/// password hashes:
+///
+/// - Shadow entries of users that are not in the passwd database are removed.
+/// - Users that are not in the shadow database get a shadow entry. A hash from their passwd
+///   password field is moved there, otherwise the password is locked.
+/// - Password fields in the passwd database that don't point to the shadow database are reset.
+///
+/// Returns the number of resolved discrepancies. Each one is logged.
+pub fn reconcile(passwd_db: &mut Passwd, shadow_db: &mut Shadow) -> usize {
+    let mut count = 0;
+    for name in shadow_db.find_orphans(passwd_db) {
+        log::warn!(
+            "User {name} is not in the passwd database. Removing it from the shadow database."
+        );
+        shadow_db.remove(&name);
+        count += 1;
+    }
+    for entry in passwd_db.entries_mut() {
+        let name = entry.name().to_owned();
+        let password = entry.take_password();
+        if shadow_db.get(&name).is_some() {
+            if password.is_some() {
+                log::warn!(
+                    "User {name} has a password in the passwd database. Using the one from the shadow database instead."
+                );
+                count += 1;
+            }
+            continue;
+        }
+        let hash = password.filter(|password| password.starts_with('$'));
+        log::warn!(
+            "User {name} is not in the shadow database. Adding it with {}.",
+            if hash.is_some() {
+                "the hash from the passwd database"
+            } else {
+                "a locked password"
+            }
+        );
+        if let Err(e) = shadow_db.insert(shadow::Entry::new(name.clone(), hash)) {
+            log::error!("Failed to add user {name} to the shadow database: {e:#}");
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Report static UIDs and GIDs from the config that are already held by a different user or group.
+///
+/// When applying the config, these only show up as failures to insert the entries. This simulates
+/// the allocation of [`update_users_and_groups`] over a copy of the IDs in the databases, so
+/// collisions between users and groups in the config are found as well. Existing users and groups
+/// keep their IDs and renamed users keep the UID (and possibly the primary group) of their previous
+/// name.
+///
+/// Returns the number of collisions. Each one is logged.
+pub fn check_id_collisions(config: &Config, group_db: &Group, passwd_db: &Passwd) -> usize {
+    let mut uids: BTreeMap<u32, String> = passwd_db
+        .entries()
+        .into_iter()
+        .map(|entry| (entry.uid(), entry.name().to_owned()))
+        .collect();
+    let mut gids: BTreeMap<u32, String> = group_db
+        .entries()
+        .into_iter()
+        .map(|entry| (entry.gid(), entry.name().to_owned()))
+        .collect();
+    let mut users: BTreeSet<String> = uids.values().cloned().collect();
+    let mut groups: BTreeSet<String> = gids.values().cloned().collect();
+
+    for user_config in &config.users {
+        let name = &user_config.name;
+        if users.contains(name) {
+            continue;
+        }
+        let Some(previous_name) = user_config
+            .previous_names
+            .iter()
+            .find(|previous_name| users.contains(*previous_name))
+        else {
+            continue;
+        };
+        for holder in uids.values_mut().filter(|holder| *holder == previous_name) {
+            holder.clone_from(name);
+        }
+        users.remove(previous_name);
+        users.insert(name.clone());
+
+        // The primary group is renamed together with the user, see `rename_user()`.
+        let primary_group = passwd_db
+            .get(previous_name)
+            .and_then(|entry| gids.get_mut(&entry.gid()))
+            .filter(|holder| *holder == previous_name);
+        let is_declared = config.groups.iter().any(|g| g.name == *previous_name);
+        if let Some(holder) = primary_group.filter(|_| !is_declared && !groups.contains(name)) {
+            holder.clone_from(name);
+            groups.remove(previous_name);
+            groups.insert(name.clone());
+        }
+    }
+
+    let mut count = 0;
+    let mut claim =
+        |ids: &mut BTreeMap<u32, String>, kind: IdKind, id: u32, name: &str| match ids.get(&id) {
+            Some(holder) if holder != name => {
+                log::error!("{kind} {id} requested for {name} but already held by {holder}.");
+                count += 1;
+            }
+            Some(_) => {}
+            None => {
+                ids.insert(id, name.to_owned());
+            }
+        };
+
+    for group_config in &config.groups {
+        if config::validate_name("group", &group_config.name).is_err()
+            || !groups.insert(group_config.name.clone())
+        {
+            continue;
+        }
+        if let Some(gid) = group_config.gid {
+            claim(&mut gids, IdKind::Gid, gid, &group_config.name);
+        }
+    }
+
+    for user_config in &config.users {
+        if config::validate_name("user", &user_config.name).is_err()
+            || !users.insert(user_config.name.clone())
+        {
+            continue;
+        }
+        if let Some(uid) = user_config.uid {
+            claim(&mut uids, IdKind::Uid, uid, &user_config.name);
+        }
+        // The group of the user is only created if no primary group is provided.
+        if user_config.group.is_none() && groups.insert(user_config.name.clone()) {
+            if let Some(gid) = user_config.group_id {
+                claim(&mut gids, IdKind::Gid, gid, &user_config.name);
+            }
+        }
+    }
+
+    count
+}
+
+/// Create and update users and groups in the provided databases.
+///
+/// Doesn't actually write anything to disk, only mutates the databases and the ledger in memory.
+/// Plaintext passwords are hashed via `hasher`, usually [`XcryptHasher`].
+pub fn update_users_and_groups(
+    config: &Config,
+    group_db: &mut Group,
+    passwd_db: &mut Passwd,
+    shadow_db: &mut Shadow,
+    ledger: &mut Ledger,
+    hasher: &dyn Hasher,
+) {
+    rename_users(config, group_db, passwd_db, shadow_db, ledger);
+
+    for group_config in &config.groups {
+        if !is_valid_name("group", &group_config.name) {
+            continue;
+        }
+        if let Some(existing_entry) = group_db.get_mut(&group_config.name) {
+            existing_entry.update(
+                group::arrange_members(config.group_members(group_config), config.member_order),
+                config.group_member_mode,
+            );
+            ledger.record_gid(&group_config.name, existing_entry.gid());
+        } else if let Err(e) = create_group(config, group_config, group_db, ledger) {
+            log::error!("Failed to create group {}: {e:#}", group_config.name);
+        };
+    }
+
+    // Resolve all passwords before modifying the databases so that they can be hashed in parallel.
+    let users = config
+        .users
+        .iter()
+        .filter(|user_config| {
+            !config.no_shadow
+                // Users with invalid names are skipped below.
+                && config::validate_name("user", &user_config.name).is_ok()
+                && !user_config.locked
+                && !user_config.lock_password
+                // The passwords of existing immutable users are never used.
+                && (!user_config.immutable || !passwd_db.is_name_taken(&user_config.name))
+        })
+        .map(|user_config| {
+            let existing_password = shadow_db
+                .get(&user_config.name)
+                .map(shadow::Entry::password);
+            let current_password = reusable_hash(config, existing_password);
+            PasswordRequest {
+                name: &user_config.name,
+                config: &user_config.password,
+                current_password,
+                replaced_password: existing_password.filter(|_| current_password.is_none()),
+            }
+        })
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    let mut passwords = ResolvedPasswords::resolve(hasher, &users);
+    log::debug!(
+        "Resolved the passwords of {} users in {:?}.",
+        users.len(),
+        start.elapsed()
+    );
+
+    let mut users_in_config: BTreeSet<&str> = BTreeSet::new();
+
+    for user_config in &config.users {
+        if !is_valid_name("user", &user_config.name) {
+            continue;
+        }
+        users_in_config.insert(&user_config.name);
+
+        if let Some(existing_entry) = passwd_db.get_mut(&user_config.name) {
+            ledger.record_uid(&user_config.name, existing_entry.uid());
+            if let Err(e) = update_user(
+                config,
+                existing_entry,
+                user_config,
+                group_db,
+                shadow_db,
+                ledger,
+                &mut passwords,
+            ) {
+                log::error!("Failed to update user {}: {e:#}", user_config.name);
+            };
+        } else if let Err(e) = create_user(
+            config,
+            user_config,
+            group_db,
+            passwd_db,
+            shadow_db,
+            ledger,
+            &mut passwords,
+        ) {
+            log::error!("Failed to create user {}: {e:#}", user_config.name);
+        };
+    }
+
+    // Without a shadow database, there is nothing to lock.
+    if config.no_shadow {
+        return;
+    }
+
+    lock_stale_users(config, &users_in_config, passwd_db, shadow_db);
+}
+
+/// Lock the accounts of users in the shadow database that are not in the config.
+///
+/// Users that are removed via `absentUsers`, unmanaged users and root are not locked.
+fn lock_stale_users(
+    config: &Config,
+    users_in_config: &BTreeSet<&str>,
+    passwd_db: &mut Passwd,
+    shadow_db: &mut Shadow,
+) {
+    for entry in shadow_db.entries_mut() {
+        if users_in_config.contains(entry.name()) {
+            continue;
+        }
+        if config.absent_users.iter().any(|name| name == entry.name()) {
+            // The user is removed via `remove_absent()` instead.
+            continue;
+        }
+        if config
+            .unmanaged_users
+            .iter()
+            .any(|name| name == entry.name())
+        {
+            log::debug!(
+                "User {} is not in the config but unmanaged. Not locking it.",
+                entry.name()
+            );
+            continue;
+        }
+        if is_root(entry.name(), passwd_db) {
+            // Locking root could make the system unrecoverable, so never do it implicitly.
+            log::warn!(
+                "User {} is not in the config. Not locking the account because it is root.",
+                entry.name()
+            );
+            continue;
+        }
+        log::info!("Locking account for user {}...", entry.name());
+        entry.lock_account(config.lock_mode);
+        if config.lock_sets_nologin {
+            if let Some(passwd_entry) = passwd_db.get_mut(entry.name()) {
+                passwd_entry.update(None, None, None, Some(no_login_path(config)));
+            }
+        }
+    }
+}
+
+/// Rename users that don't exist yet but exist under one of their previous names.
+///
+/// The UID and the password are kept. The primary group is renamed as well if it has the previous
+/// name of the user (i.e. it was created together with the user) and isn't declared in the config.
+fn rename_users(
+    config: &Config,
+    group_db: &mut Group,
+    passwd_db: &mut Passwd,
+    shadow_db: &mut Shadow,
+    ledger: &mut Ledger,
+) {
+    for user_config in &config.users {
+        let name = &user_config.name;
+        // Invalid names are reported when the users are created.
+        if passwd_db.is_name_taken(name) || config::validate_name("user", name).is_err() {
+            continue;
+        }
+        let Some(previous_name) = user_config
+            .previous_names
+            .iter()
+            .find(|previous_name| passwd_db.is_name_taken(previous_name))
+        else {
+            continue;
+        };
+
+        log::info!("Renaming user {previous_name} to {name}...");
+        if let Err(e) = rename_user(
+            config,
+            previous_name,
+            name,
+            group_db,
+            passwd_db,
+            shadow_db,
+            ledger,
+        ) {
+            log::error!("Failed to rename user {previous_name} to {name}: {e:#}");
+        }
+    }
+}
+
+fn rename_user(
+    config: &Config,
+    previous_name: &str,
+    name: &str,
+    group_db: &mut Group,
+    passwd_db: &mut Passwd,
+    shadow_db: &mut Shadow,
+    ledger: &mut Ledger,
+) -> Result<()> {
+    passwd_db.rename(previous_name, name)?;
+    if shadow_db.get(previous_name).is_some() {
+        shadow_db.rename(previous_name, name)?;
+    }
+    group_db.rename_member(previous_name, name);
+
+    let gid = passwd_db.get(name).map(passwd::Entry::gid);
+    let is_primary_group = group_db
+        .get(previous_name)
+        .is_some_and(|group_entry| Some(group_entry.gid()) == gid);
+    let is_declared = config.groups.iter().any(|g| g.name == previous_name);
+    if is_primary_group && !is_declared && !group_db.is_name_taken(name) {
+        log::info!("Renaming group {previous_name} to {name}...");
+        group_db.rename(previous_name, name)?;
+        if let Some(gid) = gid {
+            ledger.record_gid(name, gid);
+        }
+    }
+
+    Ok(())
+}
+
+/// Create and update the gshadow entries of all groups in the group database.
+///
+/// The members are copied from the group database. The administrators and the password are only
+/// updated for groups in the config that specify them. Otherwise, new entries get a locked
+/// password.
+///
+/// Without a shadow database (i.e. `--no-shadow`), there is no gshadow database either. Group
+/// passwords are ignored then.
+///
+/// Plaintext group passwords are hashed via `hasher`, usually [`XcryptHasher`].
+pub fn update_gshadow(
+    config: &Config,
+    group_db: &Group,
+    gshadow_db: &mut GShadow,
+    hasher: &dyn Hasher,
+) {
+    if config.no_shadow {
+        for group_config in &config.groups {
+            if group_config.password.is_some() || group_config.hashed_password.is_some() {
+                log::warn!(
+                    "Ignoring the password of group {} because there is no gshadow database.",
+                    group_config.name
+                );
+            }
+        }
+        return;
+    }
+
+    for group_entry in group_db.entries() {
+        let group_config = config.groups.iter().find(|g| g.name == group_entry.name());
+        let administrators = group_config.and_then(|g| g.administrators.clone());
+        let current_password = reusable_hash(
+            config,
+            gshadow_db
+                .get(group_entry.name())
+                .map(gshadow::Entry::password),
+        );
+        let password = group_config
+            .map_or(Ok(None), |g| {
+                password::hash_group_password(g, current_password, hasher)
+            })
+            .unwrap_or_else(|e| {
+                log::error!(
+                    "Failed to hash password of group {}: {e:#}",
+                    group_entry.name()
+                );
+                None
+            });
+
+        if let Some(existing_entry) = gshadow_db.get_mut(group_entry.name()) {
+            existing_entry.update_members(group_entry.members().to_vec());
+            if let Some(administrators) = administrators {
+                existing_entry.update_administrators(administrators);
+            }
+            if let Some(password) = password {
+                existing_entry.update_password(password);
+            }
+        } else {
+            log::debug!("Creating gshadow entry for {}...", group_entry.name());
+            let new_entry = gshadow::Entry::new(
+                group_entry.name().into(),
+                password,
+                administrators.unwrap_or_default(),
+                group_entry.members().to_vec(),
+            );
+            if let Err(e) = gshadow_db.insert(new_entry) {
+                log::error!(
+                    "Failed to add entry to gshadow database for group {}: {e:#}",
+                    group_entry.name()
+                );
+            }
+        }
+    }
+}
+
+/// Remove the users and groups listed in `absentUsers` and `absentGroups`.
+///
+/// Users and groups that are also declared in the config, root, unmanaged users and groups that
+/// are still the primary group of a user are not removed.
+pub fn remove_absent(
+    config: &Config,
+    group_db: &mut Group,
+    passwd_db: &mut Passwd,
+    shadow_db: &mut Shadow,
+    gshadow_db: &mut GShadow,
+) {
+    for name in &config.absent_users {
+        if config.users.iter().any(|u| &u.name == name) {
+            log::error!("User {name} is declared in the config. Not removing it.");
+            continue;
+        }
+        if is_root(name, passwd_db) {
+            log::error!("User {name} is root. Not removing it.");
+            continue;
+        }
+        if config.unmanaged_users.contains(name) {
+            log::error!("User {name} is unmanaged. Not removing it.");
+            continue;
+        }
+        let passwd_entry = passwd_db.remove(name);
+        let shadow_entry = shadow_db.remove(name);
+        if passwd_entry.is_none() && shadow_entry.is_none() {
+            log::debug!("User {name} is already absent.");
+            continue;
+        }
+        group_db.remove_member(name);
+        gshadow_db.remove_user(name);
+        log::info!("Removed user {name}.");
+    }
+
+    for name in &config.absent_groups {
+        if config.groups.iter().any(|g| &g.name == name) {
+            log::error!("Group {name} is declared in the config. Not removing it.");
+            continue;
+        }
+        let Some(gid) = group_db.get(name).map(group::Entry::gid) else {
+            gshadow_db.remove(name);
+            log::debug!("Group {name} is already absent.");
+            continue;
+        };
+        if name == "root" || gid == 0 {
+            log::error!("Group {name} is the root group. Not removing it.");
+            continue;
+        }
+        if let Some(user) = passwd_db.entries().into_iter().find(|u| u.gid() == gid) {
+            log::error!(
+                "Group {name} is the primary group of user {}. Not removing it.",
+                user.name()
+            );
+            continue;
+        }
+        group_db.remove(name);
+        gshadow_db.remove(name);
+        log::info!("Removed group {name} with GID {gid}.");
+    }
+}
+
+/// Add the subordinate UIDs and GIDs of all users in the config.
+///
+/// Entries that are not in the config are kept and existing ranges are never reassigned.
+#[allow(clippy::similar_names)]
+pub fn update_subids(config: &Config, subuid_db: &mut SubId, subgid_db: &mut SubId) {
+    for user_config in &config.users {
+        // Invalid names are already reported when the users are created.
+        if config::validate_name("user", &user_config.name).is_err() {
+            continue;
+        }
+        update_subid(user_config, &user_config.sub_uid_ranges, subuid_db, "UIDs");
+        update_subid(user_config, &user_config.sub_gid_ranges, subgid_db, "GIDs");
+    }
+}
+
+/// Add the configured subordinate IDs of a single user to a database.
+///
+/// If the user has no subordinate IDs at all and requests them automatically, a new range that
+/// doesn't overlap any other range is allocated.
+fn update_subid(
+    user_config: &config::User,
+    ranges: &[config::SubIdRange],
+    subid_db: &mut SubId,
+    kind: &str,
+) {
+    let name = &user_config.name;
+
+    for range in ranges {
+        if !subid_db.contains(name, range.start, range.count) {
+            let entry = subid::Entry::new(name.clone(), range.start, range.count);
+            log::info!("Adding subordinate {kind} of user {}.", entry.describe());
+            subid_db.insert(entry);
+        }
+    }
+
+    let Some(count) = user_config.auto_sub_id_count else {
+        return;
+    };
+    if !subid_db.get(name).is_empty() {
+        return;
+    }
+    match id::allocate_block(&subid_db.ranges(), count, id::SUB_ID_RANGE) {
+        Ok(start) => {
+            let entry = subid::Entry::new(name.clone(), start, count);
+            log::info!("Allocated subordinate {kind} of user {}.", entry.describe());
+            subid_db.insert(entry);
+        }
+        Err(e) => log::error!("Failed to allocate subordinate {kind} for user {name}: {e:#}"),
+    }
+}
+
+/// Whether the name of a user or group is valid.
+///
+/// Logs an error if it is invalid and warns if it is longer than most tools can handle.
+fn is_valid_name(kind: &'static str, name: &str) -> bool {
+    if let Err(e) = config::validate_name(kind, name) {
+        log::error!(
+            "Skipping {kind} {name:?}: {:#}",
+            anyhow::Error::from(e).root_cause()
+        );
+        return false;
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        log::warn!("Name of {kind} {name} is longer than {MAX_NAME_LENGTH} bytes. Some tools might not handle it.");
+    }
+    true
+}
+
+/// Whether a user is root, i.e. is called `root` or has UID 0.
+fn is_root(name: &str, passwd_db: &Passwd) -> bool {
+    name == "root" || passwd_db.get(name).is_some_and(|entry| entry.uid() == 0)
+}
+
+/// Create a new group entry and add it to the database.
+///
+/// Returns the GID of the new group.
+///
+/// If no GID is provided, groups with a well-known name (e.g. `nogroup`) get their well-known GID.
+/// Otherwise, the GID previously recorded in the ledger is re-used if it is still free.
+fn create_group(
+    config: &Config,
+    group_config: &config::Group,
+    group_db: &mut Group,
+    ledger: &mut Ledger,
+) -> Result<u32> {
+    let reserved_ids = config.reserved_ids.as_slice();
+    let direction = config.allocation_directions.get(group_config.is_normal);
+
+    let gid = if let Some(gid) = group_config.gid {
+        warn_about_unexpected_range(gid, group_config.is_normal, "group", &group_config.name);
+        gid
+    } else if let Some(gid) =
+        id::well_known(&group_config.name).filter(|gid| group_db.is_gid_free(*gid))
+    {
+        gid
+    } else if let Some(gid) = ledger
+        .gid(&group_config.name)
+        .filter(|gid| is_reusable(*gid, reserved_ids, |gid| group_db.is_gid_free(gid)))
+    {
+        log::debug!(
+            "Re-using GID {gid} from ledger for group {}...",
+            group_config.name
+        );
+        gid
+    } else {
+        // Don't hand out GIDs that were previously assigned to other groups.
+        let reserved_ids = [reserved_ids, &ledger.reserved_gids()].concat();
+        let limit = config.allocation_limit(group_config.is_normal);
+        let gid = group_db.allocate_gid(&reserved_ids, group_config.is_normal, limit, direction)?;
+        log::info!(
+            "Allocated GID {gid} for {} ({}).",
+            group_config.name,
+            id::describe_allocation(group_config.is_normal, limit, direction)
+        );
+        gid
+    };
+    check_root_id("group", &group_config.name, gid)?;
+
+    let new_entry = group::Entry::new(
+        group_config.name.clone(),
+        gid,
+        group::arrange_members(config.group_members(group_config), config.member_order),
+    );
+
+    let description = new_entry.describe();
+
+    group_db
+        .insert(new_entry)
+        .with_context(|| format!("Failed to add group entry {}", group_config.name))?;
+
+    ledger.record_gid(&group_config.name, gid);
+
+    log::info!("Created group {description}.");
+
+    Ok(gid)
+}
+
+/// Create a new user entry and add it to the database.
+///
+/// Creates an entry both in the passwd and the shadow database.
+///
+/// If no UID is provided, users with a well-known name (e.g. `nobody`) get their well-known UID.
+/// Otherwise, the UID previously recorded in the ledger is re-used if it is still free.
+///
+/// If no primary group is provided, a group with the same name as the user is created. Its GID is
+/// `groupId` if it is set. Otherwise, it is the UID of the user if it is free and
+/// `userGroupReusesUid` is set. Otherwise, a new GID is allocated.
+///
+/// If no shell is provided, the default shell from the config is used. If that isn't set either,
+/// the nologin binary is used.
+#[allow(clippy::too_many_lines)]
+fn create_user(
+    config: &Config,
+    user_config: &config::User,
+    group_db: &mut Group,
+    passwd_db: &mut Passwd,
+    shadow_db: &mut Shadow,
+    ledger: &mut Ledger,
+    passwords: &mut ResolvedPasswords,
+) -> Result<()> {
+    log::debug!("Creating new passwd entry for {}...", user_config.name);
+
+    let reserved_ids = config.reserved_ids.as_slice();
+
+    let uid = if let Some(uid) = user_config.uid {
+        warn_about_unexpected_range(uid, user_config.is_normal, "user", &user_config.name);
+        uid
+    } else if let Some(uid) =
+        id::well_known(&user_config.name).filter(|uid| passwd_db.is_uid_free(*uid))
+    {
+        uid
+    } else if let Some(uid) = ledger
+        .uid(&user_config.name)
+        .filter(|uid| is_reusable(*uid, reserved_ids, |uid| passwd_db.is_uid_free(uid)))
+    {
+        log::debug!(
+            "Re-using UID {uid} from ledger for user {}...",
+            user_config.name
+        );
+        uid
+    } else if let Some(uid) = group_gid_as_uid(config, user_config, group_db, passwd_db, ledger) {
+        log::info!(
+            "Using the GID of group {name} as UID {uid} for user {name}.",
+            name = user_config.name
+        );
+        uid
+    } else {
+        // Don't hand out UIDs that were previously assigned to other users.
+        let reserved_ids = [reserved_ids, &ledger.reserved_uids()].concat();
+        let direction = config.allocation_directions.get(user_config.is_normal);
+        let limit = config.allocation_limit(user_config.is_normal);
+        let uid = passwd_db.allocate_uid(&reserved_ids, user_config.is_normal, limit, direction)?;
+        log::info!(
+            "Allocated UID {uid} for {} ({}).",
+            user_config.name,
+            id::describe_allocation(user_config.is_normal, limit, direction)
+        );
+        uid
+    };
+    check_root_id("user", &user_config.name, uid)?;
+
+    let gid = if let Some(ref primary_group) = user_config.group {
+        let gid = resolve_primary_group(config, user_config, primary_group, group_db, ledger)?;
+        check_group_id(user_config, primary_group, gid)?;
+        gid
+    } else if let (Some(_), Some(existing_entry)) =
+        (user_config.group_id, group_db.get(&user_config.name))
+    {
+        // The group was already created, e.g. because it is declared in the config as well.
+        check_group_id(user_config, &user_config.name, existing_entry.gid())?;
+        existing_entry.gid()
+    } else {
+        // If we cannot re-use the UID as GID (because it's already used) or shouldn't, allocate a
+        // new GID.
+        let gid = if user_config.group_id.is_some() {
+            user_config.group_id
+        } else if !config.user_group_reuses_uid || !group_db.is_gid_free(uid) {
+            None
+        } else {
+            Some(uid)
+        };
+
+        // The group lands in the same range as the user, even if the UID is in the range of the
+        // other kind of users (e.g. a normal user with a static UID in the system range).
+        let is_normal = if id::is_normal(uid) {
+            true
+        } else if id::is_system(uid) {
+            false
+        } else {
+            user_config.is_normal
+        };
+
+        // No group was provided so create a new group with the same name of the user and re-use
+        // the UID as GID.
+        let group_config = config::Group {
+            is_normal,
+            name: user_config.name.clone(),
+            gid,
+            members: vec![user_config.name.clone()],
+            merge_members: false,
+            administrators: None,
+            password: None,
+            hashed_password: None,
+        };
+
+        create_group(config, &group_config, group_db, ledger)
+            .with_context(|| format!("Failed to create group for user {}", user_config.name))?
+    };
+    check_root_id("primary group of user", &user_config.name, gid)?;
+
+    let mut new_entry = passwd::Entry::new(
+        user_config.name.clone(),
+        passwd_marker(config),
+        uid,
+        gid,
+        gecos(user_config, None).unwrap_or_default(),
+        user_config.home.clone().unwrap_or_default(),
+        user_config
+            .shell
+            .clone()
+            .or_else(|| config.default_shell.clone())
+            .unwrap_or_else(|| no_login_path(config)),
+    );
+
+    if config.no_shadow {
+        new_entry.set_password_without_shadow(
+            user_config.locked || user_config.lock_password,
+            passwd_marker(config),
+        );
+    }
+    if let Some(root) = &config.shell_root {
+        check_shell(&user_config.name, new_entry.shell(), root);
+    }
+
+    let description = new_entry.describe();
+
+    passwd_db.insert(new_entry).with_context(|| {
+        format!(
+            "Failed to add entry to passwd database for user {}",
+            user_config.name
+        )
+    })?;
+
+    ensure_shadow(config, user_config, shadow_db, passwords)?;
+
+    ledger.record_uid(&user_config.name, uid);
+
+    log::info!("Created user {description}.");
+    Ok(())
+}
+
+/// Path to the nologin binary.
+///
+/// `nologinPath` from the config takes precedence over the runtime environment variable
+/// `USERBORN_NO_LOGIN_PATH` which takes precedence over the compile-time default.
+pub fn no_login_path(config: &Config) -> String {
+    config.nologin_path.clone().unwrap_or_else(|| {
+        std::env::var("USERBORN_NO_LOGIN_PATH")
+            .unwrap_or(NO_LOGIN_DEFAULT.unwrap_or(NO_LOGIN_FALLBACK).into())
+    })
+}
+
+/// Check that the path to the nologin binary is absolute and warn if it doesn't exist.
+///
+/// The path is looked up inside the filesystem tree at `root`.
+pub fn check_no_login_path(path: &str, root: &Path) -> Result<(), Error> {
+    if !Path::new(path).is_absolute() {
+        return Err(Error::RelativeNoLoginPath { path: path.into() });
+    }
+    let rooted_path = root.join(path.trim_start_matches('/'));
+    if !rooted_path.exists() {
+        log::warn!(
+            "The nologin binary {} doesn't exist.",
+            rooted_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Warn if the shell of a user doesn't exist or isn't executable.
+///
+/// The path is looked up inside the filesystem tree at `root`. This is only advisory because the
+/// shell might be provided by a system that isn't running yet.
+fn check_shell(name: &str, shell: &str, root: &Path) {
+    let rooted_path = root.join(shell.trim_start_matches('/'));
+    match std::fs::metadata(&rooted_path) {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => {}
+        Ok(_) => log::warn!(
+            "The shell {} of user {name} is not executable.",
+            rooted_path.display()
+        ),
+        Err(_) => log::warn!(
+            "The shell {} of user {name} doesn't exist.",
+            rooted_path.display()
+        ),
+    }
+}
+
+/// The GID of the group with the same name as the user if it can be used as the user's UID.
+///
+/// This keeps the conventional alignment of UID and GID for users whose group already exists. The
+/// GID has to be in the range of the user, unused as UID and neither reserved nor previously
+/// assigned to another user.
+fn group_gid_as_uid(
+    config: &Config,
+    user_config: &config::User,
+    group_db: &Group,
+    passwd_db: &Passwd,
+    ledger: &Ledger,
+) -> Option<u32> {
+    let gid = group_db.get(&user_config.name)?.gid();
+    let in_range = if user_config.is_normal {
+        id::is_normal(gid)
+    } else {
+        id::is_system(gid)
+    };
+    let reserved_ids = [config.reserved_ids.as_slice(), &ledger.reserved_uids()].concat();
+    (in_range && is_reusable(gid, &reserved_ids, |uid| passwd_db.is_uid_free(uid))).then_some(gid)
+}
+
+/// Ensure that root gets the ID 0.
+///
+/// Creating root with another ID (e.g. because the config accidentally sets one) would leave the
+/// system without a working root account.
+fn check_root_id(kind: &str, name: &str, id: u32) -> Result<()> {
+    if name == "root" && id != 0 {
+        bail!("The {kind} root must have the ID 0, not {id}");
+    }
+    Ok(())
+}
+
+/// Ensure that the primary group of a user has the GID from `groupId` if it is set.
+fn check_group_id(user_config: &config::User, group: &str, gid: u32) -> Result<()> {
+    match user_config.group_id {
+        Some(group_id) if group_id != gid => bail!(
+            "Primary group {group} of user {} has GID {gid} but groupId is {group_id}",
+            user_config.name
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Warn if the static ID of a user or group is in the range of the other kind of users/groups.
+///
+/// The ID is still used, this is only meant to catch mistakes.
+fn warn_about_unexpected_range(id: u32, is_normal: bool, kind: &str, name: &str) {
+    if let Some(range_name) = id::unexpected_range(id, is_normal) {
+        let expected = if is_normal { "normal" } else { "system" };
+        log::warn!("The {expected} {kind} {name} has the ID {id} from the {range_name} range.");
+    }
+}
+
+/// Whether an ID recorded in the ledger can be re-used, i.e. is neither reserved nor allocated.
+fn is_reusable(id: u32, reserved_ids: &[ReservedId], is_free: impl Fn(u32) -> bool) -> bool {
+    !id::is_reserved(id, reserved_ids) && is_free(id)
+}
+
+/// Update an already existing user, directly mutating the passed entry.
+fn update_user(
+    config: &Config,
+    existing_entry: &mut passwd::Entry,
+    user_config: &config::User,
+    group_db: &mut Group,
+    shadow_db: &mut Shadow,
+    ledger: &mut Ledger,
+    passwords: &mut ResolvedPasswords,
+) -> Result<()> {
+    if user_config.immutable {
+        log::info!("User {} is immutable. Not updating it.", user_config.name);
+        return Ok(());
+    }
+
+    log::debug!("Updating passwd entry for {}...", user_config.name);
+
+    let gid = user_config.group.as_ref().and_then(|g| {
+        match resolve_primary_group(config, user_config, g, group_db, ledger) {
+            Ok(gid) => Some(gid),
+            Err(e) => {
+                log::error!(
+                    "Not updating primary group of user {}: {e:#}.",
+                    user_config.name
+                );
+                None
+            }
+        }
+    });
+
+    let gecos = gecos(user_config, Some(existing_entry));
+
+    existing_entry.update(
+        gid,
+        gecos,
+        user_config.home.clone(),
+        user_config.shell.clone(),
+    );
+    if config.no_shadow {
+        existing_entry.set_password_without_shadow(
+            user_config.locked || user_config.lock_password,
+            passwd_marker(config),
+        );
+    }
+    if let Some(root) = &config.shell_root {
+        check_shell(&user_config.name, existing_entry.shell(), root);
+    }
+
+    ensure_shadow(config, user_config, shadow_db, passwords)?;
+
+    Ok(())
+}
+
+/// The password field in /etc/passwd of accounts that aren't locked.
+///
+/// The marker from the config only applies without a shadow database. Otherwise it has to be `x`
+/// so that the password is looked up in /etc/shadow.
+fn passwd_marker(config: &Config) -> &'static str {
+    if config.no_shadow {
+        config.passwd_marker.as_str()
+    } else {
+        PasswdMarker::Shadow.as_str()
+    }
+}
+
+/// Assemble the GECOS field of a user from the config.
+///
+/// The raw `description` takes precedence over the structured `gecos`. Components of `gecos` that
+/// are not set are taken from the existing entry.
+fn gecos(user_config: &config::User, existing_entry: Option<&passwd::Entry>) -> Option<String> {
+    if let Some(description) = &user_config.description {
+        return Some(description.clone());
+    }
+    let gecos = user_config.gecos.clone()?;
+    let existing_gecos = existing_entry.map(passwd::Entry::gecos).unwrap_or_default();
+    Some(gecos.or(existing_gecos).to_string())
+}
+
+/// Resolve a string that can either be a group name or a GID to a proper GID.
+///
+/// Resolve GID from group name using the group database.
+fn resolve_group(s: &str, group_db: &Group) -> Result<u32> {
+    if let Ok(uid) = s.parse::<u32>() {
+        Ok(uid)
+    } else {
+        let existing_group_entry = group_db.get(s).ok_or(anyhow!("Group {s} doesn't exist"))?;
+        Ok(existing_group_entry.gid())
+    }
+}
+
+/// Resolve the primary group of a user to a GID.
+///
+/// A group name must exist. A GID that no group has is handled according to
+/// `missingPrimaryGroup`: it is used with a warning, is an error or a placeholder group with the
+/// name of the user is created for it.
+fn resolve_primary_group(
+    config: &Config,
+    user_config: &config::User,
+    primary_group: &str,
+    group_db: &mut Group,
+    ledger: &mut Ledger,
+) -> Result<u32> {
+    let gid = resolve_group(primary_group, group_db)?;
+    if !group_db.is_gid_free(gid) {
+        return Ok(gid);
+    }
+    let name = &user_config.name;
+    match config.missing_primary_group {
+        MissingPrimaryGroup::Warn => {
+            log::warn!("The primary group {gid} of user {name} doesn't exist.");
+        }
+        MissingPrimaryGroup::Error => bail!("The primary group {gid} of user {name} doesn't exist"),
+        MissingPrimaryGroup::Create => {
+            let group_config = config::Group {
+                is_normal: id::is_normal(gid),
+                name: name.clone(),
+                gid: Some(gid),
+                members: vec![name.clone()],
+                merge_members: false,
+                administrators: None,
+                password: None,
+                hashed_password: None,
+            };
+            create_group(config, &group_config, group_db, ledger)
+                .with_context(|| format!("Failed to create primary group {gid} of user {name}"))?;
+        }
+    }
+    Ok(gid)
+}
+
+/// Ensure that a shadow entry exists for the provided uses.
+///
+/// Updates an existing shadow entry or creates a new one.
+///
+/// The hashed password is taken from the passwords that were resolved ahead of time.
+///
+/// If the user is locked in the config, the account is locked regardless of the configured
+/// password. If `lockMode` is `prefix`, an account that is not locked in the config anymore and
+/// doesn't get a new password is unlocked.
+///
+/// With `lockPassword`, the password is replaced with an invalid one on creation and update.
+///
+/// The password aging fields are taken from the password policy of the user and the top-level
+/// password policy. Fields that are set in neither keep their existing value. With
+/// `disablePasswordAging`, all aging fields are cleared instead.
+///
+/// If `expirePassword` is set, the password is expired when the entry is created or its password
+/// is changed. It is not expired again after the user has changed it themselves.
+///
+/// Does nothing with `--no-shadow`.
+fn ensure_shadow(
+    config: &Config,
+    user_config: &config::User,
+    shadow_db: &mut Shadow,
+    passwords: &mut ResolvedPasswords,
+) -> Result<()> {
+    if config.no_shadow {
+        if user_config.password.is_set() {
+            log::warn!(
+                "Ignoring the password of user {} because there is no shadow database.",
+                user_config.name
+            );
+        }
+        return Ok(());
+    }
+
+    let password_policy = user_config.password_policy.or(&config.password_policy);
+
+    if let Some(existing_entry) = shadow_db.get_mut(&user_config.name) {
+        log::debug!("Updating shadow entry for {}...", user_config.name);
+
+        if user_config.disable_password_aging {
+            existing_entry.disable_password_aging();
+        } else {
+            existing_entry.update_password_policy(&password_policy);
+        }
+
+        if user_config.locked {
+            if !existing_entry.is_locked() {
+                log::info!("Locking account for user {}...", user_config.name);
+                existing_entry.lock_account(config.lock_mode);
+            }
+            return Ok(());
+        }
+
+        // With `rehashInsecure` and `--rehash-all`, the current hash is not re-used. If the
+        // plaintext password still matches it, only the hash is rotated but the password stays
+        // the same. An initial password is only rotated with `--rehash-all`.
+        let unchanged = reusable_hash(config, Some(existing_entry.password())).is_none()
+            && passwords.is_unchanged(
+                &user_config.name,
+                &user_config.password,
+                existing_entry.password(),
+            );
+        let rotate = config.rehash_all && unchanged;
+
+        let hashed_password = if user_config.lock_password {
+            Some(shadow::PASSWORD_LOCKED_AND_INVALID.into())
+        } else {
+            passwords
+                .take(
+                    &user_config.name,
+                    &user_config.password,
+                    reusable_hash(config, Some(existing_entry.password())),
+                )?
+                .and_then(|hashed_password| match hashed_password {
+                    HashedPassword::Override(s) => Some(s),
+                    HashedPassword::Initial(s) => rotate.then_some(s),
+                })
+        };
+
+        let has_hashed_password = hashed_password.is_some();
+        if unchanged {
+            if hashed_password.is_some_and(|s| existing_entry.rotate_hash(s)) {
+                log::info!("Rotated the password hash of user {}.", user_config.name);
+            }
+        } else if existing_entry.update(hashed_password) && user_config.expire_password {
+            log::info!("Expiring password of user {}...", user_config.name);
+            existing_entry.expire_password();
+        }
+
+        let unlocked = match config.lock_mode {
+            LockMode::Replace => false,
+            LockMode::Prefix => !has_hashed_password && existing_entry.unlock_account(),
+            LockMode::Expire => existing_entry.unexpire_account(),
+        };
+        if unlocked {
+            log::info!("Unlocked account for user {}.", user_config.name);
+        }
+    } else {
+        log::debug!("Creating shadow entry for {}...", user_config.name);
+
+        let hashed_password = if user_config.locked || user_config.lock_password {
+            None
+        } else {
+            passwords
+                .take(&user_config.name, &user_config.password, None)?
+                .map(|hashed_password| match hashed_password {
+                    HashedPassword::Override(s) | HashedPassword::Initial(s) => s,
+                })
+        };
+
+        let mut new_entry = shadow::Entry::new(user_config.name.clone(), hashed_password);
+        if !user_config.disable_password_aging && password_policy.is_set() {
+            new_entry.set_password_policy(&password_policy);
+            // Otherwise, the password of the new account would already be expired.
+            new_entry.set_last_password_change_today();
+        }
+        if user_config.expire_password {
+            new_entry.expire_password();
+        }
+        if let Some(reserved) = &user_config.shadow_reserved {
+            new_entry.set_reserved(reserved.clone())?;
+        }
+
+        shadow_db.insert(new_entry).with_context(|| {
+            format!(
+                "Failed to add entry to shadow database for user {}",
+                user_config.name
+            )
+        })?;
+    };
+    Ok(())
+}
+
+/// The current hash of a password that is re-used when hashing a plaintext password.
+///
+/// Re-using it keeps the hash stable as long as the password doesn't change. With
+/// `rehashInsecure`, hashes using an insecure scheme are not re-used so that the password is
+/// hashed again with a secure scheme. With `--rehash-all`, no hash is re-used.
+fn reusable_hash<'a>(config: &Config, current_password: Option<&'a str>) -> Option<&'a str> {
+    current_password.filter(|hash| {
+        !config.rehash_all
+            && (!config.rehash_insecure
+                || shadow::password_hash_is_secure(hash, &config.secure_hash_schemes))
+    })
+}
+
+/// Emit warnings for user entries that use weak password hashing schemes.
+///
+/// The schemes that are deemed secure are taken from `secureHashSchemes`.
+pub fn warn_about_weak_password_hashes(config: &Config, shadow_db: &Shadow) {
+    for entry in shadow_db.entries() {
+        if !entry.uses_secure_hash(&config.secure_hash_schemes) {
+            log::warn!("User {} uses an insecure password hashing scheme. Update their password as soon as possible.", entry.name());
+        }
+    }
+}
+
+/// Number of entries and size of a database as it is written to disk.
+///
+/// This is useful to monitor the growth of the databases over time.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub name: &'static str,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Compute the number of entries and the size in bytes of each database.
+///
+/// The sizes are counted while serializing so that no second copy of the databases is built.
+///
+/// Without a shadow database (i.e. `--no-shadow`), only group and passwd are included.
+pub fn database_stats(
+    group_db: &Group,
+    passwd_db: &Passwd,
+    shadow_db: Option<&Shadow>,
+) -> Vec<DatabaseStats> {
+    let mut stats = vec![
+        DatabaseStats {
+            name: "group",
+            entries: group_db.len(),
+            bytes: fs::written_bytes(|w| group_db.to_writer(w)),
+        },
+        DatabaseStats {
+            name: "passwd",
+            entries: passwd_db.len(),
+            bytes: fs::written_bytes(|w| passwd_db.to_writer(w)),
+        },
+    ];
+    if let Some(shadow_db) = shadow_db {
+        stats.push(DatabaseStats {
+            name: "shadow",
+            entries: shadow_db.len(),
+            bytes: fs::written_bytes(|w| shadow_db.to_writer_sorted(passwd_db, w)),
+        });
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use expect_test::expect;
+    use indoc::indoc;
+
+    /// The time the date of the last password change is derived from (2024-10-27).
+    const SOURCE_DATE_EPOCH: &str = "1730000000";
+
+    fn gen0() -> Result<Config> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "name": "root",
+                    "uid": 0,
+                },
+                {
+                    "isNormal": true,
+                    "name": "normalo",
+                    "home": "/home/normalo",
+                    "shell": "/bin/bash",
+                    "hashedPassword": "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4", // "hello"
+                },
+            ],
+            "groups": [
+                {
+                    "name": "wheel",
+                    "members": [ "normalo", ],
+                },
+            ],
+        }))?)
+    }
+
+    fn gen1() -> Result<Config> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "name": "root",
+                    "uid": 0,
+                },
+                {
+                    "isNormal": true,
+                    "name": "normalo",
+                    // This should update the shell to zsh
+                    "shell": "/bin/zsh",
+                    // This shouldn't change the hash as it hashes the same as the existing
+                    // password
+                    "password": "hello",
+                },
+                {
+                    "isNormal": false,
+                    "name": "initial",
+                    "initialHashedPassword": "$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2",
+                },
+            ],
+            "groups": [
+                {
+                    "name": "wheel",
+                    "members": [ "normalo", "initial" ],
+                },
+            ],
+        }))?)
+    }
+
+    fn gen2() -> Result<Config> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "name": "root",
+                    "uid": 0,
+                    "home": "/root",
+                    // This shouldn't apply. The user should stay disabled.
+                    "initialHashedPassword": "$y$j9T$IMBPYrUksH4dZME8IQZPZ0$J3P/05qML9xZYHhkkIv3rNvXOAyb.tN56dJo8lTf0TA",
+                },
+                {
+                    // The users should keep the previous values even though they aren't present
+                    // here anymore.
+                    "name": "normalo",
+                    "description": "I'm normal I swear",
+                    // This should change the password
+                    "hashedPassword": "$y$j9T$CZSAJTLCfrBvcCgvOTY4W1$G7uzyX3O6K.DR8KJLL/oL.8EREPSRTIjBn76SpvcH4A",
+                },
+                // initial user should still exist even though we remove them from the config
+            ],
+            // wheel group should still exist even though we remove it from the config
+        }))?)
+    }
+
+    /// Capture the log messages of the current thread.
+    ///
+    /// Tests run in parallel on different threads, so each test only sees its own messages.
+    mod capture {
+        use std::{cell::RefCell, sync::Once};
+
+        use log::{LevelFilter, Log, Metadata, Record};
+
+        thread_local! {
+            static MESSAGES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+
+        struct Logger;
+
+        impl Log for Logger {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                MESSAGES.with(|messages| messages.borrow_mut().push(record.args().to_string()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: Logger = Logger;
+
+        /// Start capturing, discarding the messages captured so far.
+        pub fn start() {
+            static INIT: Once = Once::new();
+            INIT.call_once(|| {
+                // Another logger may already be installed, then nothing is captured.
+                if log::set_logger(&LOGGER).is_ok() {
+                    log::set_max_level(LevelFilter::Info);
+                }
+            });
+            MESSAGES.with(|messages| messages.borrow_mut().clear());
+        }
+
+        /// The messages captured since the last call to `start()`.
+        pub fn messages() -> Vec<String> {
+            MESSAGES.with(|messages| messages.borrow().clone())
+        }
+    }
+
+    /// Apply a config twice to the same databases and assert that the second run doesn't change
+    /// anything.
+    ///
+    /// The second run must produce byte-identical databases and must neither create nor update
+    /// any user or group.
+    fn assert_idempotent(
+        config: &Config,
+        group_db: &mut Group,
+        passwd_db: &mut Passwd,
+        shadow_db: &mut Shadow,
+    ) {
+        let mut ledger = Ledger::default();
+
+        update_users_and_groups(
+            config,
+            group_db,
+            passwd_db,
+            shadow_db,
+            &mut ledger,
+            &XcryptHasher,
+        );
+        let buffers = [
+            group_db.to_buffer(),
+            passwd_db.to_buffer(),
+            shadow_db.to_buffer_sorted(passwd_db),
+        ];
+
+        capture::start();
+        update_users_and_groups(
+            config,
+            group_db,
+            passwd_db,
+            shadow_db,
+            &mut ledger,
+            &XcryptHasher,
+        );
+
+        assert_eq!(
+            [
+                group_db.to_buffer(),
+                passwd_db.to_buffer(),
+                shadow_db.to_buffer_sorted(passwd_db),
+            ],
+            buffers
+        );
+        let changes = capture::messages()
+            .into_iter()
+            .filter(|message| message.starts_with("Updating") || message.starts_with("Created"))
+            .collect::<Vec<_>>();
+        assert!(changes.is_empty(), "Second run changed: {changes:?}");
+    }
+
+    #[test]
+    fn update_users_and_groups_across_generations() -> Result<()> {
+        // Explicitly set these because the expected values depend on them.
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+        std::env::set_var("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+        let mut shadow_db = Shadow::default();
+
+        // GEN 0
+
+        update_users_and_groups(
+            &gen0()?,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            root:x:0:root
+            wheel:x:999:normalo
+            normalo:x:1000:normalo
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            root:x:0:0:::/run/current-system/sw/bin/nologin
+            normalo:x:1000:1000::/home/normalo:/bin/bash
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let expected_shadow = expect![[r#"
+            root:!*:1::::::
+            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        // GEN 1
+
+        update_users_and_groups(
+            &gen1()?,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            root:x:0:root
+            initial:x:998:initial
+            wheel:x:999:initial,normalo
+            normalo:x:1000:normalo
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            root:x:0:0:::/run/current-system/sw/bin/nologin
+            initial:x:999:998:::/run/current-system/sw/bin/nologin
+            normalo:x:1000:1000::/home/normalo:/bin/zsh
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let expected_shadow = expect![[r#"
+            root:!*:1::::::
+            initial:$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2:1::::::
+            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        // GEN 2
+
+        update_users_and_groups(
+            &gen2()?,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            root:x:0:root
+            initial:x:998:initial
+            wheel:x:999:initial,normalo
+            normalo:x:1000:normalo
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            root:x:0:0::/root:/run/current-system/sw/bin/nologin
+            initial:x:999:998:::/run/current-system/sw/bin/nologin
+            normalo:x:1000:1000:I'm normal I swear:/home/normalo:/bin/zsh
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let expected_shadow = expect![[r#"
+            root:!*:1::::::
+            initial:!*:1::::::
+            normalo:$y$j9T$CZSAJTLCfrBvcCgvOTY4W1$G7uzyX3O6K.DR8KJLL/oL.8EREPSRTIjBn76SpvcH4A:20023::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn never_lock_root() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            root:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "normalo",
+                },
+            ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            root:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+            normalo:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        let passwd_db = Passwd::from_buffer("toor:x:0:0::/root:/bin/bash");
+        assert!(is_root("root", &passwd_db));
+        assert!(is_root("toor", &passwd_db));
+        assert!(!is_root("normalo", &passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_users_from_config() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            existing:x:1000:1000::/home/existing:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            existing:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "existing",
+                    "uid": 1000,
+                    "password": "hello",
+                    "locked": true,
+                },
+                {
+                    "isNormal": true,
+                    "name": "new",
+                    "hashedPassword": "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4",
+                    "locked": true,
+                },
+            ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            existing:!*:1::::::
+            new:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_with_prefix() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            normalo:x:1000:1000::/home/normalo:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "});
+
+        // The user is not in the config, so the account is locked.
+        let config = serde_json::from_value(serde_json::json!({ "lockMode": "prefix" }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            normalo:!$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        // The user is back in the config, so the account is unlocked with its previous password.
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "normalo",
+                    "uid": 1000,
+                    "initialPassword": "ignored",
+                },
+            ],
+            "lockMode": "prefix",
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::similar_names)]
+    fn remove_absent_users_and_groups() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::from_buffer(indoc! {"
+            root:x:0:
+            wheel:x:1:normalo,svc
+            svc:x:990:
+            normalo:x:1000:
+        "});
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            svc:x:990:990:::/bin/nologin
+            normalo:x:1000:1000::/home/normalo:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            root:!*:1::::::
+            svc:!*:1::::::
+            normalo:!*:1::::::
+        "});
+        let mut gshadow_db = GShadow::from_buffer(indoc! {"
+            root:!::
+            wheel:!:svc:normalo,svc
+            svc:!::
+            normalo:!::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "isNormal": true, "name": "normalo" } ],
+            "absentUsers": [ "root", "svc", "gone", "normalo" ],
+            "absentGroups": [ "root", "svc", "gone", "normalo" ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+        update_gshadow(&config, &group_db, &mut gshadow_db, &XcryptHasher);
+        remove_absent(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut gshadow_db,
+        );
+
+        let expected_group = expect![[r#"
+            root:x:0:
+            wheel:x:1:normalo
+            normalo:x:1000:
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            root:x:0:0::/root:/bin/bash
+            normalo:x:1000:1000::/home/normalo:/bin/bash
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let expected_shadow = expect![[r#"
+            root:!*:1::::::
+            normalo:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        let expected_gshadow = expect![[r#"
+            root:!::
+            wheel:!::normalo
+            normalo:!::
+        "#]];
+        expected_gshadow.assert_eq(&gshadow_db.to_buffer_sorted(&group_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_password() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+        std::env::set_var("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            existing:x:1000:1000::/home/existing:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            existing:$y$j9T$salt$existing:19911::::::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "existing", "uid": 1000, "lockPassword": true },
+                { "isNormal": true, "name": "new", "password": "hello", "lockPassword": true },
+            ],
+            "lockMode": "expire",
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        // Both the existing and the new user get an invalid password but the accounts are not
+        // expired.
+        let expected_shadow = expect![[r#"
+            existing:!*:20023::::::
+            new:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn immutable_users() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let passwd = indoc! {"
+            admin:x:1000:1000::/home/admin:/bin/bash
+        "};
+        let shadow = indoc! {"
+            admin:$y$j9T$salt$admin:19911::::::
+        "};
+        let mut passwd_db = Passwd::from_buffer(passwd);
+        let mut shadow_db = Shadow::from_buffer(shadow);
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "admin",
+                    "home": "/root",
+                    "shell": "/bin/sh",
+                    "hashedPassword": "$y$j9T$salt$changed",
+                    "immutable": true,
+                },
+                { "isNormal": true, "name": "new", "uid": 1001, "shell": "/bin/sh", "immutable": true },
+            ],
+        }))?;
+        capture::start();
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        // The existing user isn't changed but new users are still created.
+        let expected_passwd = expect![[r#"
+            admin:x:1000:1000::/home/admin:/bin/bash
+            new:x:1001:1001:::/bin/sh
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+        assert!(shadow_db.to_buffer_sorted(&passwd_db).starts_with(shadow));
+        assert!(capture::messages().contains(&"User admin is immutable. Not updating it.".into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn unmanaged_users() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            installer:x:900:900::/opt/installer:/bin/bash
+            agent:x:901:901::/opt/agent:/bin/bash
+            stale:x:1000:1000::/home/stale:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            installer:$y$j9T$salt$installer:19911::::::
+            agent:$y$j9T$salt$agent:19911::::::
+            stale:$y$j9T$salt$stale:19911::::::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "unmanagedUsers": [ "installer", "agent" ],
+            "absentUsers": [ "agent" ],
+            "lockSetsNologin": true,
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+        remove_absent(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut GShadow::default(),
+        );
+
+        // Only the stale user is locked. The unmanaged users are neither locked nor removed.
+        let expected_passwd = expect![[r#"
+            installer:x:900:900::/opt/installer:/bin/bash
+            agent:x:901:901::/opt/agent:/bin/bash
+            stale:x:1000:1000::/home/stale:/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+        let expected_shadow = expect![[r#"
+            installer:$y$j9T$salt$installer:19911::::::
+            agent:$y$j9T$salt$agent:19911::::::
+            stale:!*:19911::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shadow_reserved_field() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            existing:x:1000:1000:::/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            existing:!*:1::::::keep
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "existing", "shadowReserved": "ignored" },
+                { "isNormal": true, "name": "new", "shadowReserved": "site" },
+                { "isNormal": true, "name": "invalid", "shadowReserved": "site:x" },
+            ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            existing:!*:1::::::keep
+            new:!*:1::::::site
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_mode_expire() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            stale:x:1000:1000::/home/stale:/bin/bash
+            returning:x:1001:1001::/home/returning:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            stale:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+            returning:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1:::::1:
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "lockMode": "expire",
+            "users": [ { "isNormal": true, "name": "returning" } ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        // The password hashes are kept.
+        let expected_shadow = expect![[r#"
+            stale:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1:::::1:
+            returning:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lock_sets_nologin() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            stale:x:1000:1000::/home/stale:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            root:!*:1::::::
+            stale:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({ "lockSetsNologin": true }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            root:x:0:0::/root:/bin/bash
+            stale:x:1000:1000::/home/stale:/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let expected_shadow = expect![[r#"
+            root:!*:1::::::
+            stale:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_shadow() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            stale:x:1000:1000::/home/stale:/bin/bash
+        "});
+        let mut shadow_db = Shadow::default();
+
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "normalo", "password": "hello" },
+                { "isNormal": true, "name": "locked", "locked": true },
+            ],
+        }))?;
+        config.no_shadow = true;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        // The stale user is not locked.
+        let expected_passwd = expect![[r#"
+            stale:x:1000:1000::/home/stale:/bin/bash
+            normalo:x:1001:1001:::/run/current-system/sw/bin/nologin
+            locked:*:1002:1002:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+        assert!(shadow_db.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn passwd_marker() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            existing:x:1000:1000::/home/existing:/bin/bash
+        "});
+        let mut shadow_db = Shadow::default();
+
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "passwdMarker": "!",
+            "users": [
+                { "isNormal": true, "name": "existing", "uid": 1000 },
+                { "isNormal": true, "name": "normalo" },
+                { "isNormal": true, "name": "locked", "locked": true },
+            ],
+        }))?;
+        config.no_shadow = true;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            existing:!:1000:1000::/home/existing:/bin/bash
+            normalo:!:1001:1001:::/run/current-system/sw/bin/nologin
+            locked:*:1002:1002:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        // With the shadow database, the marker is ignored.
+        let mut passwd_db = Passwd::default();
+        config.no_shadow = false;
+        update_users_and_groups(
+            &config,
+            &mut Group::default(),
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            existing:x:1000:1000:::/run/current-system/sw/bin/nologin
+            normalo:x:1001:1001:::/run/current-system/sw/bin/nologin
+            locked:x:1002:1002:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        // Only conventional markers are accepted.
+        assert!(serde_json::from_value::<Config>(serde_json::json!({
+            "passwdMarker": "foo",
+            "users": [],
+        }))
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_passwd_and_shadow() {
+        let hash = "$y$j9T$salt$hash";
+        let mut passwd_db = Passwd::from_buffer(&format!(
+            indoc! {"
+                both:x:1000:1000::/home/both:/bin/bash
+                stale:{}:1001:1001::/home/stale:/bin/bash
+                passwdonly:{}:1002:1002::/home/passwdonly:/bin/bash
+                nohash:x:1003:1003::/home/nohash:/bin/bash
+            "},
+            "$y$j9T$salt$stale", hash
+        ));
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            both:$y$j9T$salt$both:1::::::
+            stale:$y$j9T$salt$fromshadow:1::::::
+            shadowonly:$y$j9T$salt$shadowonly:1::::::
+        "});
+
+        // Shadow is newer: shadowonly is dropped. Passwd is newer: passwdonly and nohash are
+        // added. Both have a hash: the one from shadow wins for stale.
+        assert_eq!(reconcile(&mut passwd_db, &mut shadow_db), 4);
+
+        let expected_passwd = expect![[r#"
+            both:x:1000:1000::/home/both:/bin/bash
+            stale:x:1001:1001::/home/stale:/bin/bash
+            passwdonly:x:1002:1002::/home/passwdonly:/bin/bash
+            nohash:x:1003:1003::/home/nohash:/bin/bash
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+        let expected_shadow = expect![[r#"
+            both:$y$j9T$salt$both:1::::::
+            stale:$y$j9T$salt$fromshadow:1::::::
+            passwdonly:$y$j9T$salt$hash:1::::::
+            nohash:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        // Reconciled databases agree.
+        assert_eq!(reconcile(&mut passwd_db, &mut shadow_db), 0);
+    }
+
+    #[test]
+    fn id_collisions() -> Result<()> {
+        let group_db = Group::from_buffer(indoc! {"
+            alice:x:1000:alice
+            old:x:1001:old
+        "});
+        let passwd_db = Passwd::from_buffer(indoc! {"
+            alice:x:1000:1000::/home/alice:/bin/bash
+            old:x:1001:1001::/home/old:/bin/bash
+        "});
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "users": [
+                // Existing users keep their UID.
+                { "name": "alice", "uid": 1005 },
+                // Renamed users keep the UID of their previous name.
+                { "name": "new", "uid": 1001, "previousNames": [ "old" ] },
+                { "name": "bob", "uid": 1000 },
+                { "name": "carol", "uid": 1002 },
+                { "name": "dave", "uid": 1002, "groupId": 1001 },
+            ],
+            "groups": [
+                { "name": "devs", "gid": 1000 },
+            ],
+        }))?;
+
+        capture::start();
+        assert_eq!(check_id_collisions(&config, &group_db, &passwd_db), 4);
+        let expected = expect![[r#"
+            [
+                "GID 1000 requested for devs but already held by alice.",
+                "UID 1000 requested for bob but already held by alice.",
+                "UID 1002 requested for dave but already held by carol.",
+                "GID 1001 requested for dave but already held by new.",
+            ]
+        "#]];
+        expected.assert_debug_eq(&capture::messages());
+
+        Ok(())
+    }
+
+    #[test]
+    fn member_order() -> Result<()> {
+        let apply = |member_order: &str, buffer: &str| -> Result<(String, String)> {
+            let config: Config = serde_json::from_value(serde_json::json!({
+                "groups": [ { "name": "wheel", "members": [ "peter", "gary", "peter" ] } ],
+                "memberOrder": member_order,
+            }))?;
+            let mut group_db = Group::from_buffer(buffer);
+            group_db.set_member_order(config.member_order);
+            let mut gshadow_db = GShadow::default();
+            update_users_and_groups(
+                &config,
+                &mut group_db,
+                &mut Passwd::default(),
+                &mut Shadow::default(),
+                &mut Ledger::default(),
+                &XcryptHasher,
+            );
+            update_gshadow(&config, &group_db, &mut gshadow_db, &XcryptHasher);
+            Ok((group_db.to_buffer(), gshadow_db.to_buffer_sorted(&group_db)))
+        };
+
+        let (group, gshadow) = apply("sorted", "")?;
+        expect![[r"
+            wheel:x:999:gary,peter
+        "]]
+        .assert_eq(&group);
+        expect![[r"
+            wheel:!::gary,peter
+        "]]
+        .assert_eq(&gshadow);
+        assert_eq!(apply("sorted", &group)?.0, group);
+
+        let (group, gshadow) = apply("declared", "")?;
+        expect![[r"
+            wheel:x:999:peter,gary
+        "]]
+        .assert_eq(&group);
+        expect![[r"
+            wheel:!::peter,gary
+        "]]
+        .assert_eq(&gshadow);
+        assert_eq!(apply("declared", &group)?.0, group);
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_member_mode() -> Result<()> {
+        let apply = |group_member_mode: &str| -> Result<String> {
+            let config: Config = serde_json::from_value(serde_json::json!({
+                "groups": [ { "name": "wheel", "members": [ "gary", "peter" ] } ],
+                "groupMemberMode": group_member_mode,
+            }))?;
+            // synced is added to the group by a directory sync outside of the config.
+            let mut group_db = Group::from_buffer("wheel:x:999:gary,synced\n");
+            assert_idempotent(
+                &config,
+                &mut group_db,
+                &mut Passwd::default(),
+                &mut Shadow::default(),
+            );
+            Ok(group_db.to_buffer())
+        };
+
+        assert_eq!(apply("replace")?, "wheel:x:999:gary,peter\n");
+        assert_eq!(apply("merge")?, "wheel:x:999:gary,peter,synced\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn shrink_group_membership() -> Result<()> {
+        let mut group_db = Group::default();
+        let mut gshadow_db = GShadow::default();
+        let mut apply = |groups: serde_json::Value| -> Result<()> {
+            let config: Config = serde_json::from_value(serde_json::json!({ "groups": groups }))?;
+            update_users_and_groups(
+                &config,
+                &mut group_db,
+                &mut Passwd::default(),
+                &mut Shadow::default(),
+                &mut Ledger::default(),
+                &XcryptHasher,
+            );
+            update_gshadow(&config, &group_db, &mut gshadow_db, &XcryptHasher);
+            Ok(())
+        };
+
+        apply(serde_json::json!([
+            { "name": "wheel", "gid": 10, "members": [ "gary", "peter" ] },
+            { "name": "audio", "gid": 11, "members": [ "peter" ] },
+        ]))?;
+        // peter is not declared as a member of any group anymore.
+        apply(serde_json::json!([
+            { "name": "wheel", "gid": 10, "members": [ "gary" ] },
+            { "name": "audio", "gid": 11 },
+        ]))?;
+
+        let expected_group = expect![[r#"
+            wheel:x:10:gary
+            audio:x:11:
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+        let expected_gshadow = expect![[r#"
+            wheel:!::gary
+            audio:!::
+        "#]];
+        expected_gshadow.assert_eq(&gshadow_db.to_buffer_sorted(&group_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rehash_insecure_passwords() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        // sha512crypt hash of "hello".
+        let insecure_hash = "$6$saltsalt$YTeBOLnmm3CoeJTzBuijUEtOEWCqrw/nQ8/AeMmON4LGp3k0ZiMjC1OdqzWFUAfDQBvYkOch9BWtQTBcZ1E/p0";
+        let passwd_db = || {
+            Passwd::from_buffer(indoc! {"
+                legacy:x:1000:1000::/home/legacy:/bin/bash
+            "})
+        };
+        let shadow_db = || Shadow::from_buffer(&format!("legacy:{insecure_hash}:19911::::::\n"));
+        let config = |rehash_insecure: bool| {
+            serde_json::from_value::<Config>(serde_json::json!({
+                "users": [
+                    {
+                        "isNormal": true,
+                        "name": "legacy",
+                        "uid": 1000,
+                        "password": "hello",
+                    },
+                ],
+                "rehashInsecure": rehash_insecure,
+            }))
+        };
+
+        let mut passwd = passwd_db();
+        let mut shadow = shadow_db();
+        update_users_and_groups(
+            &config(false)?,
+            &mut Group::default(),
+            &mut passwd,
+            &mut shadow,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+        assert_eq!(
+            shadow.get("legacy").map(shadow::Entry::password),
+            Some(insecure_hash)
+        );
+
+        let mut passwd = passwd_db();
+        let mut shadow = shadow_db();
+        assert_idempotent(
+            &config(true)?,
+            &mut Group::default(),
+            &mut passwd,
+            &mut shadow,
+        );
+        assert!(shadow
+            .get("legacy")
+            .is_some_and(|entry| entry.password().starts_with("$y$")));
+        // Only the hash has changed, not the password.
+        assert!(shadow.to_buffer_sorted(&passwd).ends_with(":19911::::::\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rehash_all() -> Result<()> {
+        // yescrypt hash of "hello".
+        let hash = "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4";
+        let mut passwd = Passwd::from_buffer(indoc! {"
+            plain:x:1000:1000::/home/plain:/bin/bash
+            initial:x:1001:1001::/home/initial:/bin/bash
+            changed:x:1002:1002::/home/changed:/bin/bash
+            external:x:1003:1003::/home/external:/bin/bash
+        "});
+        let mut shadow = Shadow::from_buffer(&format!(
+            "plain:{hash}:19911::::::\ninitial:{hash}:19911::::::\nchanged:{hash}:19911::::::\nexternal:{hash}:19911::::::\n"
+        ));
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "plain", "password": "hello" },
+                { "isNormal": true, "name": "initial", "initialPassword": "hello" },
+                // The user has changed the initial password.
+                { "isNormal": true, "name": "changed", "initialPassword": "mellow" },
+                { "isNormal": true, "name": "external", "hashedPassword": hash },
+            ],
+        }))?;
+        config.rehash_all = true;
+
+        capture::start();
+        update_users_and_groups(
+            &config,
+            &mut Group::default(),
+            &mut passwd,
+            &mut shadow,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let rotated = |name| {
+            shadow.get(name).is_some_and(|entry| {
+                entry.password() != hash && entry.password().starts_with("$y$")
+            })
+        };
+        assert!(rotated("plain"));
+        assert!(rotated("initial"));
+        assert!(!rotated("changed"));
+        assert!(!rotated("external"));
+        // Rotating the hash keeps the date of the last password change.
+        assert!(shadow
+            .to_buffer_sorted(&passwd)
+            .lines()
+            .all(|line| line.ends_with(":19911::::::")));
+        let expected = expect![[r#"
+            [
+                "Rotated the password hash of user plain.",
+                "Rotated the password hash of user initial.",
+            ]
+        "#]];
+        expected.assert_debug_eq(
+            &capture::messages()
+                .into_iter()
+                .filter(|message| message.starts_with("Rotated"))
+                .collect::<Vec<_>>(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn expire_password() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let hashed_password =
+            "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4";
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            changed:x:1000:1000::/home/changed:/bin/bash
+            unchanged:x:1001:1001::/home/unchanged:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            changed:!*:19000::::::
+            unchanged:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:19000:0:90:7:::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "changed",
+                    "uid": 1000,
+                    "hashedPassword": hashed_password,
+                    "expirePassword": true,
+                },
+                {
+                    "isNormal": true,
+                    "name": "unchanged",
+                    "uid": 1001,
+                    "hashedPassword": hashed_password,
+                    "expirePassword": true,
+                },
+                {
+                    "isNormal": true,
+                    "name": "new",
+                    "initialHashedPassword": hashed_password,
+                    "expirePassword": true,
+                },
+            ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            changed:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:0::::::
+            unchanged:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:19000:0:90:7:::
+            new:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:0::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn password_policy() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+        std::env::set_var("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            managed:x:1000:1000::/home/managed:/bin/bash
+            unmanaged:x:1001:1001::/home/unmanaged:/bin/bash
+            service:x:999:999:::/run/current-system/sw/bin/nologin
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            managed:!*:19000:0:99999:7:::
+            unmanaged:!*:19000:0:99999:7:::
+            service:!*:19000:1:90:7:30::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "passwordPolicy": { "minDays": 1, "maxDays": 90, "warnDays": 7 },
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "managed",
+                    "uid": 1000,
+                    "passwordPolicy": { "warnDays": 14 },
+                },
+                {
+                    "isNormal": true,
+                    "name": "new",
+                    "passwordPolicy": { "inactiveDays": 30 },
+                },
+                {
+                    "name": "service",
+                    "passwordPolicy": { "maxDays": 30 },
+                    "disablePasswordAging": true,
+                },
+                { "name": "new-service", "disablePasswordAging": true },
+            ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_shadow = expect![[r#"
+            new-service:!*:1::::::
+            service:!*:19000::::::
+            managed:!*:19000:1:90:14:::
+            unmanaged:!*:19000:0:99999:7:::
+            new:!*:20023:1:90:7:30::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_ids_from_ledger() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut ledger = Ledger::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "name": "first" }, { "name": "second" } ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut Group::default(),
+            &mut Passwd::default(),
+            &mut Shadow::default(),
+            &mut ledger,
+            &XcryptHasher,
+        );
+
+        // Start over with empty databases and create the users in the reverse order with a new
+        // user in front. Without the ledger, their IDs would be different.
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+        let mut shadow_db = Shadow::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "name": "third" }, { "name": "second" }, { "name": "first" } ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut ledger,
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            third:x:997:third
+            second:x:998:second
+            first:x:999:first
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            third:x:997:997:::/run/current-system/sw/bin/nologin
+            second:x:998:998:::/run/current-system/sw/bin/nologin
+            first:x:999:999:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        assert_eq!(ledger.uid("third"), Some(997));
+
+        Ok(())
+    }
+
+    #[test]
+    fn well_known_ids() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "name": "nobody", "group": "nogroup" } ],
+            "groups": [ { "name": "nogroup" } ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            nogroup:x:65534:
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            nobody:x:65534:65534:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn root_must_have_id_zero() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "users": [ { "name": "root", "uid": 5 } ],
+            "groups": [ { "name": "root", "gid": 5 } ],
+        }))?;
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+
+        let Err(err) = create_user(
+            &config,
+            &config.users[0],
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &mut ResolvedPasswords::default(),
+        ) else {
+            panic!("Creating root with UID 5 should fail");
+        };
+        assert_eq!(err.to_string(), "The user root must have the ID 0, not 5");
+        assert!(passwd_db.is_empty());
+
+        let Err(err) = create_group(
+            &config,
+            &config.groups[0],
+            &mut group_db,
+            &mut Ledger::default(),
+        ) else {
+            panic!("Creating the root group with GID 5 should fail");
+        };
+        assert_eq!(err.to_string(), "The group root must have the ID 0, not 5");
+        assert!(group_db.is_empty());
+
+        // Without a static ID, root gets UID and GID 0.
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "name": "root" } ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+        let expected_passwd = expect![[r#"
+            root:x:0:0:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn user_group_reuses_uid() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let users = serde_json::json!([
+            { "isNormal": true, "name": "normalo" },
+            { "isNormal": true, "name": "taken" },
+        ]);
+        let existing_group = "existing:x:1001:\n";
+
+        let mut group_db = Group::from_buffer(existing_group);
+        let mut passwd_db = Passwd::default();
+        let config = serde_json::from_value(serde_json::json!({ "users": users }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            normalo:x:1000:normalo
+            existing:x:1001:
+            taken:x:1002:taken
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            normalo:x:1000:1000:::/run/current-system/sw/bin/nologin
+            taken:x:1001:1002:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let mut group_db = Group::from_buffer(existing_group);
+        let mut passwd_db = Passwd::default();
+        let config = serde_json::from_value(serde_json::json!({
+            "users": users,
+            "userGroupReusesUid": false,
+            "reservedIds": [ 1000 ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            existing:x:1001:
+            normalo:x:1002:normalo
+            taken:x:1003:taken
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            normalo:x:1001:1002:::/run/current-system/sw/bin/nologin
+            taken:x:1002:1003:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_user() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::from_buffer(indoc! {"
+            wheel:x:1:normalo
+            normalo:x:1000:normalo
+            shared:x:1001:
+        "});
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            normalo:x:1000:1000::/home/normalo:/bin/bash
+            other:x:1001:1001::/home/other:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+            other:!*:1::::::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "renamed",
+                    "previousNames": [ "does-not-exist", "normalo" ],
+                    "home": "/home/renamed",
+                },
+                {
+                    "isNormal": true,
+                    "name": "another",
+                    "previousNames": [ "other" ],
+                },
+            ],
+            "groups": [
+                { "name": "wheel", "members": [ "renamed" ] },
+                { "name": "other" },
+            ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            wheel:x:1:renamed
+            other:x:999:
+            renamed:x:1000:renamed
+            shared:x:1001:
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            renamed:x:1000:1000::/home/renamed:/bin/bash
+            another:x:1001:1001::/home/other:/bin/bash
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        let expected_shadow = expect![[r#"
+            renamed:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+            another:!*:1::::::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn static_group_id() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "normalo", "groupId": 2000 },
+                { "isNormal": true, "name": "declared", "groupId": 2001 },
+                { "isNormal": true, "name": "conflict", "groupId": 2002 },
+                { "isNormal": true, "name": "member", "group": "declared", "groupId": 2000 },
+            ],
+            "groups": [
+                { "name": "declared", "gid": 2001 },
+                { "name": "conflict", "gid": 2003 },
+            ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            normalo:x:2000:normalo
+            declared:x:2001:
+            conflict:x:2003:
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            normalo:x:1000:2000:::/run/current-system/sw/bin/nologin
+            declared:x:2001:2001:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_primary_group() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let apply = |missing_primary_group: &str| -> Result<(String, String, Vec<String>)> {
+            let config: Config = serde_json::from_value(serde_json::json!({
+                "users": [ { "isNormal": true, "name": "normalo", "uid": 1000, "group": "1500" } ],
+                "missingPrimaryGroup": missing_primary_group,
+            }))?;
+            let mut group_db = Group::default();
+            let mut passwd_db = Passwd::default();
+            capture::start();
+            update_users_and_groups(
+                &config,
+                &mut group_db,
+                &mut passwd_db,
+                &mut Shadow::default(),
+                &mut Ledger::default(),
+                &XcryptHasher,
+            );
+            let messages = capture::messages()
+                .into_iter()
+                .filter(|message| message.contains("1500"))
+                .collect();
+            Ok((group_db.to_buffer(), passwd_db.to_buffer(), messages))
+        };
+
+        let (group, passwd, messages) = apply("warn")?;
+        assert_eq!(group, "");
+        assert_eq!(
+            passwd,
+            format!("normalo:x:1000:1500:::{NO_LOGIN_FALLBACK}\n")
+        );
+        assert_eq!(
+            messages,
+            ["The primary group 1500 of user normalo doesn't exist."]
+        );
+
+        let (group, passwd, messages) = apply("error")?;
+        assert_eq!((group.as_str(), passwd.as_str()), ("", ""));
+        assert_eq!(
+            messages,
+            ["Failed to create user normalo: The primary group 1500 of user normalo doesn't exist"]
+        );
+
+        let (group, passwd, _) = apply("create")?;
+        assert_eq!(group, "normalo:x:1500:normalo\n");
+        assert_eq!(
+            passwd,
+            format!("normalo:x:1000:1500:::{NO_LOGIN_FALLBACK}\n")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn uid_matches_gid_of_existing_group() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::from_buffer(indoc! {"
+            free:x:1500:
+            taken:x:1501:
+            system:x:500:
+            reserved:x:1502:
+        "});
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            occupant:x:1501:1501:::/bin/bash
+        "});
+        let config = serde_json::from_value(serde_json::json!({
+            "reservedIds": [ 1502 ],
+            "users": [
+                { "isNormal": true, "name": "free", "group": "free" },
+                { "isNormal": true, "name": "taken", "group": "taken" },
+                // The GID is in the range of system users.
+                { "isNormal": true, "name": "system", "group": "system" },
+                { "isNormal": true, "name": "reserved", "group": "reserved" },
+            ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            taken:x:1000:1501:::/run/current-system/sw/bin/nologin
+            system:x:1001:500:::/run/current-system/sw/bin/nologin
+            reserved:x:1002:1502:::/run/current-system/sw/bin/nologin
+            free:x:1500:1500:::/run/current-system/sw/bin/nologin
+            occupant:x:1501:1501:::/bin/bash
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn user_group_in_range_of_uid() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::from_buffer(indoc! {"
+            taken:x:500:
+            other:x:1500:
+        "});
+        let mut passwd_db = Passwd::default();
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "service", "uid": 500 },
+                { "name": "human", "uid": 1500 },
+                { "isNormal": true, "name": "normalo" },
+            ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            taken:x:500:
+            service:x:999:service
+            human:x:1000:human
+            normalo:x:1001:normalo
+            other:x:1500:
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            service:x:500:999:::/run/current-system/sw/bin/nologin
+            normalo:x:1000:1001:::/run/current-system/sw/bin/nologin
+            human:x:1500:1000:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_invalid_names() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "name": "evil:x:0:0::/root:/bin/sh" }, { "name": "normalo" } ],
+            "groups": [ { "name": "-wheel" } ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_group = expect![[r#"
+            normalo:x:999:normalo
+        "#]];
+        expected_group.assert_eq(&group_db.to_buffer());
+
+        let expected_passwd = expect![[r#"
+            normalo:x:999:999:::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_consistency_check() {
+        let passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+        "});
+        let shadow_db = Shadow::from_buffer(indoc! {"
+            root:!*:1::::::
+        "});
+        let root_group_db = Group::from_buffer("root:x:0:\n");
+        assert!(check_consistency(&root_group_db, &passwd_db, Some(&shadow_db), true).is_ok());
+
+        // Without a shadow database, users don't need shadow entries.
+        assert!(matches!(
+            check_consistency(&root_group_db, &passwd_db, Some(&Shadow::default()), true),
+            Err(Error::Inconsistent { count: 1 })
+        ));
+        assert!(check_consistency(&root_group_db, &passwd_db, None, true).is_ok());
+
+        // The primary group of root is missing.
+        assert!(check_consistency(&Group::default(), &passwd_db, Some(&shadow_db), false).is_ok());
+        assert!(check_consistency(&Group::default(), &passwd_db, Some(&shadow_db), true).is_err());
+        assert_eq!(
+            passwd_db.users_with_missing_primary_group(&Group::default()),
+            vec!["root".to_string()]
+        );
+        assert!(passwd_db
+            .users_with_missing_primary_group(&root_group_db)
+            .is_empty());
+
+        let passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            toor:x:0:0::/root:/bin/bash
+        "});
+        assert!(check_consistency(&root_group_db, &passwd_db, Some(&shadow_db), false).is_ok());
+        assert!(check_consistency(&root_group_db, &passwd_db, Some(&shadow_db), true).is_err());
+
+        let passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            gary:x:1000:0::/home/gary:/bin/bash
+        "});
+        assert!(check_consistency(&root_group_db, &passwd_db, Some(&shadow_db), true).is_err());
+
+        let passwd_db = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+        "});
+        let group_db = Group::from_buffer(indoc! {"
+            root:x:0:
+            wheel:x:1:
+            admins:x:1:
+        "});
+        assert!(check_consistency(&group_db, &passwd_db, Some(&shadow_db), false).is_ok());
+        assert!(check_consistency(&group_db, &passwd_db, Some(&shadow_db), true).is_err());
+    }
+
+    #[test]
+    fn update_gshadow_entries() -> Result<()> {
+        let group_db = Group::from_buffer(indoc! {"
+            wheel:x:1:normalo
+            audio:x:2:normalo
+            video:x:3:
+        "});
+        let mut gshadow_db = GShadow::default();
+        gshadow_db.insert(gshadow::Entry::new(
+            "audio".into(),
+            None,
+            BTreeSet::from(["root".into()]),
+            Vec::new(),
+        ))?;
+        gshadow_db.insert(gshadow::Entry::new(
+            "video".into(),
+            None,
+            BTreeSet::from(["root".into()]),
+            Vec::new(),
+        ))?;
+
+        let config = serde_json::from_value(serde_json::json!({
+            "groups": [
+                {
+                    "name": "wheel",
+                    "members": [ "normalo" ],
+                    "administrators": [ "root", "admin" ],
+                },
+                {
+                    "name": "audio",
+                    "members": [ "normalo" ],
+                },
+                {
+                    "name": "video",
+                    "administrators": [],
+                },
+            ],
+        }))?;
+
+        update_gshadow(&config, &group_db, &mut gshadow_db, &XcryptHasher);
+
+        let expected_gshadow = expect![[r#"
+            wheel:!:admin,root:normalo
+            audio:!:root:normalo
+            video:!::
+        "#]];
+        expected_gshadow.assert_eq(&gshadow_db.to_buffer_sorted(&group_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_passwords() -> Result<()> {
+        let group_db = Group::from_buffer(indoc! {"
+            wheel:x:1:
+            audio:x:2:
+            video:x:3:
+            users:x:4:
+        "});
+        let mut gshadow_db = GShadow::from_buffer(indoc! {"
+            audio:$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1::
+            video:$y$j9T$existing::
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "groups": [
+                { "name": "wheel", "hashedPassword": "$y$j9T$wheel" },
+                // This hashes the same as the existing password.
+                { "name": "audio", "password": "hello" },
+                { "name": "video" },
+            ],
+        }))?;
+
+        update_gshadow(&config, &group_db, &mut gshadow_db, &XcryptHasher);
+
+        let expected_gshadow = expect![[r#"
+            wheel:$y$j9T$wheel::
+            audio:$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1::
+            video:$y$j9T$existing::
+            users:!::
+        "#]];
+        expected_gshadow.assert_eq(&gshadow_db.to_buffer_sorted(&group_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_hasher() -> Result<()> {
+        /// A hasher that reverses the password instead of hashing it.
+        struct ReverseHasher;
+
+        impl Hasher for ReverseHasher {
+            fn crypt(&self, password: &str, _setting: &str) -> Result<String, Error> {
+                Ok(format!(
+                    "$rev${}",
+                    password.chars().rev().collect::<String>()
+                ))
+            }
+
+            fn gensalt(&self) -> Result<String, Error> {
+                Ok("$rev$".into())
+            }
+        }
+
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [ { "isNormal": true, "name": "normalo", "password": "hello" } ],
+            "groups": [ { "name": "wheel", "password": "world" } ],
+        }))?;
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+        let mut shadow_db = Shadow::default();
+        let mut gshadow_db = GShadow::default();
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &ReverseHasher,
+        );
+        update_gshadow(&config, &group_db, &mut gshadow_db, &ReverseHasher);
+
+        assert_eq!(
+            shadow_db.get("normalo").map(shadow::Entry::password),
+            Some("$rev$olleh")
+        );
+        assert_eq!(
+            gshadow_db.get("wheel").map(gshadow::Entry::password),
+            Some("$rev$dlrow")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_passwords_of_invalid_users() -> Result<()> {
+        /// A hasher that counts how many passwords it hashes.
+        #[derive(Default)]
+        struct CountingHasher(std::sync::atomic::AtomicUsize);
+
+        impl Hasher for CountingHasher {
+            fn crypt(&self, password: &str, _setting: &str) -> Result<String, Error> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Ok(format!("$count${password}"))
+            }
+
+            fn gensalt(&self) -> Result<String, Error> {
+                Ok("$count$".into())
+            }
+        }
+
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "normalo", "password": "hello" },
+                { "isNormal": true, "name": "bad:name", "password": "world" },
+            ],
+        }))?;
+        let hasher = CountingHasher::default();
+
+        update_users_and_groups(
+            &config,
+            &mut Group::default(),
+            &mut Passwd::default(),
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &hasher,
+        );
+
+        // The password of the user that is skipped because of its name is never hashed.
+        assert_eq!(hasher.0.into_inner(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_shell_for_new_users() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            existing:x:1000:1000:::/run/current-system/sw/bin/nologin
+        "});
+        let mut shadow_db = Shadow::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "defaultShell": "/bin/sh",
+            "users": [
+                { "name": "existing", "isNormal": true },
+                { "name": "normalo", "isNormal": true },
+                { "name": "zshuser", "isNormal": true, "shell": "/bin/zsh" },
+            ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            existing:x:1000:1000:::/run/current-system/sw/bin/nologin
+            normalo:x:1001:1001:::/bin/sh
+            zshuser:x:1002:1002:::/bin/zsh
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn configure_no_login_path() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+        let mut shadow_db = Shadow::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "nologinPath": "/usr/sbin/nologin",
+            "users": [ { "name": "sysuser" } ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            sysuser:x:999:999:::/usr/sbin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        assert!(check_no_login_path("/usr/sbin/nologin", Path::new("/")).is_ok());
+        assert!(check_no_login_path("/usr/sbin/nologin", Path::new("/mnt")).is_ok());
+        assert!(matches!(
+            check_no_login_path("nologin", Path::new("/")),
+            Err(Error::RelativeNoLoginPath { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_last_password_change() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+        std::env::set_var("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH);
+
+        let hash = "$y$j9T$salt$hash";
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            normalo:x:1000:1000:Old:/home/normalo:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(&format!("normalo:{hash}:19911:0:99999:7:::\n"));
+
+        let config = |hashed_password: &str| {
+            serde_json::from_value::<Config>(serde_json::json!({
+                "users": [
+                    {
+                        "isNormal": true,
+                        "name": "normalo",
+                        "uid": 1000,
+                        "description": "New",
+                        "hashedPassword": hashed_password,
+                    },
+                ],
+            }))
+        };
+
+        // Only the GECOS field changed, so the date of the last password change is kept.
+        update_users_and_groups(
+            &config(hash)?,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+        assert!(passwd_db.to_buffer().contains(":New:"));
+        assert_eq!(
+            shadow_db.to_buffer_sorted(&passwd_db),
+            format!("normalo:{hash}:19911:0:99999:7:::\n")
+        );
+
+        // A new password moves it to today.
+        update_users_and_groups(
+            &config("$y$j9T$salt$new")?,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+        let expected_shadow = expect![[r#"
+            normalo:$y$j9T$salt$new:20023:0:99999:7:::
+        "#]];
+        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+
+        Ok(())
+    }
+
+    #[test]
+    fn structured_gecos() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            existing:x:1000:1000:Gary Oak,Lab 1,555-1234::/home/existing:/bin/bash
+        "});
+        let mut shadow_db = Shadow::default();
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "name": "existing",
+                    "isNormal": true,
+                    "gecos": { "room": "Lab 2" },
+                },
+                {
+                    "name": "normalo",
+                    "isNormal": true,
+                    "gecos": { "fullName": "Normalo", "homePhone": "555-4321" },
+                },
+                {
+                    "name": "raw",
+                    "isNormal": true,
+                    "description": "Raw description",
+                    "gecos": { "fullName": "Ignored" },
+                },
+            ],
+        }))?;
+
+        update_users_and_groups(
+            &config,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            existing:x:1000:1000:Gary Oak,Lab 2,555-1234::/home/existing:/bin/bash
+            normalo:x:1001:1001:Normalo,,,555-4321::/run/current-system/sw/bin/nologin
+            raw:x:1002:1002:Raw description::/run/current-system/sw/bin/nologin
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_or_keep_fields() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let existing_passwd = indoc! {"
+            cleared:x:1000:1000:Stale Description:/home/cleared:/bin/bash
+            kept:x:1001:1001:Kept Description:/home/kept:/bin/bash
+            components:x:1002:1002:Full Name,Room,,,Other:/home/components:/bin/bash
+        "};
+        let mut passwd_db = Passwd::from_buffer(existing_passwd);
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "cleared",
+                    "description": "",
+                    "home": "",
+                    "shell": "",
+                },
+                {
+                    "isNormal": true,
+                    "name": "kept",
+                    // An explicit null is the same as an absent key.
+                    "description": null,
+                },
+                {
+                    "isNormal": true,
+                    "name": "components",
+                    "gecos": { "room": "", "other": "New" },
+                },
+            ],
+        }))?;
+        update_users_and_groups(
+            &config,
+            &mut Group::default(),
+            &mut passwd_db,
+            &mut Shadow::default(),
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected_passwd = expect![[r#"
+            cleared:x:1000:1000:::
+            kept:x:1001:1001:Kept Description:/home/kept:/bin/bash
+            components:x:1002:1002:Full Name,,,,New:/home/components:/bin/bash
+        "#]];
+        expected_passwd.assert_eq(&passwd_db.to_buffer());
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_database_stats() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::default();
+        let mut shadow_db = Shadow::default();
+
+        update_users_and_groups(
+            &gen0()?,
+            &mut group_db,
+            &mut passwd_db,
+            &mut shadow_db,
+            &mut Ledger::default(),
+            &XcryptHasher,
+        );
+
+        let expected = expect![[r#"
+            [
+                DatabaseStats {
+                    name: "group",
+                    entries: 3,
+                    bytes: 57,
+                },
+                DatabaseStats {
+                    name: "passwd",
+                    entries: 2,
+                    bytes: 93,
+                },
+                DatabaseStats {
+                    name: "shadow",
+                    entries: 2,
+                    bytes: 106,
+                },
+            ]
+        "#]];
+        let stats = database_stats(&group_db, &passwd_db, Some(&shadow_db));
+        expected.assert_debug_eq(&stats);
+
+        // The counted sizes match the serialized databases.
+        assert_eq!(stats[0].bytes, group_db.to_buffer().len());
+        assert_eq!(stats[1].bytes, passwd_db.to_buffer().len());
+        assert_eq!(stats[2].bytes, shadow_db.to_buffer_sorted(&passwd_db).len());
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::similar_names)]
+    fn update_subid_ranges() -> Result<()> {
+        let mut subuid_db = SubId::from_buffer(indoc! {"
+            manual:100000:65536
+            normalo:300000:1000
+        "});
+        let mut subgid_db = SubId::from_buffer(indoc! {"
+            manual:100000:65536
+        "});
+
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "name": "normalo",
+                    "subUidRanges": [ { "start": 300_000, "count": 1000 } ],
+                    "subGidRanges": [ { "start": 300_000, "count": 1000 } ],
+                    "autoSubIdCount": 65536,
+                },
+                {
+                    "name": "auto",
+                    "autoSubIdCount": 65536,
+                },
+                {
+                    "name": "plain",
+                },
+            ],
+        }))?;
+
+        update_subids(&config, &mut subuid_db, &mut subgid_db);
+
+        let expected = expect![[r#"
+            manual:100000:65536
+            normalo:300000:1000
+            auto:165536:65536
+        "#]];
+        expected.assert_eq(&subuid_db.to_buffer());
+
+        let expected = expect![[r#"
+            manual:100000:65536
+            normalo:300000:1000
+            auto:165536:65536
+        "#]];
+        expected.assert_eq(&subgid_db.to_buffer());
+
+        // Applying the config again doesn't reassign any range.
+        let subuid_buffer = subuid_db.to_buffer();
+        update_subids(&config, &mut subuid_db, &mut subgid_db);
+        assert_eq!(subuid_db.to_buffer(), subuid_buffer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn idempotent() -> Result<()> {
+        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
+
+        for config in [gen0()?, gen1()?, gen2()?] {
+            assert_idempotent(
+                &config,
+                &mut Group::default(),
+                &mut Passwd::default(),
+                &mut Shadow::default(),
+            );
+        }
+
+        // Plaintext passwords must not be hashed with a new salt on every run.
+        let config = serde_json::from_value(serde_json::json!({
+            "users": [
+                {
+                    "isNormal": true,
+                    "name": "normalo",
+                    "password": "hello",
+                    "gecos": { "fullName": "Normal User" },
+                    "expirePassword": true,
+                    "passwordPolicy": { "maxDays": 90 },
+                },
+                { "name": "locked", "locked": true },
+                { "name": "grouped", "groupId": 500 },
+            ],
+            "groups": [
+                { "name": "wheel", "members": [ "normalo", "locked" ] },
+            ],
+            "lockMode": "prefix",
+        }))?;
+        let mut group_db = Group::default();
+        let mut passwd_db = Passwd::from_buffer(indoc! {"
+            stale:x:1001:1001::/home/stale:/bin/bash
+        "});
+        let mut shadow_db = Shadow::from_buffer(indoc! {"
+            stale:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+        "});
+        assert_idempotent(&config, &mut group_db, &mut passwd_db, &mut shadow_db);
+
+        Ok(())
+    }
+}
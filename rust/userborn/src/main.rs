@@ -1,55 +1,44 @@
-mod config;
-mod fs;
-mod group;
-mod id;
-mod passwd;
-mod password;
-mod shadow;
-
-use std::{collections::BTreeSet, io::Write, process::ExitCode};
-
-use anyhow::{anyhow, Context, Result};
-use log::{Level, LevelFilter};
-
-use config::Config;
-use group::Group;
-use passwd::Passwd;
-use password::HashedPassword;
-use shadow::Shadow;
-
-/// Fallback path to the nologin binary.
-///
-/// This is used when `USERBORN_NO_LOGIN_PATH` is not set during runtime and
-/// `USERBORN_NO_LOGIN_DEFAULT_PATH` hasn't been set during compilation.
-const NO_LOGIN_FALLBACK: &str = "/run/current-system/sw/bin/nologin";
-/// Default path to the nolign binary.
-///
-/// This can be configured via a compile-time environment variable.
-const NO_LOGIN_DEFAULT: Option<&'static str> = option_env!("USERBORN_NO_LOGIN_DEFAULT_PATH");
-const DEFAULT_DIRECTORY: &str = "/etc";
+mod cli;
+
+use std::{
+    io::{self, IsTerminal, Write},
+    path::Path,
+    process::{self, ExitCode},
+    time::Instant,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{Level, LevelFilter, Record};
+
+use cli::{Args, Command, USAGE};
+use userborn::{
+    check_consistency, check_id_collisions, check_no_login_path, config::Config, database_stats,
+    excess_mode, export::Export, group::Group, gshadow::GShadow, has_content, ledger::Ledger,
+    no_login_path, passwd::Passwd, reconcile, remove_absent, restrict_mode, shadow::Shadow,
+    subid::SubId, update_gshadow, update_subids, update_users_and_groups,
+    warn_about_weak_password_hashes, XcryptHasher,
+};
+
+/// Exit code signalling that the databases were changed when `--detailed-exit-code` is set.
+const EXIT_CODE_CHANGED: u8 = 2;
 
 fn main() -> ExitCode {
-    // Setup the logger to use the kernel's `printk()` scheme so that systemd can interpret the
-    // levels.
+    let args = Args::parse(std::env::args().skip(1));
+
+    let log_format = LogFormat::detect();
     env_logger::builder()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "<{}>{}",
-                match record.level() {
-                    Level::Error => 3,
-                    Level::Warn => 4,
-                    Level::Info => 6,
-                    Level::Debug | Level::Trace => 7,
-                },
-                record.args()
-            )
-        })
-        .filter(None, LevelFilter::Info)
+        .format(move |buf, record| log_format.write(buf, record))
+        .filter(
+            None,
+            args.as_ref().map_or(LevelFilter::Info, Args::log_level),
+        )
+        // Let `RUST_LOG` override the level from the command line.
+        .parse_default_env()
         .init();
 
-    match run() {
-        Ok(()) => ExitCode::SUCCESS,
+    match args.and_then(|args| Ok((run(&args)?, args.detailed_exit_code || args.check))) {
+        Ok((Outcome::Changed, true)) => ExitCode::from(EXIT_CODE_CHANGED),
+        Ok(_) => ExitCode::SUCCESS,
         Err(err) => {
             log::error!("{err:#}.");
             ExitCode::FAILURE
@@ -57,432 +46,503 @@ fn main() -> ExitCode {
     }
 }
 
-fn run() -> Result<()> {
-    let config_path = std::env::args()
-        .nth(1)
-        .ok_or(anyhow!("No config provided"))?;
-    let directory = std::env::args().nth(2).unwrap_or(DEFAULT_DIRECTORY.into());
-
-    let config = Config::from_file(config_path)?;
-
-    let group_path = format!("{directory}/group");
-    let passwd_path = format!("{directory}/passwd");
-    let shadow_path = format!("{directory}/shadow");
-
-    let mut group_db = Group::from_file(&group_path).unwrap_or_default();
-    let mut passwd_db = Passwd::from_file(&passwd_path).unwrap_or_default();
-    let mut shadow_db = Shadow::from_file(&shadow_path).unwrap_or_default();
-
-    update_users_and_groups(&config, &mut group_db, &mut passwd_db, &mut shadow_db);
+/// How log records are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// Use the kernel's `printk()` scheme so that systemd can interpret the levels.
+    Printk,
+    /// Prefix each record with the name of its level for a human.
+    Human { color: bool },
+}
 
-    warn_about_weak_password_hashes(&shadow_db);
+impl LogFormat {
+    /// Use the human-readable format if stderr is a terminal.
+    ///
+    /// Colors are disabled if `NO_COLOR` is set to a non-empty value (see <https://no-color.org>).
+    fn detect() -> Self {
+        if !io::stderr().is_terminal() {
+            return Self::Printk;
+        }
+        let color = std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+        Self::Human { color }
+    }
 
-    log::debug!("Persisting files to disk...");
-    // We should skip this if the files haven't actually changed
-    // We should create backup files with an `-` appended to the file name.
-    group_db.to_file(group_path)?;
-    passwd_db.to_file(passwd_path)?;
-    shadow_db.to_file_sorted(&passwd_db, shadow_path)?;
+    fn write(self, buf: &mut impl Write, record: &Record) -> io::Result<()> {
+        let level = record.level();
+        match self {
+            Self::Printk => {
+                let printk_level = match level {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                writeln!(buf, "<{printk_level}>{}", record.args())
+            }
+            Self::Human { color: false } => writeln!(buf, "{level:<5} {}", record.args()),
+            Self::Human { color: true } => {
+                // ANSI foreground colors.
+                let color = match level {
+                    Level::Error => 31,
+                    Level::Warn => 33,
+                    Level::Info => 32,
+                    Level::Debug => 34,
+                    Level::Trace => 35,
+                };
+                writeln!(buf, "\x1b[{color}m{level:<5}\x1b[0m {}", record.args())
+            }
+        }
+    }
+}
 
-    Ok(())
+/// The result of a successful run.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    /// No database was modified.
+    Unchanged,
+    /// At least one database was modified.
+    Changed,
 }
 
-/// Create and update users and groups in the provided databases.
-///
-/// Doesn't actually write anything to disk, only mutates the databases in memory.
-fn update_users_and_groups(
-    config: &Config,
-    group_db: &mut Group,
-    passwd_db: &mut Passwd,
-    shadow_db: &mut Shadow,
-) {
-    for group_config in &config.groups {
-        if let Some(existing_entry) = group_db.get_mut(&group_config.name) {
-            existing_entry.update(group_config.members.clone());
-        } else if let Err(e) = create_group(group_config, group_db) {
-            log::error!("Failed to create group {}: {e:#}", group_config.name);
-        };
+fn run(args: &Args) -> Result<Outcome> {
+    match &args.command {
+        Command::Apply { config } => {
+            let start = Instant::now();
+            let config = Config::from_file(config)?;
+            log::debug!("Parsed the config in {:?}.", start.elapsed());
+            apply(config, args)
+        }
+        Command::ApplyDir { config_dir } => {
+            let start = Instant::now();
+            let config = Config::from_dir(config_dir)?;
+            log::debug!("Parsed the config in {:?}.", start.elapsed());
+            apply(config, args)
+        }
+        Command::Validate => {
+            validate(args)?;
+            Ok(Outcome::Unchanged)
+        }
+        Command::Export => {
+            export(args)?;
+            Ok(Outcome::Unchanged)
+        }
+        Command::Schema => {
+            let schema = serde_json::to_string_pretty(&Config::json_schema())
+                .context("Failed to serialize schema")?;
+            writeln!(std::io::stdout(), "{schema}").context("Failed to print schema")?;
+            Ok(Outcome::Unchanged)
+        }
+        Command::Help => {
+            print!("{USAGE}");
+            Ok(Outcome::Unchanged)
+        }
     }
+}
 
-    let mut users_in_config: BTreeSet<&str> = BTreeSet::new();
-
-    for user_config in &config.users {
-        users_in_config.insert(&user_config.name);
-
-        if let Some(existing_entry) = passwd_db.get_mut(&user_config.name) {
-            if let Err(e) = update_user(existing_entry, user_config, group_db, shadow_db) {
-                log::error!("Failed to update user {}: {e:#}", user_config.name);
-            };
-        } else if let Err(e) = create_user(user_config, group_db, passwd_db, shadow_db) {
-            log::error!("Failed to create user {}: {e:#}", user_config.name);
-        };
+/// Apply the config to the databases.
+#[allow(clippy::similar_names, clippy::too_many_lines)]
+fn apply(mut config: Config, args: &Args) -> Result<Outcome> {
+    config.no_shadow = args.no_shadow;
+    config.rehash_all = args.rehash_all;
+    if args.check_shells {
+        config.shell_root = Some(args.root.as_deref().unwrap_or("/").into());
     }
 
-    // Find users in the shadow DB that are not in the config and disable them.
-    for entry in shadow_db.entries_mut() {
-        if !users_in_config.contains(entry.name()) {
-            log::info!("Locking account for user {}...", entry.name());
-            entry.lock_account();
+    let group_path = args.group_path();
+    let passwd_path = args.passwd_path();
+    let shadow_path = args.shadow_path();
+    let gshadow_path = args.gshadow_path();
+    let subuid_path = args.subuid_path();
+    let subgid_path = args.subgid_path();
+
+    let start = Instant::now();
+    let mut group_db = read_database(&group_path, |path| Group::from_file(path), Group::is_empty);
+    let mut passwd_db = read_database(
+        &passwd_path,
+        |path| Passwd::from_file(path),
+        Passwd::is_empty,
+    );
+    let mut shadow_db = if args.no_shadow {
+        Shadow::default()
+    } else {
+        read_database(
+            &shadow_path,
+            |path| Shadow::from_file(path),
+            Shadow::is_empty,
+        )
+    };
+    let mut gshadow_db = if args.no_shadow {
+        GShadow::default()
+    } else {
+        GShadow::from_file(&gshadow_path).unwrap_or_default()
+    };
+    group_db.set_sort_order(config.sort_order);
+    group_db.set_member_order(config.member_order);
+    passwd_db.set_sort_order(config.sort_order);
+    let mut subuid_db = SubId::from_file(&subuid_path).unwrap_or_default();
+    let mut subgid_db = SubId::from_file(&subgid_path).unwrap_or_default();
+    log::debug!("Read the databases in {:?}.", start.elapsed());
+
+    if args.reconcile {
+        let count = reconcile(&mut passwd_db, &mut shadow_db);
+        if count > 0 {
+            log::info!(
+                "Resolved {count} disagreements between the passwd and the shadow database."
+            );
         }
     }
-}
-
-/// Create a new group entry and add it to the database.
-fn create_group(group_config: &config::Group, group_db: &mut Group) -> Result<()> {
-    let gid = if let Some(gid) = group_config.gid {
-        gid
+    check_consistency(
+        &group_db,
+        &passwd_db,
+        (!args.no_shadow).then_some(&shadow_db),
+        args.strict,
+    )?;
+    let permissive_shadow = !args.no_shadow && check_shadow_mode(&shadow_path);
+    check_no_login_path(
+        &no_login_path(&config),
+        Path::new(args.root.as_deref().unwrap_or("/")),
+    )?;
+    if args.strict {
+        config.validate_names()?;
+    }
+    let collisions = if args.dry_run {
+        check_id_collisions(&config, &group_db, &passwd_db)
     } else {
-        group_db
-            .allocate_gid(group_config.is_normal)
-            .context("Failed to allocate new GID")?
+        0
     };
 
-    let new_entry = group::Entry::new(group_config.name.clone(), gid, group_config.members.clone());
-
-    let description = new_entry.describe();
+    let ledger_path = config.ledger_path.as_deref().map(|path| args.rooted(path));
+    let mut ledger = match &ledger_path {
+        Some(path) if Path::new(path).exists() => Ledger::from_file(path)?,
+        _ => Ledger::default(),
+    };
 
-    group_db
-        .insert(&new_entry)
-        .with_context(|| format!("Failed to add group entry {}", group_config.name))?;
+    let start = Instant::now();
+    update_users_and_groups(
+        &config,
+        &mut group_db,
+        &mut passwd_db,
+        &mut shadow_db,
+        &mut ledger,
+        &XcryptHasher,
+    );
+    update_gshadow(&config, &group_db, &mut gshadow_db, &XcryptHasher);
+    remove_absent(
+        &config,
+        &mut group_db,
+        &mut passwd_db,
+        &mut shadow_db,
+        &mut gshadow_db,
+    );
+    update_subids(&config, &mut subuid_db, &mut subgid_db);
+    log::debug!("Updated users and groups in {:?}.", start.elapsed());
+
+    warn_about_weak_password_hashes(&config, &shadow_db);
+
+    let changed = if args.dry_run || args.check {
+        [
+            would_change(&group_path, |writer| group_db.to_writer(writer)),
+            would_change(&passwd_path, |writer| passwd_db.to_writer(writer)),
+            !args.no_shadow
+                && (would_change(&shadow_path, |writer| {
+                    shadow_db.to_writer_sorted(&passwd_db, writer)
+                }) || permissive_shadow),
+            !args.no_shadow
+                && would_change(&gshadow_path, |writer| {
+                    gshadow_db.to_writer_sorted(&group_db, writer)
+                }),
+            (!subuid_db.is_empty() || Path::new(&subuid_path).exists())
+                && would_change(&subuid_path, |writer| subuid_db.to_writer(writer)),
+            (!subgid_db.is_empty() || Path::new(&subgid_path).exists())
+                && would_change(&subgid_path, |writer| subgid_db.to_writer(writer)),
+        ]
+    } else {
+        log::debug!("Persisting files to disk...");
+        let start = Instant::now();
+        // We should create backup files with an `-` appended to the file name.
+        let changed = [
+            group_db.to_file(&group_path, args.group_mode)?,
+            passwd_db.to_file(&passwd_path, args.passwd_mode)?,
+            !args.no_shadow && {
+                let restricted =
+                    restrict_mode(Path::new(&shadow_path), userborn::shadow::MAX_MODE)?;
+                shadow_db.to_file_sorted(&passwd_db, &shadow_path, args.shadow_mode)? || restricted
+            },
+            !args.no_shadow && gshadow_db.to_file_sorted(&group_db, &gshadow_path)?,
+            subid_to_file(&subuid_db, &subuid_path)?,
+            subid_to_file(&subgid_db, &subgid_path)?,
+        ];
+        if let Some(path) = &ledger_path {
+            ledger.to_file(path)?;
+        }
+        log::debug!("Wrote the databases in {:?}.", start.elapsed());
+        changed
+    };
 
-    log::info!("Created group {description}.");
+    for stats in database_stats(
+        &group_db,
+        &passwd_db,
+        (!args.no_shadow).then_some(&shadow_db),
+    ) {
+        log::info!(
+            "{} contains {} entries ({} bytes).",
+            stats.name,
+            stats.entries,
+            stats.bytes
+        );
+    }
 
-    Ok(())
+    if collisions > 0 {
+        bail!(
+            "The config requests {collisions} IDs that are already held by other users or groups"
+        );
+    }
+    if args.check {
+        let paths = [
+            &group_path,
+            &passwd_path,
+            &shadow_path,
+            &gshadow_path,
+            &subuid_path,
+            &subgid_path,
+        ];
+        let changed_paths = paths
+            .into_iter()
+            .zip(changed)
+            .filter_map(|(path, changed)| changed.then_some(path.as_str()))
+            .collect::<Vec<_>>();
+        if changed_paths.is_empty() {
+            writeln!(io::stdout(), "The password databases are up to date.")
+        } else {
+            writeln!(io::stdout(), "Would change {}.", changed_paths.join(", "))
+        }
+        .context("Failed to print summary")?;
+    }
+    if (args.dry_run || args.check) && changed.contains(&true) {
+        Ok(Outcome::Changed)
+    } else if changed.contains(&true) {
+        if let Some(command) = &args.on_change {
+            run_hook(command);
+        }
+        Ok(Outcome::Changed)
+    } else {
+        log::info!("No changes to the password databases.");
+        Ok(Outcome::Unchanged)
+    }
 }
 
-/// Create a new user entry and add it to the database.
+/// Run the `--on-change` hook via `sh -c` and log its output and exit status.
 ///
-/// Creates an entry both in the passwd and the shadow database.
-fn create_user(
-    user_config: &config::User,
-    group_db: &mut Group,
-    passwd_db: &mut Passwd,
-    shadow_db: &mut Shadow,
-) -> Result<()> {
-    log::debug!("Creating new passwd entry for {}...", user_config.name);
-
-    let uid = if let Some(uid) = user_config.uid {
-        uid
-    } else {
-        passwd_db
-            .allocate_uid(user_config.is_normal)
-            .context("Failed to allocate new UID")?
+/// The hook runs after all databases have been written. A failing hook is only logged because the
+/// changes to the databases are already committed.
+fn run_hook(command: &str) {
+    log::info!("Running hook {command:?}...");
+    let output = match process::Command::new("/bin/sh")
+        .args(["-c", command])
+        .stdin(process::Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            log::error!("Failed to run hook {command:?}: {e}.");
+            return;
+        }
     };
 
-    let gid = if let Some(ref primary_group) = user_config.group {
-        resolve_group(primary_group, group_db)?
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        log::info!("Hook: {line}");
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        log::warn!("Hook: {line}");
+    }
+    if output.status.success() {
+        log::info!("Hook {command:?} succeeded.");
     } else {
-        // If we cannot re-use the UID as GID (because it's already used), allocate a new GID.
-        let gid = if group_db.contains_gid(uid) {
-            None
-        } else {
-            Some(uid)
-        };
-
-        // No group was provided so create a new group with the same name of the user and re-use
-        // the UID as GID.
-        let group_config = config::Group {
-            is_normal: user_config.is_normal,
-            name: user_config.name.clone(),
-            gid,
-            members: BTreeSet::from([user_config.name.clone()]),
-        };
-
-        create_group(&group_config, group_db)
-            .with_context(|| format!("Failed to create group for user {}", user_config.name))?;
-        uid
-    };
-
-    let new_entry = passwd::Entry::new(
-        user_config.name.clone(),
-        uid,
-        gid,
-        user_config.description.clone().unwrap_or_default(),
-        user_config.home.clone().unwrap_or_default(),
-        user_config.shell.clone().unwrap_or(
-            std::env::var("USERBORN_NO_LOGIN_PATH")
-                .unwrap_or(NO_LOGIN_DEFAULT.unwrap_or(NO_LOGIN_FALLBACK).into()),
-        ),
-    );
-
-    let description = new_entry.describe();
-
-    passwd_db.insert(&new_entry).with_context(|| {
-        format!(
-            "Failed to add entry to passwd database for user {}",
-            user_config.name
-        )
-    })?;
-
-    ensure_shadow(user_config, shadow_db)?;
-
-    log::info!("Created user {description}.");
-    Ok(())
+        log::error!("Hook {command:?} failed with {}.", output.status);
+    }
 }
 
-/// Update an already existing user, directly mutating the passed entry.
-fn update_user(
-    existing_entry: &mut passwd::Entry,
-    user_config: &config::User,
-    group_db: &Group,
-    shadow_db: &mut Shadow,
-) -> Result<()> {
-    log::debug!("Updating passwd entry for {}...", user_config.name);
-
-    let gid = user_config.group.as_ref().and_then(|g| {
-        if let Ok(gid) = resolve_group(g, group_db) {
-            Some(gid)
-        } else {
+/// Read a database, falling back to its backup (e.g. /etc/passwd-) if the file is corrupt.
+///
+/// A file that exists but cannot be read or doesn't contain a single valid entry is considered
+/// corrupt. Treating it as empty would allocate new IDs for all users and groups. A database that
+/// doesn't exist is empty.
+fn read_database<T: Default>(
+    path: &str,
+    from_file: impl Fn(&str) -> Result<T, userborn::Error>,
+    is_empty: impl Fn(&T) -> bool,
+) -> T {
+    if !Path::new(path).exists() {
+        return T::default();
+    }
+    let db = match from_file(path).map_err(anyhow::Error::from) {
+        Ok(db) if !is_empty(&db) => return db,
+        db => db,
+    };
+
+    let backup_path = format!("{path}-");
+    match from_file(&backup_path) {
+        Ok(backup) if !is_empty(&backup) => {
             log::error!(
-                "Group {g} doesn't exist. Not updating primary group of user {}.",
-                user_config.name
+                "{path} is corrupt or empty. Reading the entries from the backup {backup_path} \
+                 instead. Check {path} manually!"
             );
-            None
+            backup
         }
-    });
-
-    existing_entry.update(
-        gid,
-        user_config.description.clone(),
-        user_config.home.clone(),
-        user_config.shell.clone(),
-    );
-
-    ensure_shadow(user_config, shadow_db)?;
-
-    Ok(())
+        _ => {
+            if let Err(e) = &db {
+                log::warn!("Failed to read {path}, treating it as empty: {e:#}");
+            }
+            db.unwrap_or_default()
+        }
+    }
 }
 
-/// Resolve a string that can either be a group name or a GID to a proper GID.
+/// Whether writing a database to `path` would change the file.
 ///
-/// Resolve GID from group name using the group database.
-fn resolve_group(s: &str, group_db: &Group) -> Result<u32> {
-    if let Ok(uid) = s.parse::<u32>() {
-        Ok(uid)
-    } else {
-        let existing_group_entry = group_db.get(s).ok_or(anyhow!("Group {s} doesn't exist"))?;
-        Ok(existing_group_entry.gid())
+/// Used instead of writing the database with `--dry-run` and `--check`. This compares the
+/// content exactly like writing the database does to skip unchanged files. Each database that
+/// would change is logged.
+fn would_change(path: &str, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> bool {
+    let changed = !has_content(Path::new(path), write);
+    if changed {
+        log::info!("Would change {path}.");
     }
+    changed
 }
 
-/// Ensure that a shadow entry exists for the provided uses.
+/// Warn if the shadow database grants more permissions than [`userborn::shadow::MAX_MODE`].
 ///
-/// Updates an existing shadow entry or creates a new one.
-fn ensure_shadow(user_config: &config::User, shadow_db: &mut Shadow) -> Result<()> {
-    if let Some(existing_entry) = shadow_db.get_mut(&user_config.name) {
-        log::debug!("Updating shadow entry for {}...", user_config.name);
-
-        let hashed_password = HashedPassword::from_config(
-            &user_config.password,
-            Some(existing_entry.password()),
-            &user_config.name,
-        )?
-        .and_then(|hashed_password| match hashed_password {
-            HashedPassword::Override(s) => Some(s),
-            HashedPassword::Initial(_) => None,
-        });
-
-        existing_entry.update(hashed_password);
-    } else {
-        log::debug!("Creating shadow entry for {}...", user_config.name);
+/// Returns whether it does. The mode is restricted when the database is written.
+fn check_shadow_mode(path: &str) -> bool {
+    let Some(mode) = excess_mode(Path::new(path), userborn::shadow::MAX_MODE) else {
+        return false;
+    };
+    log::warn!(
+        "{path} has the mode {mode:04o}, which is more permissive than {:04o}.",
+        userborn::shadow::MAX_MODE
+    );
+    true
+}
 
-        let hashed_password =
-            HashedPassword::from_config(&user_config.password, None, &user_config.name)?.map(
-                |hashed_password| match hashed_password {
-                    HashedPassword::Override(s) | HashedPassword::Initial(s) => s,
-                },
-            );
+/// Write a subordinate ID database to a file.
+///
+/// Systems that don't use subordinate IDs don't get empty files.
+fn subid_to_file(subid_db: &SubId, path: &str) -> Result<bool> {
+    if subid_db.is_empty() && !Path::new(path).exists() {
+        return Ok(false);
+    }
+    Ok(subid_db.to_file(path)?)
+}
 
-        let new_entry = shadow::Entry::new(user_config.name.clone(), hashed_password);
+/// Check the databases for inconsistencies without modifying them.
+fn validate(args: &Args) -> Result<()> {
+    let group_db = Group::from_file(args.group_path())?;
+    let passwd_db = Passwd::from_file(args.passwd_path())?;
+    let shadow_db = read_shadow(args)?;
 
-        shadow_db.insert(&new_entry).with_context(|| {
-            format!(
-                "Failed to add entry to shadow database for user {}",
-                user_config.name
-            )
-        })?;
-    };
+    check_consistency(
+        &group_db,
+        &passwd_db,
+        (!args.no_shadow).then_some(&shadow_db),
+        args.strict,
+    )?;
+
+    log::info!("Validated the password databases.");
     Ok(())
 }
 
-/// Emit warnings for user entries that use weak password hashing schemes.
-fn warn_about_weak_password_hashes(shadow_db: &Shadow) {
-    for entry in shadow_db.entries() {
-        if !entry.uses_secure_hash() {
-            log::warn!("User {} uses an insecure password hashing scheme. Update their password as soon as possible.", entry.name());
-        }
+/// Read the shadow database unless it is not used (`--no-shadow`).
+fn read_shadow(args: &Args) -> Result<Shadow> {
+    if args.no_shadow {
+        return Ok(Shadow::default());
     }
+    Ok(Shadow::from_file(args.shadow_path())?)
+}
+
+/// Print the users and groups from the databases as JSON.
+fn export(args: &Args) -> Result<()> {
+    let group_db = Group::from_file(args.group_path())?;
+    let passwd_db = Passwd::from_file(args.passwd_path())?;
+    let shadow_db = read_shadow(args)?;
+
+    let export = Export::new(&group_db, &passwd_db, &shadow_db, args.include_secrets);
+    let export = serde_json::to_string_pretty(&export).context("Failed to serialize export")?;
+    writeln!(std::io::stdout(), "{export}").context("Failed to print export")?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use expect_test::expect;
-
-    fn gen0() -> Result<Config> {
-        Ok(serde_json::from_value(serde_json::json!({
-            "users": [
-                {
-                    "name": "root",
-                    "uid": 0,
-                },
-                {
-                    "isNormal": true,
-                    "name": "normalo",
-                    "home": "/home/normalo",
-                    "shell": "/bin/bash",
-                    "hashedPassword": "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4", // "hello"
-                },
-            ],
-            "groups": [
-                {
-                    "name": "wheel",
-                    "members": [ "normalo", ],
-                },
-            ],
-        }))?)
-    }
-
-    fn gen1() -> Result<Config> {
-        Ok(serde_json::from_value(serde_json::json!({
-            "users": [
-                {
-                    "name": "root",
-                    "uid": 0,
-                },
-                {
-                    "isNormal": true,
-                    "name": "normalo",
-                    // This should update the shell to zsh
-                    "shell": "/bin/zsh",
-                    // This shouldn't change the hash as it hashes the same as the existing
-                    // password
-                    "password": "hello",
-                },
-                {
-                    "isNormal": false,
-                    "name": "initial",
-                    "initialHashedPassword": "$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2",
-                },
-            ],
-            "groups": [
-                {
-                    "name": "wheel",
-                    "members": [ "normalo", "initial" ],
-                },
-            ],
-        }))?)
+    fn format(log_format: LogFormat, level: Level) -> io::Result<String> {
+        let mut buf = Vec::new();
+        log_format.write(
+            &mut buf,
+            &Record::builder()
+                .level(level)
+                .args(format_args!("Created user root."))
+                .build(),
+        )?;
+        Ok(String::from_utf8_lossy(&buf).into())
     }
 
-    fn gen2() -> Result<Config> {
-        Ok(serde_json::from_value(serde_json::json!({
-            "users": [
-                {
-                    "name": "root",
-                    "uid": 0,
-                    "home": "/root",
-                    // This shouldn't apply. The user should stay disabled.
-                    "initialHashedPassword": "$y$j9T$IMBPYrUksH4dZME8IQZPZ0$J3P/05qML9xZYHhkkIv3rNvXOAyb.tN56dJo8lTf0TA",
-                },
-                {
-                    // The users should keep the previous values even though they aren't present
-                    // here anymore.
-                    "name": "normalo",
-                    "description": "I'm normal I swear",
-                    // This should change the password
-                    "hashedPassword": "$y$j9T$CZSAJTLCfrBvcCgvOTY4W1$G7uzyX3O6K.DR8KJLL/oL.8EREPSRTIjBn76SpvcH4A",
-                },
-                // initial user should still exist even though we remove them from the config
-            ],
-            // wheel group should still exist even though we remove it from the config
-        }))?)
+    #[test]
+    fn log_formats() -> io::Result<()> {
+        assert_eq!(
+            format(LogFormat::Printk, Level::Warn)?,
+            "<4>Created user root.\n"
+        );
+        assert_eq!(
+            format(LogFormat::Human { color: false }, Level::Info)?,
+            "INFO  Created user root.\n"
+        );
+        assert_eq!(
+            format(LogFormat::Human { color: true }, Level::Error)?,
+            "\x1b[31mERROR\x1b[0m Created user root.\n"
+        );
+        Ok(())
     }
 
     #[test]
-    fn update_users_and_groups_across_generations() -> Result<()> {
-        // Explicitly set this because the expected values depend on this.
-        std::env::set_var("USERBORN_NO_LOGIN_PATH", NO_LOGIN_FALLBACK);
-
-        let mut group_db = Group::default();
-        let mut passwd_db = Passwd::default();
-        let mut shadow_db = Shadow::default();
-
-        // GEN 0
-
-        update_users_and_groups(&gen0()?, &mut group_db, &mut passwd_db, &mut shadow_db);
-
-        let expected_group = expect![[r#"
-            root:x:0:root
-            wheel:x:999:normalo
-            normalo:x:1000:normalo
-        "#]];
-        expected_group.assert_eq(&group_db.to_buffer());
-
-        let expected_passwd = expect![[r#"
-            root:x:0:0:::/run/current-system/sw/bin/nologin
-            normalo:x:1000:1000::/home/normalo:/bin/bash
-        "#]];
-        expected_passwd.assert_eq(&passwd_db.to_buffer());
-
-        let expected_shadow = expect![[r#"
-            root:!*:1::::::
-            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
-        "#]];
-        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
-
-        // GEN 1
-
-        update_users_and_groups(&gen1()?, &mut group_db, &mut passwd_db, &mut shadow_db);
-
-        let expected_group = expect![[r#"
-            root:x:0:root
-            initial:x:998:initial
-            wheel:x:999:initial,normalo
-            normalo:x:1000:normalo
-        "#]];
-        expected_group.assert_eq(&group_db.to_buffer());
-
-        let expected_passwd = expect![[r#"
-            root:x:0:0:::/run/current-system/sw/bin/nologin
-            initial:x:999:999:::/run/current-system/sw/bin/nologin
-            normalo:x:1000:1000::/home/normalo:/bin/zsh
-        "#]];
-        expected_passwd.assert_eq(&passwd_db.to_buffer());
-
-        let expected_shadow = expect![[r#"
-            root:!*:1::::::
-            initial:$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2:1::::::
-            normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
-        "#]];
-        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
-
-        // GEN 2
-
-        update_users_and_groups(&gen2()?, &mut group_db, &mut passwd_db, &mut shadow_db);
-
-        let expected_group = expect![[r#"
-            root:x:0:root
-            initial:x:998:initial
-            wheel:x:999:initial,normalo
-            normalo:x:1000:normalo
-        "#]];
-        expected_group.assert_eq(&group_db.to_buffer());
-
-        let expected_passwd = expect![[r#"
-            root:x:0:0::/root:/run/current-system/sw/bin/nologin
-            initial:x:999:999:::/run/current-system/sw/bin/nologin
-            normalo:x:1000:1000:I'm normal I swear:/home/normalo:/bin/zsh
-        "#]];
-        expected_passwd.assert_eq(&passwd_db.to_buffer());
-
-        let expected_shadow = expect![[r#"
-            root:!*:1::::::
-            initial:!*:1::::::
-            normalo:$y$j9T$CZSAJTLCfrBvcCgvOTY4W1$G7uzyX3O6K.DR8KJLL/oL.8EREPSRTIjBn76SpvcH4A:1::::::
-        "#]];
-        expected_shadow.assert_eq(&shadow_db.to_buffer_sorted(&passwd_db));
+    fn read_backup_of_corrupt_database() -> Result<()> {
+        let directory =
+            std::env::temp_dir().join(format!("userborn-test-read-backup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory)?;
+        let path = directory.join("passwd");
+        let path = path
+            .to_str()
+            .context("Temporary directory is not valid UTF-8")?;
+        let backup_path = format!("{path}-");
+
+        let read =
+            || read_database(path, |path| Passwd::from_file(path), Passwd::is_empty).to_buffer();
+
+        // Neither the database nor its backup exist.
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(&backup_path);
+        assert_eq!(read(), "");
+
+        std::fs::write(&backup_path, "root:x:0:0::/root:/bin/sh\n")?;
+
+        // A database that doesn't exist is not corrupt.
+        assert_eq!(read(), "");
+
+        std::fs::write(path, "")?;
+        assert_eq!(read(), "root:x:0:0::/root:/bin/sh\n");
+
+        std::fs::write(path, "garbage\n")?;
+        assert_eq!(read(), "root:x:0:0::/root:/bin/sh\n");
+
+        std::fs::write(path, [0xff, 0xfe])?;
+        assert_eq!(read(), "root:x:0:0::/root:/bin/sh\n");
+
+        std::fs::write(path, "normalo:x:1000:1000:::/bin/sh\n")?;
+        assert_eq!(read(), "normalo:x:1000:1000:::/bin/sh\n");
 
+        std::fs::remove_dir_all(&directory)?;
         Ok(())
     }
 }
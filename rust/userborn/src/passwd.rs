@@ -1,16 +1,103 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs,
+    fmt, fs,
+    io::{self, Write},
     path::Path,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
 
-use crate::{fs::atomic_write, id};
+use crate::{
+    compat::CompatLines,
+    config::{AllocationDirection, ReservedId, SortOrder},
+    error::Error,
+    fs::write_if_changed,
+    group::Group,
+    id::{self, IdKind},
+    shadow::Shadow,
+};
 
 /// Password for /etc/passwd indicating that the actual password is stored in /etc/shadow.
 const PASSWORD_IN_SHADOW: &str = "x";
 
+/// Password for /etc/passwd of a locked account on a system without /etc/shadow.
+const PASSWORD_DISABLED: &str = "*";
+
+/// Mode of a newly created /etc/passwd unless another one is provided.
+pub const DEFAULT_MODE: u32 = 0o644;
+
+/// Home directories that are conventionally shared by users that don't have a real home.
+const SHARED_HOMES: [&str; 3] = ["", "/var/empty", "/nonexistent"];
+
+/// The components of the GECOS field of an /etc/passwd entry.
+///
+/// By convention, the GECOS field consists of these comma-separated components.
+#[derive(Deserialize, JsonSchema, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Gecos {
+    /// The full name of the user
+    pub full_name: Option<String>,
+    /// The room number or building of the user
+    pub room: Option<String>,
+    /// The work phone number of the user
+    pub work_phone: Option<String>,
+    /// The home phone number of the user
+    pub home_phone: Option<String>,
+    /// Any other information about the user
+    pub other: Option<String>,
+}
+
+impl Gecos {
+    /// Parse a GECOS field into its components.
+    ///
+    /// Empty components are `None`. Everything after the fourth comma ends up in `other`.
+    pub fn parse(s: &str) -> Self {
+        let mut components = s
+            .splitn(5, ',')
+            .map(|c| Some(c.to_string()).filter(|c| !c.is_empty()));
+        Self {
+            full_name: components.next().flatten(),
+            room: components.next().flatten(),
+            work_phone: components.next().flatten(),
+            home_phone: components.next().flatten(),
+            other: components.next().flatten(),
+        }
+    }
+
+    /// Use the components of `fallback` for all components that are not set.
+    #[must_use]
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            full_name: self.full_name.or(fallback.full_name),
+            room: self.room.or(fallback.room),
+            work_phone: self.work_phone.or(fallback.work_phone),
+            home_phone: self.home_phone.or(fallback.home_phone),
+            other: self.other.or(fallback.other),
+        }
+    }
+}
+
+impl fmt::Display for Gecos {
+    /// Join the components with commas, omitting trailing empty components.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let components = [
+            &self.full_name,
+            &self.room,
+            &self.work_phone,
+            &self.home_phone,
+            &self.other,
+        ]
+        .map(|c| c.as_deref().unwrap_or_default());
+        let len = components
+            .iter()
+            .rposition(|c| !c.is_empty())
+            .map_or(0, |i| i + 1);
+        write!(f, "{}", components[..len].join(","))
+    }
+}
+
 #[derive(Clone)]
 pub struct Entry {
     name: String,
@@ -25,10 +112,11 @@ pub struct Entry {
 impl Entry {
     /// Create a new /etc/passwd entry.
     ///
-    /// The password is always set to `x` because the actual password hash is stored in
-    /// /etc/shadow.
+    /// The password is set to `marker`. This should be `x` unless there is no /etc/shadow
+    /// because otherwise the actual password hash is not looked up there.
     pub fn new(
         name: String,
+        marker: &str,
         uid: u32,
         gid: u32,
         gecos: String,
@@ -37,7 +125,7 @@ impl Entry {
     ) -> Self {
         Self {
             name,
-            password: PASSWORD_IN_SHADOW.into(),
+            password: marker.into(),
             uid,
             gid,
             gecos,
@@ -47,6 +135,9 @@ impl Entry {
     }
 
     /// Update an /etc/passwd entry.
+    ///
+    /// Fields that are `None` keep their existing value. `Some` with an empty string clears a
+    /// field.
     pub fn update(
         &mut self,
         gid: Option<u32>,
@@ -96,6 +187,35 @@ impl Entry {
         }
     }
 
+    /// Point the password field to /etc/shadow.
+    ///
+    /// Returns the previous content of the field unless it already pointed to /etc/shadow.
+    pub fn take_password(&mut self) -> Option<String> {
+        if self.password == PASSWORD_IN_SHADOW {
+            return None;
+        }
+        Some(std::mem::replace(
+            &mut self.password,
+            PASSWORD_IN_SHADOW.into(),
+        ))
+    }
+
+    /// Set the password field for a system without /etc/shadow.
+    ///
+    /// A locked account gets `*`, every other account `marker`. Neither is supposed to be a valid
+    /// hash, so password authentication is impossible either way.
+    pub fn set_password_without_shadow(&mut self, locked: bool, marker: &str) {
+        let password = if locked { PASSWORD_DISABLED } else { marker };
+        if self.password != password {
+            log::info!(
+                "Updating password field of user {} from {} to {password}...",
+                self.name,
+                self.password,
+            );
+            self.password = password.into();
+        }
+    }
+
     /// Read an entry from a single line from /etc/shadow.
     ///
     /// Whenever a field in this line doesn't exist or cannot be parsed, returns `None`.
@@ -103,6 +223,8 @@ impl Entry {
         if line.starts_with('#') {
             return None;
         }
+        // Only strip a trailing carriage return. Other whitespace may be part of the last field.
+        let line = line.strip_suffix('\r').unwrap_or(line);
         let mut fields = line.splitn(7, ':');
         Some(Self {
             name: fields.next()?.into(),
@@ -136,6 +258,32 @@ impl Entry {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// The raw GECOS field.
+    pub fn description(&self) -> &str {
+        &self.gecos
+    }
+
+    /// The components of the GECOS field.
+    pub fn gecos(&self) -> Gecos {
+        Gecos::parse(&self.gecos)
+    }
+
+    pub fn home(&self) -> &str {
+        &self.directory
+    }
+
+    pub fn shell(&self) -> &str {
+        &self.shell
+    }
 }
 
 #[derive(Default)]
@@ -144,41 +292,136 @@ pub struct Passwd {
     entries: BTreeMap<u32, Entry>,
     /// Mapping of names to UIDs.
     uids: BTreeMap<String, u32>,
+    /// Names of users that share a UID in the file this database was read from.
+    duplicate_uids: BTreeMap<u32, BTreeSet<String>>,
+    /// UIDs of users that appear multiple times in the file this database was read from.
+    duplicate_names: BTreeMap<String, BTreeSet<u32>>,
+    /// The order in which the entries are written.
+    sort_order: SortOrder,
+    /// NIS compat lines that are passed through unchanged.
+    compat_lines: CompatLines,
 }
 
 impl Passwd {
-    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let file = fs::read_to_string(path.as_ref())
-            .with_context(|| format!("Failed to read {:?}.", path.as_ref()))?;
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = fs::read_to_string(path.as_ref()).map_err(|source| Error::ReadDatabase {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
 
         Ok(Self::from_buffer(&file))
     }
 
+    /// Read the database from a string buffer.
+    ///
+    /// If multiple users share a UID, only the last one is kept. Duplicates are recorded and can
+    /// be retrieved via `duplicate_uids()` and `duplicate_names()`.
+    ///
+    /// NIS compat lines (e.g. `+` or `-user`) are kept and written at their original position.
     pub fn from_buffer(s: &str) -> Self {
-        let mut entries = BTreeMap::new();
-        let mut uids = BTreeMap::new();
+        let mut passwd = Self::default();
         for line in s.lines() {
-            if let Some(e) = Entry::from_line(line) {
-                entries.insert(e.uid, e.clone());
-                uids.insert(e.name.clone(), e.uid);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if CompatLines::is_compat_line(line) {
+                passwd.compat_lines.push(line);
+            } else if let Some(e) = Entry::from_line(line) {
+                passwd.compat_lines.entry(&e.name);
+                passwd.insert_parsed(e);
             } else {
                 log::warn!("Skipping passwd line because it cannot be parsed: {line}.");
             }
         }
-        Self { entries, uids }
+        passwd.compat_lines.finish();
+        passwd
     }
 
-    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<()> {
-        atomic_write(path, self.to_buffer(), 0o644)
+    /// Insert an entry read from a file, recording duplicate UIDs and names.
+    fn insert_parsed(&mut self, e: Entry) {
+        if let Some(existing_uid) = self.uids.get(&e.name).copied() {
+            if existing_uid != e.uid {
+                log::warn!(
+                    "User {} appears multiple times with UIDs {existing_uid} and {}.",
+                    e.name,
+                    e.uid
+                );
+                self.duplicate_names
+                    .entry(e.name.clone())
+                    .or_default()
+                    .extend([existing_uid, e.uid]);
+            }
+        }
+
+        if let Some(existing_entry) = self.entries.get(&e.uid) {
+            let existing_name = existing_entry.name.clone();
+            log::warn!(
+                "Users {existing_name} and {} share UID {}. Only keeping {}.",
+                e.name,
+                e.uid,
+                e.name
+            );
+            self.duplicate_uids
+                .entry(e.uid)
+                .or_default()
+                .extend([existing_name.clone(), e.name.clone()]);
+            // The dropped user must not resolve to the entry that replaced it.
+            if self.uids.get(&existing_name) == Some(&e.uid) {
+                self.uids.remove(&existing_name);
+            }
+        }
+
+        self.uids.insert(e.name.clone(), e.uid);
+        self.entries.insert(e.uid, e);
+    }
+
+    /// Write the passwd database to a file.
+    ///
+    /// `mode` is only used if the file doesn't exist yet. Returns whether the file has changed.
+    pub fn to_file(&self, path: impl AsRef<Path>, mode: u32) -> Result<bool, Error> {
+        write_if_changed(path, mode, |writer| self.to_writer(writer))
+    }
+
+    /// Write the database line by line into a writer.
+    pub fn to_writer(&self, mut writer: impl Write) -> io::Result<()> {
+        for line in self.lines() {
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
     }
 
     pub fn to_buffer(&self) -> String {
-        let mut s = String::new();
-        for entry in self.entries.values() {
-            s.push_str(&entry.to_line());
-            s.push('\n');
+        self.lines().map(|line| line + "\n").collect()
+    }
+
+    fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.compat_lines.interleave(
+            self.sorted_entries()
+                .into_iter()
+                .map(|entry| (entry.name.as_str(), entry.to_line())),
+        )
+    }
+
+    /// Set the order in which the entries are written.
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    /// The entries in the order in which they are written.
+    pub fn sorted_entries(&self) -> Vec<&Entry> {
+        let mut entries = self.entries();
+        if self.sort_order == SortOrder::ByName {
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
         }
-        s
+        entries
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        let uid = self.uids.get(name);
+        uid.and_then(|uid| self.entries.get(uid))
+    }
+
+    /// The user owning a UID.
+    pub fn get_by_uid(&self, uid: u32) -> Option<&Entry> {
+        self.entries.get(&uid)
     }
 
     pub fn get_mut(&mut self, name: &str) -> Option<&mut Entry> {
@@ -189,32 +432,184 @@ impl Passwd {
     /// Insert a new entry.
     ///
     /// This will fail if a user with the UID or name already exists.
-    pub fn insert(&mut self, entry: &Entry) -> Result<()> {
+    pub fn insert(&mut self, entry: Entry) -> Result<(), Error> {
         if self.entries.contains_key(&entry.uid) {
-            bail!("User with UID {} already exists", entry.uid);
+            return Err(Error::IdTaken {
+                database: "passwd",
+                kind: IdKind::Uid,
+                id: entry.uid,
+            });
         }
 
         if self.uids.contains_key(&entry.name) {
-            bail!("User {} already exists", entry.name);
+            return Err(Error::NameTaken {
+                database: "passwd",
+                name: entry.name,
+            });
         }
 
-        self.entries.entry(entry.uid).or_insert(entry.clone());
         self.uids.insert(entry.name.clone(), entry.uid);
+        self.entries.insert(entry.uid, entry);
 
         Ok(())
     }
 
+    /// Remove a user.
+    ///
+    /// Returns the removed entry.
+    pub fn remove(&mut self, name: &str) -> Option<Entry> {
+        let uid = self.uids.remove(name)?;
+        self.entries.remove(&uid)
+    }
+
+    /// Rename a user, keeping its UID.
+    ///
+    /// This will fail if a user with the new name already exists.
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), Error> {
+        if self.is_name_taken(new_name) {
+            return Err(Error::NameTaken {
+                database: "passwd",
+                name: new_name.into(),
+            });
+        }
+        let uid = self.uids.remove(name).ok_or_else(|| Error::NoSuchEntry {
+            database: "passwd",
+            name: name.into(),
+        })?;
+        if let Some(entry) = self.entries.get_mut(&uid) {
+            entry.name = new_name.into();
+        }
+        self.uids.insert(new_name.into(), uid);
+        Ok(())
+    }
+
     /// Allocate a new (i.e. unused) UID.
     ///
     /// Returns `Err` if it cannot allocate a new UID because all in the range are already used.
-    pub fn allocate_uid(&self, is_normal: bool) -> Result<u32> {
+    pub fn allocate_uid(
+        &self,
+        reserved_ids: &[ReservedId],
+        is_normal: bool,
+        limit: Option<u32>,
+        direction: AllocationDirection,
+    ) -> Result<u32, Error> {
         let allocated_uids = self.entries.keys().copied().collect::<BTreeSet<u32>>();
-        id::allocate(&allocated_uids, is_normal)
+        id::allocate(
+            &allocated_uids,
+            reserved_ids,
+            is_normal,
+            limit,
+            direction,
+            IdKind::Uid,
+        )
+    }
+
+    /// Names of users that shared a UID when the database was read.
+    pub fn duplicate_uids(&self) -> &BTreeMap<u32, BTreeSet<String>> {
+        &self.duplicate_uids
+    }
+
+    /// UIDs of users that appeared multiple times when the database was read.
+    pub fn duplicate_names(&self) -> &BTreeMap<String, BTreeSet<u32>> {
+        &self.duplicate_names
+    }
+
+    /// Find users that are in the passwd database but not in the shadow database.
+    pub fn find_orphans(&self, shadow: &Shadow) -> Vec<String> {
+        self.entries
+            .values()
+            .filter(|entry| shadow.get(&entry.name).is_none())
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    /// Find users whose primary GID doesn't exist in the group database.
+    pub fn users_with_missing_primary_group(&self, group: &Group) -> Vec<String> {
+        self.entries
+            .values()
+            .filter(|entry| group.is_gid_free(entry.gid))
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    /// Find home directories that are shared by multiple normal users.
+    ///
+    /// Conventionally shared directories like /var/empty are ignored. The users are sorted by UID.
+    pub fn duplicate_homes(&self) -> Vec<(String, Vec<String>)> {
+        let mut homes: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for entry in self.entries.values() {
+            if id::is_normal(entry.uid) && !SHARED_HOMES.contains(&entry.directory.as_str()) {
+                homes
+                    .entry(&entry.directory)
+                    .or_default()
+                    .push(entry.name.clone());
+            }
+        }
+        homes
+            .into_iter()
+            .filter(|(_, names)| names.len() > 1)
+            .map(|(home, names)| (home.into(), names))
+            .collect()
+    }
+
+    /// Find users that are not a member of their per-user primary group.
+    ///
+    /// A per-user group has the same name as the user. Groups created by Userborn for users
+    /// without a primary group always contain the user. Root is ignored because the root group
+    /// conventionally has no members.
+    pub fn users_missing_from_own_group(&self, group: &Group) -> Vec<String> {
+        self.entries
+            .values()
+            .filter(|entry| entry.uid != 0)
+            .filter(|entry| {
+                group.get(&entry.name).is_some_and(|group_entry| {
+                    group_entry.gid() == entry.gid && !group_entry.members().contains(&entry.name)
+                })
+            })
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    /// Users whose UID differs from the GID of the group with the same name.
+    ///
+    /// Returns the name, the UID and the GID. By convention, a per-user group has the UID of its
+    /// user as GID, so a mismatch is often a latent bug. It is not an error though, e.g. if the
+    /// GID was already taken when the group was created.
+    pub fn users_with_mismatched_group_id(&self, group: &Group) -> Vec<(String, u32, u32)> {
+        self.entries
+            .values()
+            .filter_map(|entry| {
+                let gid = group.get(&entry.name)?.gid();
+                (gid != entry.uid).then(|| (entry.name.clone(), entry.uid, gid))
+            })
+            .collect()
+    }
+
+    /// Whether no user has this UID.
+    pub fn is_uid_free(&self, uid: u32) -> bool {
+        !self.entries.contains_key(&uid)
+    }
+
+    /// Whether a user with this name exists.
+    pub fn is_name_taken(&self, name: &str) -> bool {
+        self.uids.contains_key(name)
     }
 
     pub fn entries(&self) -> Vec<&Entry> {
         self.entries.values().collect()
     }
+
+    pub fn entries_mut(&mut self) -> impl IntoIterator<Item = &mut Entry> {
+        self.entries.values_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -246,6 +641,43 @@ mod tests {
         expected.assert_eq(&recreated_buffer);
     }
 
+    #[test]
+    fn sort_by_name() {
+        let mut passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            messagebus:x:4:4::/run/dbus:/run/current-system/sw/bin/nologin
+            gary:x:1000:1000::/home/gary:/bin/bash
+            alice:x:1001:1001::/home/alice:/bin/bash
+        "});
+        passwd.set_sort_order(SortOrder::ByName);
+
+        let expected = expect![[r#"
+            alice:x:1001:1001::/home/alice:/bin/bash
+            gary:x:1000:1000::/home/gary:/bin/bash
+            messagebus:x:4:4::/run/dbus:/run/current-system/sw/bin/nologin
+            root:x:0:0::/root:/bin/bash
+        "#]];
+        expected.assert_eq(&passwd.to_buffer());
+    }
+
+    #[test]
+    fn free_ids_and_taken_names() -> Result<()> {
+        let mut passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            gary:x:1000:1000::/home/gary:/bin/bash
+        "});
+        assert!(!passwd.is_uid_free(1000));
+        assert!(passwd.is_uid_free(1001));
+        assert!(passwd.is_name_taken("gary"));
+        assert!(!passwd.is_name_taken("alice"));
+
+        passwd.rename("gary", "alice")?;
+        assert!(!passwd.is_name_taken("gary"));
+        assert!(passwd.is_name_taken("alice"));
+        assert!(!passwd.is_uid_free(1000));
+        Ok(())
+    }
+
     #[test]
     fn skip_comments_and_broken_lines() {
         let buffer = indoc! {"
@@ -261,4 +693,178 @@ mod tests {
         "]];
         expected.assert_eq(&recreated_buffer);
     }
+
+    #[test]
+    fn detect_duplicates() {
+        let buffer = indoc! {"
+            root:x:0:0::/root:/bin/bash
+            toor:x:0:0::/root:/bin/bash
+            gary:x:1000:1000::/home/gary:/bin/bash
+            gary:x:1001:1001::/home/gary:/bin/bash
+        "};
+        let passwd = Passwd::from_buffer(buffer);
+
+        assert_eq!(
+            passwd.duplicate_uids(),
+            &BTreeMap::from([(0, BTreeSet::from(["root".into(), "toor".into()]))])
+        );
+        assert_eq!(
+            passwd.duplicate_names(),
+            &BTreeMap::from([("gary".into(), BTreeSet::from([1000, 1001]))])
+        );
+        assert!(passwd.get("root").is_none());
+        assert!(passwd.get("toor").is_some());
+        assert_eq!(passwd.get_by_uid(0).map(Entry::name), Some("toor"));
+        assert_eq!(passwd.get_by_uid(1001).map(Entry::name), Some("gary"));
+        assert!(passwd.get_by_uid(1).is_none());
+    }
+
+    #[test]
+    fn detect_duplicate_homes() {
+        let buffer = indoc! {"
+            sshd:x:998:998::/var/empty:/bin/nologin
+            messagebus:x:997:997::/var/empty:/bin/nologin
+            alice:x:1000:1000::/home/shared:/bin/bash
+            bob:x:1001:1001::/home/shared:/bin/bash
+            carol:x:1002:1002::/home/carol:/bin/bash
+            dave:x:1003:1003::/var/empty:/bin/bash
+            erin:x:1004:1004::/var/empty:/bin/bash
+            frank:x:1005:1005:::/bin/bash
+            grace:x:1006:1006:::/bin/bash
+            service:x:500:500::/home/carol:/bin/nologin
+        "};
+        let passwd = Passwd::from_buffer(buffer);
+
+        assert_eq!(
+            passwd.duplicate_homes(),
+            vec![("/home/shared".into(), vec!["alice".into(), "bob".into()])]
+        );
+    }
+
+    #[test]
+    fn detect_users_missing_from_own_group() {
+        let passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            member:x:1000:1000:::/bin/bash
+            missing:x:1001:1001:::/bin/bash
+            shared:x:1002:100:::/bin/bash
+            other:x:1003:1004:::/bin/bash
+        "});
+        let group = Group::from_buffer(indoc! {"
+            root:x:0:
+            users:x:100:
+            member:x:1000:member
+            missing:x:1001:
+            other:x:1003:
+            elsewhere:x:1004:
+        "});
+
+        assert_eq!(
+            passwd.users_missing_from_own_group(&group),
+            vec!["missing".to_string()]
+        );
+    }
+
+    #[test]
+    fn detect_users_with_mismatched_group_id() {
+        let passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0::/root:/bin/bash
+            matching:x:1000:1000:::/bin/bash
+            mismatched:x:1001:1005:::/bin/bash
+            groupless:x:1002:100:::/bin/bash
+        "});
+        let group = Group::from_buffer(indoc! {"
+            root:x:0:
+            users:x:100:groupless
+            matching:x:1000:matching
+            mismatched:x:1005:mismatched
+        "});
+
+        assert_eq!(
+            passwd.users_with_mismatched_group_id(&group),
+            vec![("mismatched".to_string(), 1001, 1005)]
+        );
+    }
+
+    #[test]
+    fn nis_compat_lines() -> Result<()> {
+        let buffer = indoc! {"
+            root:x:0:0::/root:/bin/bash
+            -gary::::::
+            gary:x:1000:1000::/home/gary:/bin/bash
+            +@admins::::::
+            +:::::::
+        "};
+        let mut passwd = Passwd::from_buffer(buffer);
+        assert_eq!(passwd.to_buffer(), buffer);
+
+        // New entries are written before the trailing compat lines.
+        passwd.insert(Entry::new(
+            "normalo".into(),
+            PASSWORD_IN_SHADOW,
+            1001,
+            1001,
+            String::new(),
+            "/home/normalo".into(),
+            "/bin/bash".into(),
+        ))?;
+        let expected = expect![[r#"
+            root:x:0:0::/root:/bin/bash
+            -gary::::::
+            gary:x:1000:1000::/home/gary:/bin/bash
+            normalo:x:1001:1001::/home/normalo:/bin/bash
+            +@admins::::::
+            +:::::::
+        "#]];
+        expected.assert_eq(&passwd.to_buffer());
+        Ok(())
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let buffer = "root:x:0:0:System administrator :/root:/bin/bash\r\ngary:x:1000:1000:Gary ,,,:/home/gary:/bin/bash\r";
+        let passwd = Passwd::from_buffer(buffer);
+        let recreated_buffer = passwd.to_buffer();
+
+        let expected = expect![[r#"
+            root:x:0:0:System administrator :/root:/bin/bash
+            gary:x:1000:1000:Gary ,,,:/home/gary:/bin/bash
+        "#]];
+        expected.assert_eq(&recreated_buffer);
+        assert_eq!(
+            Passwd::from_buffer(&recreated_buffer).to_buffer(),
+            recreated_buffer
+        );
+    }
+
+    #[test]
+    fn gecos() {
+        let gecos = Gecos::parse("Gary Oak,Lab 1,555-1234,,likes, commas");
+        assert_eq!(
+            gecos,
+            Gecos {
+                full_name: Some("Gary Oak".into()),
+                room: Some("Lab 1".into()),
+                work_phone: Some("555-1234".into()),
+                home_phone: None,
+                other: Some("likes, commas".into()),
+            }
+        );
+        assert_eq!(gecos.to_string(), "Gary Oak,Lab 1,555-1234,,likes, commas");
+
+        assert_eq!(Gecos::parse("").to_string(), "");
+        assert_eq!(Gecos::parse("Gary ,,,").to_string(), "Gary ");
+
+        let gecos = Gecos {
+            room: Some("Lab 2".into()),
+            ..Gecos::default()
+        };
+        assert_eq!(gecos.to_string(), ",Lab 2");
+        assert_eq!(
+            gecos
+                .or(Gecos::parse("Gary Oak,Lab 1,555-1234"))
+                .to_string(),
+            "Gary Oak,Lab 2,555-1234"
+        );
+    }
 }
@@ -1,9 +1,43 @@
-use std::fs;
+use std::{
+    collections::BTreeMap,
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    thread,
+};
 
 use anyhow::{Context, Result};
 use xcrypt::{crypt, crypt_gensalt};
 
-use crate::config;
+use crate::{config, error::Error};
+
+/// Hashes plaintext passwords into the format of /etc/shadow.
+///
+/// This abstracts over `libxcrypt` so that tests can use a deterministic implementation.
+pub trait Hasher: Send + Sync {
+    /// Hash a password with the scheme and salt of `setting`.
+    ///
+    /// `setting` is either generated via `gensalt()` or an existing hash.
+    fn crypt(&self, password: &str, setting: &str) -> Result<String, Error>;
+
+    /// Generate a setting with a new random salt.
+    fn gensalt(&self) -> Result<String, Error>;
+}
+
+/// Hash passwords with yescrypt via `libxcrypt`.
+pub struct XcryptHasher;
+
+impl Hasher for XcryptHasher {
+    fn crypt(&self, password: &str, setting: &str) -> Result<String, Error> {
+        crypt(password, setting).map_err(|e| Error::Hash { source: e.into() })
+    }
+
+    fn gensalt(&self) -> Result<String, Error> {
+        crypt_gensalt(Some("$y$"), 0, None)
+            .context("Failed to generate setting for crypt")
+            .map_err(|e| Error::Hash { source: e.into() })
+    }
+}
 
 /// A hashed password.
 ///
@@ -13,6 +47,7 @@ use crate::config;
 /// This is the order in which they are considered:
 ///
 /// - `hashed_password_file`
+/// - `hashed_password_credential`
 /// - `hashed_password`
 /// - `password`
 /// - `initial_hashed_password`
@@ -37,12 +72,21 @@ impl HashedPassword {
         password_config: &config::Password,
         current_password: Option<&str>,
         name: &str,
+        hasher: &dyn Hasher,
     ) -> Result<Option<Self>> {
         let hashed_password = if let Some(path) = &password_config.hashed_password_file {
+            let path =
+                config::expand_env_vars(path).context("Failed to expand hashedPasswordFile")?;
             log::debug!("Using hashedPasswordFile {path:?} for user {name}...");
-            let hashed_password = fs::read_to_string(path)
+            let hashed_password = fs::read_to_string(&path)
                 .with_context(|| format!("Failed to read hashedPasswordFile {path:?}"))?;
             Some(Self::Override(hashed_password.trim().into()))
+        } else if let Some(credential) = &password_config.hashed_password_credential {
+            log::debug!("Using hashedPasswordCredential {credential:?} for user {name}...");
+            let path = credential_path(credential)?;
+            let hashed_password = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read hashedPasswordCredential {path:?}"))?;
+            Some(Self::Override(hashed_password.trim().into()))
         } else if let Some(hashed_password) = &password_config.hashed_password {
             log::debug!("Using hashedPassword for user {name}...");
             Some(Self::Override(hashed_password.clone()))
@@ -52,7 +96,8 @@ impl HashedPassword {
                 "User {name} uses a plaintext password. This is inscure and should only be used for testing purposes."
             );
             Some(Self::Override(
-                hash_password(raw_password, current_password).context("Failed to hash password")?,
+                hash_password(hasher, raw_password, current_password)
+                    .context("Failed to hash password")?,
             ))
         } else if let Some(hashed_password) = &password_config.initial_hashed_password {
             log::debug!("Using initialHashedPassword for user {name}...");
@@ -63,7 +108,8 @@ impl HashedPassword {
                 "User {name} uses a plaintext password. This is inscure and should only be used for testing purposes."
             );
             Some(Self::Initial(
-                hash_password(raw_password, current_password).context("Failed to hash password")?,
+                hash_password(hasher, raw_password, current_password)
+                    .context("Failed to hash password")?,
             ))
         } else {
             None
@@ -73,7 +119,168 @@ impl HashedPassword {
     }
 }
 
-/// Hash a raw password using `libxcrypt`.
+/// A user whose password is resolved ahead of time.
+pub struct PasswordRequest<'a> {
+    pub name: &'a str,
+    pub config: &'a config::Password,
+    /// The current hash that is re-used when hashing a plaintext password.
+    pub current_password: Option<&'a str>,
+    /// The current hash if it is replaced instead of re-used (e.g. with `--rehash-all`).
+    ///
+    /// Whether the plaintext password still matches it is checked along with hashing it.
+    pub replaced_password: Option<&'a str>,
+}
+
+/// A password that was resolved ahead of time.
+struct ResolvedPassword {
+    hashed_password: Result<Option<HashedPassword>>,
+    /// Whether the plaintext password matches the replaced hash.
+    unchanged: bool,
+}
+
+/// Hashed passwords of users that were resolved ahead of time, keyed by user name.
+pub struct ResolvedPasswords<'a> {
+    hasher: &'a dyn Hasher,
+    passwords: BTreeMap<String, ResolvedPassword>,
+}
+
+impl Default for ResolvedPasswords<'_> {
+    fn default() -> Self {
+        Self {
+            hasher: &XcryptHasher,
+            passwords: BTreeMap::new(),
+        }
+    }
+}
+
+impl<'a> ResolvedPasswords<'a> {
+    /// Resolve the hashed passwords of multiple users in parallel.
+    ///
+    /// Hashing plaintext passwords is deliberately slow. Doing it one user after another dominates
+    /// the runtime when many users are created at once. The same goes for checking whether a
+    /// plaintext password matches a hash that is replaced.
+    pub fn resolve(hasher: &'a dyn Hasher, users: &[PasswordRequest]) -> Self {
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let chunk_size = users.len().div_ceil(threads).max(1);
+
+        let passwords = thread::scope(|scope| {
+            let handles = users
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|user| {
+                                let hashed_password = HashedPassword::from_config(
+                                    user.config,
+                                    user.current_password,
+                                    user.name,
+                                    hasher,
+                                );
+                                let unchanged = user
+                                    .replaced_password
+                                    .is_some_and(|hash| matches(hasher, user.config, hash));
+                                let resolved = ResolvedPassword {
+                                    hashed_password,
+                                    unchanged,
+                                };
+                                (user.name.to_string(), resolved)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // Release builds abort on panic. Only in builds that unwind (e.g. tests) can a thread
+            // panic without taking down the process. Its passwords are then missing here and are
+            // resolved again when they are taken.
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().ok())
+                .flatten()
+                .collect()
+        });
+
+        Self { hasher, passwords }
+    }
+
+    /// Whether the plaintext password of a user matches the hash that is replaced.
+    ///
+    /// If this wasn't checked ahead of time, it is checked now.
+    pub fn is_unchanged(
+        &self,
+        name: &str,
+        password_config: &config::Password,
+        replaced_password: &str,
+    ) -> bool {
+        self.passwords.get(name).map_or_else(
+            || matches(self.hasher, password_config, replaced_password),
+            |resolved| resolved.unchanged,
+        )
+    }
+
+    /// Take the hashed password of a user.
+    ///
+    /// If it wasn't resolved ahead of time, it is resolved now.
+    pub fn take(
+        &mut self,
+        name: &str,
+        password_config: &config::Password,
+        current_password: Option<&str>,
+    ) -> Result<Option<HashedPassword>> {
+        self.passwords.remove(name).map_or_else(
+            || HashedPassword::from_config(password_config, current_password, name, self.hasher),
+            |resolved| resolved.hashed_password,
+        )
+    }
+}
+
+/// Whether the plaintext password from the config hashes to `hash`.
+fn matches(hasher: &dyn Hasher, password_config: &config::Password, hash: &str) -> bool {
+    password_config.plaintext().is_some_and(|password| {
+        hasher
+            .crypt(password, hash)
+            .is_ok_and(|hashed_password| hashed_password == hash)
+    })
+}
+
+/// Hash the password of a group.
+///
+/// `hashed_password` takes precedence over `password`. Like for users, `current_password` is used
+/// to not change the hash when the password hasn't changed.
+pub fn hash_group_password(
+    group_config: &config::Group,
+    current_password: Option<&str>,
+    hasher: &dyn Hasher,
+) -> Result<Option<String>> {
+    if let Some(hashed_password) = &group_config.hashed_password {
+        Ok(Some(hashed_password.clone()))
+    } else if let Some(raw_password) = &group_config.password {
+        log::warn!(
+            "Group {} uses a plaintext password. This is inscure and should only be used for testing purposes.",
+            group_config.name
+        );
+        Ok(Some(
+            hash_password(hasher, raw_password, current_password)
+                .context("Failed to hash password")?,
+        ))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Resolve the path of a systemd credential.
+///
+/// Credentials are passed to a service via the directory in `$CREDENTIALS_DIRECTORY`. See
+/// `systemd.exec(5)`.
+fn credential_path(credential: &str) -> Result<PathBuf> {
+    let directory = std::env::var_os("CREDENTIALS_DIRECTORY").context(
+        "Cannot resolve credential because the environment variable CREDENTIALS_DIRECTORY is not set",
+    )?;
+    Ok(Path::new(&directory).join(credential))
+}
+
+/// Hash a raw password using a hasher (usually `libxcrypt`).
 ///
 /// Optionally takes `current_password` to not change the hash (by means of a new salt) when the
 /// actual password hasn't changed.
@@ -84,9 +291,13 @@ impl HashedPassword {
 ///
 /// It only serves to convert a non-secret raw password into a format that is understood by
 /// /etc/shadow.
-fn hash_password(new_password: &str, current_password: Option<&str>) -> Result<String> {
+fn hash_password(
+    hasher: &dyn Hasher,
+    new_password: &str,
+    current_password: Option<&str>,
+) -> Result<String> {
     if let Some(current) = current_password {
-        let hashed_password_result = crypt(new_password, current);
+        let hashed_password_result = hasher.crypt(new_password, current);
 
         // If hashing fails (e.g. because the current password is invalid), generate a hash with a
         // new salt.
@@ -97,29 +308,108 @@ fn hash_password(new_password: &str, current_password: Option<&str>) -> Result<S
             }
         }
     }
-    let setting =
-        crypt_gensalt(Some("$y$"), 0, None).context("Failed to generate setting for crypt")?;
-    Ok(crypt(new_password, &setting)?)
+    let setting = hasher.gensalt()?;
+    Ok(hasher.crypt(new_password, &setting)?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::sync::atomic::{AtomicU32, Ordering};
+
     use anyhow::bail;
 
+    use crate::fs::TestDir;
+
+    /// A deterministic hasher that produces hashes like `$fake$salt0$olleh`.
+    #[derive(Default)]
+    struct FakeHasher {
+        salts: AtomicU32,
+    }
+
+    impl Hasher for FakeHasher {
+        fn crypt(&self, password: &str, setting: &str) -> Result<String, Error> {
+            let mut components = setting.split('$');
+            let (Some(""), Some("fake"), Some(salt)) =
+                (components.next(), components.next(), components.next())
+            else {
+                return Err(Error::Hash {
+                    source: format!("Invalid setting {setting}").into(),
+                });
+            };
+            let hash = password.chars().rev().collect::<String>();
+            Ok(format!("$fake${salt}${hash}"))
+        }
+
+        fn gensalt(&self) -> Result<String, Error> {
+            let salt = self.salts.fetch_add(1, Ordering::Relaxed);
+            Ok(format!("$fake$salt{salt}"))
+        }
+    }
+
+    #[test]
+    fn hash_with_fake_hasher() -> Result<()> {
+        let hasher = FakeHasher::default();
+
+        assert_eq!(hash_password(&hasher, "hello", None)?, "$fake$salt0$olleh");
+        // The salt is kept if the password hasn't changed.
+        assert_eq!(
+            hash_password(&hasher, "hello", Some("$fake$salt0$olleh"))?,
+            "$fake$salt0$olleh"
+        );
+        assert_eq!(
+            hash_password(&hasher, "mellow", Some("$fake$salt0$olleh"))?,
+            "$fake$salt1$wollem"
+        );
+        // Invalid current passwords are ignored.
+        assert_eq!(
+            hash_password(&hasher, "hello", Some("!*"))?,
+            "$fake$salt2$olleh"
+        );
+
+        let config = config::Password {
+            password: None,
+            hashed_password: None,
+            hashed_password_file: None,
+            hashed_password_credential: None,
+            initial_password: Some("hello".into()),
+            initial_hashed_password: None,
+        };
+        let hasher = FakeHasher::default();
+        let mut passwords = ResolvedPasswords::resolve(
+            &hasher,
+            &[PasswordRequest {
+                name: "normalo",
+                config: &config,
+                current_password: None,
+                replaced_password: None,
+            }],
+        );
+        let Some(HashedPassword::Initial(hashed_password)) =
+            passwords.take("normalo", &config, None)?
+        else {
+            bail!("Wrong HashedPassword variant");
+        };
+        assert_eq!(hashed_password, "$fake$salt0$olleh");
+
+        Ok(())
+    }
+
     #[test]
     fn hash_password_from_config_correctly() -> Result<()> {
         let config = config::Password {
             password: Some("hello".into()),
             hashed_password: None,
             hashed_password_file: None,
+            hashed_password_credential: None,
             initial_password: Some("mellow".into()),
             initial_hashed_password: None,
         };
 
-        let hashed_password = HashedPassword::from_config(&config, None, "test-name")?
-            .context("Failed to convert config to HashedPassword")?;
+        let hashed_password =
+            HashedPassword::from_config(&config, None, "test-name", &XcryptHasher)?
+                .context("Failed to convert config to HashedPassword")?;
 
         if let HashedPassword::Override(s) = hashed_password {
             assert!(s.starts_with("$y$"));
@@ -137,7 +427,7 @@ mod tests {
         let current_password =
             "$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1";
 
-        let hashed_password = hash_password(password, Some(current_password))?;
+        let hashed_password = hash_password(&XcryptHasher, password, Some(current_password))?;
 
         assert_eq!(hashed_password, current_password);
 
@@ -151,7 +441,7 @@ mod tests {
         let current_password =
             "$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1";
 
-        let hashed_password = hash_password(password, Some(current_password))?;
+        let hashed_password = hash_password(&XcryptHasher, password, Some(current_password))?;
 
         // Assert that the salt has changed
         let new_password_components = hashed_password.split('$').nth(3);
@@ -170,11 +460,137 @@ mod tests {
 
         let current_password = "!*";
 
-        let hashed_password = hash_password(password, Some(current_password))?;
+        let hashed_password = hash_password(&XcryptHasher, password, Some(current_password))?;
 
         assert_ne!(hashed_password, current_password);
         assert!(hashed_password.starts_with('$'));
 
         Ok(())
     }
+
+    #[test]
+    fn hashed_password_from_credential() -> Result<()> {
+        let test_dir = TestDir::new("credentials")?;
+        let directory = test_dir.path();
+        fs::write(
+            directory.join("root-hash"),
+            "$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1\n",
+        )?;
+        std::env::set_var("CREDENTIALS_DIRECTORY", directory);
+
+        let config = config::Password {
+            password: None,
+            hashed_password: Some("$y$j9T$ignored".into()),
+            hashed_password_file: None,
+            hashed_password_credential: Some("root-hash".into()),
+            initial_password: None,
+            initial_hashed_password: None,
+        };
+
+        let hashed_password = HashedPassword::from_config(&config, None, "root", &XcryptHasher)?
+            .context("Failed to convert config to HashedPassword")?;
+
+        if let HashedPassword::Override(s) = hashed_password {
+            assert_eq!(
+                s,
+                "$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1"
+            );
+        } else {
+            bail!("Wrong HashedPassword variant")
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_passwords_in_parallel() -> Result<()> {
+        let current_password =
+            "$y$j9T$qPA34Fz5ALUVSUMv1Ihat.$5mK2beqNNh5QhircGqGFJJZwA9H.vi8vV7E3Mt4oug1";
+
+        let password_config =
+            |password: String, hashed_password_file: Option<String>| config::Password {
+                password: Some(password),
+                hashed_password: None,
+                hashed_password_file,
+                hashed_password_credential: None,
+                initial_password: None,
+                initial_hashed_password: None,
+            };
+
+        let configs = (0..8)
+            .map(|i| {
+                password_config(
+                    format!("password{i}"),
+                    (i == 7).then(|| "/does/not/exist".into()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let names = (0..8).map(|i| format!("user{i}")).collect::<Vec<_>>();
+        let unchanged_config = password_config("hello".into(), None);
+
+        let mut users = names
+            .iter()
+            .zip(&configs)
+            .map(|(name, config)| PasswordRequest {
+                name,
+                config,
+                current_password: None,
+                replaced_password: None,
+            })
+            .collect::<Vec<_>>();
+        users.push(PasswordRequest {
+            name: "unchanged",
+            config: &unchanged_config,
+            current_password: Some(current_password),
+            replaced_password: None,
+        });
+        // The hash is replaced but the password matches it.
+        users.push(PasswordRequest {
+            name: "rotated",
+            config: &unchanged_config,
+            current_password: None,
+            replaced_password: Some(current_password),
+        });
+        users.push(PasswordRequest {
+            name: "changed",
+            config: &configs[0],
+            current_password: None,
+            replaced_password: Some(current_password),
+        });
+
+        let mut passwords = ResolvedPasswords::resolve(&XcryptHasher, &users);
+
+        for (i, (name, config)) in names.iter().zip(&configs).enumerate().take(7) {
+            let Some(HashedPassword::Override(hashed_password)) =
+                passwords.take(name, config, None)?
+            else {
+                bail!("Wrong HashedPassword variant");
+            };
+            assert_eq!(
+                crypt(&format!("password{i}"), &hashed_password)?,
+                hashed_password
+            );
+        }
+
+        // The error of a single user is kept for that user.
+        assert!(passwords.take("user7", &configs[7], None).is_err());
+
+        // Passwords that haven't changed keep their hash.
+        let Some(HashedPassword::Override(hashed_password)) =
+            passwords.take("unchanged", &unchanged_config, Some(current_password))?
+        else {
+            bail!("Wrong HashedPassword variant");
+        };
+        assert_eq!(hashed_password, current_password);
+
+        assert!(passwords.is_unchanged("rotated", &unchanged_config, current_password));
+        assert!(!passwords.is_unchanged("changed", &configs[0], current_password));
+        // Passwords that weren't checked ahead of time are checked now.
+        assert!(passwords.is_unchanged("unresolved", &unchanged_config, current_password));
+
+        // Passwords that weren't resolved ahead of time are resolved when they are taken.
+        assert!(passwords.take("user0", &configs[0], None)?.is_some());
+
+        Ok(())
+    }
 }
@@ -1,11 +1,56 @@
-use std::{collections::BTreeMap, fs, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{
+    config::{LockMode, PasswordPolicy},
+    error::Error,
+    fs::write_if_changed,
+    passwd::Passwd,
+};
 
-use anyhow::{bail, Context, Result};
+/// A locked and invalid password.
+pub(crate) const PASSWORD_LOCKED_AND_INVALID: &str = "!*";
+/// The prefix of the password of a locked account.
+const LOCK_PREFIX: char = '!';
+/// The account expiration date of an account locked via `LockMode::Expire`.
+///
+/// This is the second day after the epoch because `shadow(5)` discourages `0`, which some tools
+/// interpret as "never expires".
+const ACCOUNT_EXPIRED: &str = "1";
 
-use crate::{fs::atomic_write, passwd::Passwd};
+/// Today as the number of days since the epoch like in the date fields of /etc/shadow.
+///
+/// `SOURCE_DATE_EPOCH` is used instead of the current time if it is set so that images can be built
+/// reproducibly. This is never `0` because that forces the user to change their password.
+fn today() -> u64 {
+    let seconds = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default()
+        });
+    (seconds / (24 * 60 * 60)).max(1)
+}
 
-/// A locked and invalid password.
-const PASSWORD_LOCKED_AND_INVALID: &str = "!*";
+/// Mode of a newly created /etc/shadow unless another one is provided.
+///
+/// Nobody can read the file without `CAP_DAC_OVERRIDE`, i.e. only root.
+pub const DEFAULT_MODE: u32 = 0o000;
+
+/// Most permissive mode of /etc/shadow.
+///
+/// Permissions beyond this (e.g. from a bad backup) are removed when the file is written.
+pub const MAX_MODE: u32 = 0o640;
 
 #[derive(Clone)]
 pub struct Entry {
@@ -40,20 +85,184 @@ impl Entry {
     }
 
     /// Update an /etc/shadow entry.
-    pub fn update(&mut self, password: Option<String>) {
+    ///
+    /// The date of the last password change is only moved to today if the password has changed.
+    /// Otherwise, it is preserved so that the aging state (e.g. set via `chage(1)`) survives.
+    ///
+    /// Returns whether the password has changed.
+    pub fn update(&mut self, password: Option<String>) -> bool {
         if let Some(password) = password {
             if self.password != password {
                 log::info!("Updating password of user {}...", self.name,);
                 self.password = password;
+                self.bump_last_password_change(today());
+                return true;
             };
         };
+        false
+    }
+
+    /// Replace the hash of an unchanged password, e.g. with one with a new salt.
+    ///
+    /// Unlike [`Self::update`], this keeps the date of the last password change because the
+    /// password itself stays the same.
+    ///
+    /// Returns whether the hash has changed.
+    pub fn rotate_hash(&mut self, password: String) -> bool {
+        if self.password == password {
+            return false;
+        }
+        self.password = password;
+        true
+    }
+
+    /// Set the date of the last password change to today.
+    ///
+    /// This is used for new entries so that a password policy only starts aging the password from
+    /// the day the account is created.
+    pub fn set_last_password_change_today(&mut self) {
+        self.bump_last_password_change(today());
+    }
+
+    /// Set the date of the last password change to `today`.
+    ///
+    /// The date never moves backwards, e.g. because the clock is not set yet early during boot.
+    fn bump_last_password_change(&mut self, today: u64) {
+        if self
+            .last_password_change
+            .parse::<u64>()
+            .is_ok_and(|last_password_change| last_password_change >= today)
+        {
+            return;
+        }
+        self.last_password_change = today.to_string();
+    }
+
+    /// Expire the password so that the user has to change it at their next login.
+    pub fn expire_password(&mut self) {
+        self.last_password_change = "0".into();
+    }
+
+    /// Set the reserved field of a new /etc/shadow entry.
+    ///
+    /// Fails if the value would corrupt the entry.
+    pub fn set_reserved(&mut self, reserved: String) -> Result<(), Error> {
+        if let Some(character) = reserved.chars().find(|c| *c == ':' || c.is_control()) {
+            return Err(Error::InvalidCharacter {
+                field: "Reserved field",
+                character,
+            });
+        }
+        self.reserved = reserved;
+        Ok(())
+    }
+
+    /// Set the aging fields of a new /etc/shadow entry from a password policy.
+    pub fn set_password_policy(&mut self, policy: &PasswordPolicy) {
+        for (field, value) in self.aging_fields(policy) {
+            *field = value;
+        }
+    }
+
+    /// Update the aging fields of an /etc/shadow entry from a password policy.
+    pub fn update_password_policy(&mut self, policy: &PasswordPolicy) {
+        let name = self.name.clone();
+        for (field, value) in self.aging_fields(policy) {
+            if *field != value {
+                log::info!("Updating password aging of user {name} from {field:?} to {value:?}...");
+                *field = value;
+            }
+        }
+    }
+
+    /// Clear all aging fields of an /etc/shadow entry so that the password never ages.
+    pub fn disable_password_aging(&mut self) {
+        let name = self.name.clone();
+        for field in [
+            &mut self.minimum_password_age,
+            &mut self.maximum_password_age,
+            &mut self.password_warning_period,
+            &mut self.password_inactivity_period,
+        ] {
+            if !field.is_empty() {
+                log::info!("Disabling password aging of user {name} (was {field:?})...");
+                field.clear();
+            }
+        }
     }
 
-    /// Lock the account by resetting its password.
+    /// The aging fields of the entry paired with their value from the policy.
+    ///
+    /// Fields that are not set in the policy are skipped.
+    fn aging_fields(&mut self, policy: &PasswordPolicy) -> Vec<(&mut String, String)> {
+        [
+            (&mut self.minimum_password_age, policy.min_days),
+            (&mut self.maximum_password_age, policy.max_days),
+            (&mut self.password_warning_period, policy.warn_days),
+            (&mut self.password_inactivity_period, policy.inactive_days),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value?.to_string())))
+        .collect()
+    }
+
+    /// Lock the account.
     ///
     /// After locking, a user will not be able to login with a unix password anymore.
-    pub fn lock_account(&mut self) {
-        self.password = PASSWORD_LOCKED_AND_INVALID.into();
+    ///
+    /// With `LockMode::Replace`, the password is reset. With `LockMode::Prefix`, the password is
+    /// prefixed with `!` so that it can be restored via `unlock_account()`. With
+    /// `LockMode::Expire`, the account is expired via `expire_account()` instead.
+    pub fn lock_account(&mut self, mode: LockMode) {
+        match mode {
+            LockMode::Replace => self.password = PASSWORD_LOCKED_AND_INVALID.into(),
+            LockMode::Prefix => {
+                if !self.is_locked() {
+                    self.password.insert(0, LOCK_PREFIX);
+                }
+            }
+            LockMode::Expire => self.expire_account(),
+        }
+    }
+
+    /// Expire the account by setting its expiration date to the past.
+    ///
+    /// The password is kept, so the account can be restored via `unexpire_account()`.
+    pub fn expire_account(&mut self) {
+        self.account_expiration_date = ACCOUNT_EXPIRED.into();
+    }
+
+    /// Clear the expiration date of an account that was expired via `expire_account()`.
+    ///
+    /// Other expiration dates are kept because they weren't set by Userborn.
+    ///
+    /// Returns whether the account was unexpired.
+    pub fn unexpire_account(&mut self) -> bool {
+        if self.account_expiration_date != ACCOUNT_EXPIRED {
+            return false;
+        }
+        self.account_expiration_date.clear();
+        true
+    }
+
+    /// Unlock an account that was locked via `LockMode::Prefix`.
+    ///
+    /// Accounts whose password was reset stay locked because there is no password to restore.
+    ///
+    /// Returns whether the account was unlocked.
+    pub fn unlock_account(&mut self) -> bool {
+        match self.password.strip_prefix(LOCK_PREFIX) {
+            Some(password) if !password.is_empty() && password != "*" => {
+                self.password = password.into();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the account is locked, either via its password or via `expire_account()`.
+    pub fn is_locked(&self) -> bool {
+        self.password.starts_with(LOCK_PREFIX) || self.account_expiration_date == ACCOUNT_EXPIRED
     }
 
     /// Read an entry from a single line from /etc/shadow.
@@ -63,6 +272,8 @@ impl Entry {
         if line.starts_with('#') {
             return None;
         }
+        // Only strip a trailing carriage return. Other whitespace may be part of the last field.
+        let line = line.strip_suffix('\r').unwrap_or(line);
         let mut fields = line.splitn(9, ':');
         Some(Self {
             name: fields.next()?.into(),
@@ -92,8 +303,9 @@ impl Entry {
         .join(":")
     }
 
-    pub fn uses_secure_hash(&self) -> bool {
-        password_hash_is_secure(&self.password)
+    /// Whether the password is hashed with one of the `secure_schemes`.
+    pub fn uses_secure_hash(&self, secure_schemes: &[String]) -> bool {
+        password_hash_is_secure(&self.password, secure_schemes)
     }
 
     pub fn name(&self) -> &str {
@@ -105,22 +317,29 @@ impl Entry {
     }
 }
 
+/// The shadow database, i.e. /etc/shadow.
+///
+/// The entries are written in the order of the passwd database. Orphaned entries (i.e.
+/// entries of users not in the passwd database) are appended sorted by name. Thus, the output only
+/// depends on the entries and never on the order of the lines in the file that was read.
 #[derive(Default)]
 pub struct Shadow(BTreeMap<String, Entry>);
 
 impl Shadow {
-    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        let file = fs::read_to_string(path.as_ref())
-            .with_context(|| format!("Failed to read {:?}.", path.as_ref()))?;
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = fs::read_to_string(path.as_ref()).map_err(|source| Error::ReadDatabase {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
 
         Ok(Self::from_buffer(&file))
     }
 
-    fn from_buffer(s: &str) -> Self {
+    pub fn from_buffer(s: &str) -> Self {
         let mut entries = BTreeMap::new();
         for line in s.lines() {
             if let Some(e) = Entry::from_line(line) {
-                entries.insert(e.name.clone(), e.clone());
+                entries.insert(e.name.clone(), e);
             } else {
                 log::warn!("Skipping shadow line because it cannot be parsed: {line}.");
             }
@@ -130,29 +349,57 @@ impl Shadow {
 
     /// Write the shadow database to a file.
     ///
-    /// Sort the entries by their UIDs in the passwd database.
-    pub fn to_file_sorted(&self, passwd: &Passwd, path: impl AsRef<Path>) -> Result<()> {
-        atomic_write(path, self.to_buffer_sorted(passwd), 0o000)
+    /// Sort the entries like the passwd database and append orphaned entries sorted by
+    /// name. `mode` is only used if the file doesn't exist yet. Returns whether the file has
+    /// changed.
+    pub fn to_file_sorted(
+        &self,
+        passwd: &Passwd,
+        path: impl AsRef<Path>,
+        mode: u32,
+    ) -> Result<bool, Error> {
+        write_if_changed(path, mode, |writer| self.to_writer_sorted(passwd, writer))
+    }
+
+    /// Write the shadow database line by line into a writer.
+    ///
+    /// Sort the entries like the passwd database.
+    pub fn to_writer_sorted(&self, passwd: &Passwd, mut writer: impl Write) -> io::Result<()> {
+        for line in self.lines_sorted(passwd) {
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
     }
 
     /// Write the shadow database to a string buffer.
     ///
-    /// Sort the entries by their UIDs in the passwd database.
+    /// Sort the entries like the passwd database.
     pub fn to_buffer_sorted(&self, passwd: &Passwd) -> String {
-        let passwd_entries = passwd.entries();
-        let mut s = String::new();
-
-        for passwd_entry in passwd_entries {
-            let name = passwd_entry.name();
-            if let Some(shadow_entry) = self.get(name) {
-                s.push_str(&shadow_entry.to_line());
-                s.push('\n');
-            } else {
-                // This should only happen if the DB was somehow manually tampered with.
-                log::warn!("Passwd DB contains entry for {name} that is not in Shadow DB");
-            };
-        }
-        s
+        self.lines_sorted(passwd).map(|line| line + "\n").collect()
+    }
+
+    /// Lines in the same order as the passwd database.
+    ///
+    /// Entries that are not in the passwd database are kept and appended sorted by name.
+    fn lines_sorted<'a>(&'a self, passwd: &'a Passwd) -> impl Iterator<Item = String> + 'a {
+        let sorted = passwd
+            .sorted_entries()
+            .into_iter()
+            .filter_map(|passwd_entry| {
+                let name = passwd_entry.name();
+                let shadow_entry = self.get(name);
+                if shadow_entry.is_none() {
+                    // This should only happen if the DB was somehow manually tampered with.
+                    log::warn!("Passwd DB contains entry for {name} that is not in Shadow DB");
+                }
+                shadow_entry.map(Entry::to_line)
+            });
+        let orphans = self
+            .0
+            .values()
+            .filter(|entry| !passwd.is_name_taken(&entry.name))
+            .map(Entry::to_line);
+        sorted.chain(orphans)
     }
 
     pub fn get(&self, name: &str) -> Option<&Entry> {
@@ -163,16 +410,52 @@ impl Shadow {
         self.0.get_mut(name)
     }
 
-    pub fn insert(&mut self, entry: &Entry) -> Result<()> {
+    /// Remove the entry of a user.
+    pub fn remove(&mut self, name: &str) -> Option<Entry> {
+        self.0.remove(name)
+    }
+
+    pub fn insert(&mut self, entry: Entry) -> Result<(), Error> {
         if self.0.contains_key(&entry.name) {
-            bail!("User {} already exists in shadow database", entry.name);
+            return Err(Error::NameTaken {
+                database: "shadow",
+                name: entry.name,
+            });
         }
 
-        self.0.entry(entry.name.clone()).or_insert(entry.clone());
+        self.0.insert(entry.name.clone(), entry);
+
+        Ok(())
+    }
 
+    /// Rename a user, keeping its password.
+    ///
+    /// This will fail if a user with the new name already exists.
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<(), Error> {
+        if self.0.contains_key(new_name) {
+            return Err(Error::NameTaken {
+                database: "shadow",
+                name: new_name.into(),
+            });
+        }
+        let mut entry = self.0.remove(name).ok_or_else(|| Error::NoSuchEntry {
+            database: "shadow",
+            name: name.into(),
+        })?;
+        entry.name = new_name.into();
+        self.0.insert(entry.name.clone(), entry);
         Ok(())
     }
 
+    /// Find users that are in the shadow database but not in the passwd database.
+    pub fn find_orphans(&self, passwd: &Passwd) -> Vec<String> {
+        self.0
+            .keys()
+            .filter(|name| !passwd.is_name_taken(name))
+            .cloned()
+            .collect()
+    }
+
     pub fn entries(&self) -> impl IntoIterator<Item = &Entry> {
         self.0.values()
     }
@@ -180,22 +463,35 @@ impl Shadow {
     pub fn entries_mut(&mut self) -> impl IntoIterator<Item = &mut Entry> {
         self.0.values_mut()
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
-/// Determine whether a hashing scheme used in a password is secure.
-///
-/// Hashing schemes are defined in `crypt(5)`.
+/// The prefixes of the hashing schemes that are deemed secure by default.
 ///
-/// Currently deemed secure schemes:
+/// Hashing schemes are defined in `crypt(5)`:
 ///
 /// - yescrypt ("y")
 /// - gost-yescrypt ("gy")
 /// - scrypt ("7")
 /// - bcrypt ("2b")
+pub fn default_secure_hash_schemes() -> Vec<String> {
+    ["y", "gy", "7", "2b"].map(String::from).to_vec()
+}
+
+/// Determine whether the hashing scheme used in a password is one of the `secure_schemes`.
+///
+/// The schemes are identified by their prefix, e.g. "y" for yescrypt.
 ///
 /// If the passed `password` is not a result of crypt(3), i.e. doens't start with `$`, it is deemed
 /// "secure".
-fn password_hash_is_secure(password: &str) -> bool {
+pub(crate) fn password_hash_is_secure(password: &str, secure_schemes: &[String]) -> bool {
     // If it's not a hashed password, it is secure.
     if !password.starts_with('$') {
         return true;
@@ -203,7 +499,7 @@ fn password_hash_is_secure(password: &str) -> bool {
     let mut split = password.split('$');
     split.next();
     if let Some(prefix) = split.next() {
-        return matches!(prefix, "y" | "gy" | "7" | "2b");
+        return secure_schemes.iter().any(|scheme| scheme == prefix);
     }
     false
 }
@@ -212,9 +508,74 @@ fn password_hash_is_secure(password: &str) -> bool {
 mod tests {
     use super::*;
 
+    use anyhow::Context;
     use expect_test::expect;
     use indoc::indoc;
 
+    use crate::config::SortOrder;
+
+    #[test]
+    fn lock_and_unlock() {
+        let hashed_password =
+            "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4";
+
+        let mut entry = Entry::new("normalo".into(), Some(hashed_password.into()));
+        entry.lock_account(LockMode::Prefix);
+        entry.lock_account(LockMode::Prefix);
+        assert!(entry.is_locked());
+        assert_eq!(entry.password(), format!("!{hashed_password}"));
+        assert!(entry.unlock_account());
+        assert!(!entry.is_locked());
+        assert_eq!(entry.password(), hashed_password);
+        assert!(!entry.unlock_account());
+
+        entry.lock_account(LockMode::Replace);
+        assert!(entry.is_locked());
+        assert_eq!(entry.password(), "!*");
+        assert!(!entry.unlock_account());
+        assert!(entry.is_locked());
+    }
+
+    #[test]
+    fn last_password_change_never_moves_backwards() {
+        let mut entry = Entry::new("normalo".into(), None);
+        entry.last_password_change = "19911".into();
+
+        entry.bump_last_password_change(19000);
+        assert_eq!(entry.last_password_change, "19911");
+        entry.bump_last_password_change(20000);
+        assert_eq!(entry.last_password_change, "20000");
+
+        // An expired password (`0`) is replaced as well.
+        entry.expire_password();
+        entry.bump_last_password_change(20001);
+        assert_eq!(entry.last_password_change, "20001");
+    }
+
+    #[test]
+    fn expire_and_unexpire() -> Result<()> {
+        let hashed_password =
+            "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4";
+
+        let mut entry = Entry::new("normalo".into(), Some(hashed_password.into()));
+        entry.lock_account(LockMode::Expire);
+        assert!(entry.is_locked());
+        assert_eq!(entry.password(), hashed_password);
+        assert_eq!(
+            entry.to_line(),
+            format!("normalo:{hashed_password}:1:::::1:")
+        );
+        assert!(entry.unexpire_account());
+        assert!(!entry.is_locked());
+        assert!(!entry.unexpire_account());
+
+        // Expiration dates that weren't set by Userborn are kept.
+        let mut entry = Entry::from_line("normalo:!:1::::::20000:").context("Invalid line")?;
+        assert!(!entry.unexpire_account());
+        assert_eq!(entry.to_line(), "normalo:!:1::::::20000:");
+        Ok(())
+    }
+
     #[test]
     fn sort() {
         let passwd_buffer = indoc! {"
@@ -243,6 +604,96 @@ mod tests {
         expected.assert_eq(&recreated_buffer);
     }
 
+    #[test]
+    fn sort_by_name_like_passwd() {
+        let mut passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0:::
+            nixbld5:x:5:5:::
+            gary:x:1000:1000:::
+        "});
+        passwd.set_sort_order(SortOrder::ByName);
+
+        let shadow = Shadow::from_buffer(indoc! {"
+            root:!:1::::::
+            nixbld5:!:1::::::
+            gary:!:1::::::
+            orphan:!:1::::::
+            aaron:!:1::::::
+        "});
+
+        // Orphans are still appended after the entries of the passwd database.
+        let expected = expect![[r#"
+            gary:!:1::::::
+            nixbld5:!:1::::::
+            root:!:1::::::
+            aaron:!:1::::::
+            orphan:!:1::::::
+        "#]];
+        expected.assert_eq(&shadow.to_buffer_sorted(&passwd));
+    }
+
+    #[test]
+    fn keep_orphans() {
+        let passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0:::
+        "});
+
+        let buffer = indoc! {"
+            zed:!:1::::::
+            root:!*:1::::::
+            alice:*:16034:0:99999:7:::
+        "};
+        let shadow = Shadow::from_buffer(buffer);
+
+        let expected = expect![[r#"
+            root:!*:1::::::
+            alice:*:16034:0:99999:7:::
+            zed:!:1::::::
+        "#]];
+        expected.assert_eq(&shadow.to_buffer_sorted(&passwd));
+    }
+
+    #[test]
+    fn stable_order_of_orphans() {
+        let passwd = Passwd::from_buffer(indoc! {"
+            root:x:0:0:::
+            normalo:x:1000:1000:::
+        "});
+
+        let shadow = Shadow::from_buffer(indoc! {"
+            mallory:!:1::::::
+            normalo:!:1::::::
+            bob:!:1::::::
+            root:!*:1::::::
+            eve:!:1::::::
+        "});
+        let reordered = Shadow::from_buffer(indoc! {"
+            eve:!:1::::::
+            root:!*:1::::::
+            bob:!:1::::::
+            mallory:!:1::::::
+            normalo:!:1::::::
+        "});
+
+        let expected = expect![[r#"
+            root:!*:1::::::
+            normalo:!:1::::::
+            bob:!:1::::::
+            eve:!:1::::::
+            mallory:!:1::::::
+        "#]];
+        let buffer = shadow.to_buffer_sorted(&passwd);
+        expected.assert_eq(&buffer);
+
+        // The output doesn't depend on the order of the input.
+        assert_eq!(reordered.to_buffer_sorted(&passwd), buffer);
+        // Reading the output again doesn't change it.
+        assert_eq!(
+            Shadow::from_buffer(&buffer).to_buffer_sorted(&passwd),
+            buffer
+        );
+    }
+
     #[test]
     fn skip_comments_and_broken_lines() {
         let passwd_buffer = indoc! {"
@@ -264,6 +715,24 @@ mod tests {
         expected.assert_eq(&recreated_buffer);
     }
 
+    #[test]
+    fn find_orphans() {
+        let passwd_buffer = indoc! {"
+            root:x:0:0:::
+            gary:x:1000:1000:::
+        "};
+        let passwd = Passwd::from_buffer(passwd_buffer);
+
+        let buffer = indoc! {"
+            root:!:1::::::
+            nixbld5:!:1::::::
+        "};
+        let shadow = Shadow::from_buffer(buffer);
+
+        assert_eq!(shadow.find_orphans(&passwd), vec!["nixbld5".to_string()]);
+        assert_eq!(passwd.find_orphans(&shadow), vec!["gary".to_string()]);
+    }
+
     #[test]
     fn identify_secure_hashes() {
         let hashes = [
@@ -279,7 +748,35 @@ mod tests {
         ];
 
         for (hash, expected) in hashes {
-            assert_eq!(password_hash_is_secure(hash), expected);
+            assert_eq!(
+                password_hash_is_secure(hash, &default_secure_hash_schemes()),
+                expected
+            );
         }
+
+        let only_yescrypt = ["y".to_string()];
+        assert!(password_hash_is_secure(hashes[0].0, &only_yescrypt));
+        assert!(!password_hash_is_secure(hashes[3].0, &only_yescrypt));
+        assert!(!password_hash_is_secure(hashes[4].0, &[]));
+        assert!(password_hash_is_secure("!", &[]));
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let passwd = Passwd::from_buffer("root:x:0:0:::\ngary:x:1000:1000:::\n");
+
+        let buffer = "root:!:1::::::\r\ngary:*:16034:0:99999:7:::\r";
+        let shadow = Shadow::from_buffer(buffer);
+        let recreated_buffer = shadow.to_buffer_sorted(&passwd);
+
+        let expected = expect![[r"
+            root:!:1::::::
+            gary:*:16034:0:99999:7:::
+        "]];
+        expected.assert_eq(&recreated_buffer);
+        assert_eq!(
+            Shadow::from_buffer(&recreated_buffer).to_buffer_sorted(&passwd),
+            recreated_buffer
+        );
     }
 }
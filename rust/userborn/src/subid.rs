@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    io::{self, Write},
+    ops::Range,
+    path::Path,
+};
+
+use anyhow::Result;
+
+use crate::{error::Error, fs::write_if_changed};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    name: String,
+    start: u32,
+    count: u32,
+}
+
+impl Entry {
+    /// Create a new /etc/subuid or /etc/subgid entry.
+    pub fn new(name: String, start: u32, count: u32) -> Self {
+        Self { name, start, count }
+    }
+
+    /// Read an entry from a single line from /etc/subuid or /etc/subgid.
+    ///
+    /// Whenever a field in this line doesn't exist or cannot be parsed, returns `None`.
+    fn from_line(line: &str) -> Option<Self> {
+        if line.starts_with('#') {
+            return None;
+        }
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let mut fields = line.splitn(3, ':');
+        Some(Self {
+            name: fields.next()?.into(),
+            start: fields.next()?.parse().ok()?,
+            count: fields.next()?.parse().ok()?,
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!("{}:{}:{}", self.name, self.start, self.count)
+    }
+
+    /// Describe the entry in a human readable way.
+    pub fn describe(&self) -> String {
+        format!("{} with range {}..{}", self.name, self.start, self.end())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The IDs covered by this entry.
+    pub fn range(&self) -> Range<u32> {
+        self.start..self.end()
+    }
+
+    fn end(&self) -> u32 {
+        self.start.saturating_add(self.count)
+    }
+}
+
+/// The database of subordinate IDs, i.e. /etc/subuid or /etc/subgid.
+///
+/// A user can have multiple ranges, so the entries are kept in the order of the file.
+#[derive(Default)]
+pub struct SubId {
+    entries: Vec<Entry>,
+}
+
+impl SubId {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = fs::read_to_string(path.as_ref()).map_err(|source| Error::ReadDatabase {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })?;
+
+        Ok(Self::from_buffer(&file))
+    }
+
+    pub fn from_buffer(s: &str) -> Self {
+        let mut entries = Vec::new();
+        for line in s.lines() {
+            if let Some(e) = Entry::from_line(line) {
+                entries.push(e);
+            } else {
+                log::warn!("Skipping subordinate ID line because it cannot be parsed: {line}.");
+            }
+        }
+        Self { entries }
+    }
+
+    /// Write the database to a file.
+    ///
+    /// Returns whether the file has changed.
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<bool, Error> {
+        write_if_changed(path, 0o644, |writer| self.to_writer(writer))
+    }
+
+    /// Write the database line by line into a writer.
+    pub fn to_writer(&self, mut writer: impl Write) -> io::Result<()> {
+        for entry in &self.entries {
+            writeln!(writer, "{}", entry.to_line())?;
+        }
+        Ok(())
+    }
+
+    pub fn to_buffer(&self) -> String {
+        self.entries
+            .iter()
+            .map(|entry| entry.to_line() + "\n")
+            .collect()
+    }
+
+    /// All entries of a user.
+    pub fn get(&self, name: &str) -> Vec<&Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.name == name)
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str, start: u32, count: u32) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.name == name && entry.start == start && entry.count == count)
+    }
+
+    /// Append an entry.
+    ///
+    /// Existing entries are never modified so that a range is never reassigned.
+    pub fn insert(&mut self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    /// The ranges of all entries.
+    pub fn ranges(&self) -> Vec<Range<u32>> {
+        self.entries.iter().map(Entry::range).collect()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use expect_test::expect;
+    use indoc::indoc;
+
+    #[test]
+    fn keep_order_and_multiple_ranges() {
+        let buffer = indoc! {"
+            normalo:100000:65536
+            # Comment
+            broken:100000
+            admin:165536:65536
+            normalo:300000:1000
+        "};
+        let subid = SubId::from_buffer(buffer);
+
+        assert_eq!(subid.get("normalo").len(), 2);
+        assert!(subid.contains("admin", 165_536, 65536));
+        assert_eq!(
+            subid.ranges(),
+            vec![100_000..165_536, 165_536..231_072, 300_000..301_000]
+        );
+
+        let expected = expect![[r"
+            normalo:100000:65536
+            admin:165536:65536
+            normalo:300000:1000
+        "]];
+        expected.assert_eq(&subid.to_buffer());
+    }
+}
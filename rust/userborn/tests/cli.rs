@@ -0,0 +1,412 @@
+//! Run the userborn binary end-to-end against the password databases in a temporary directory.
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use expect_test::expect;
+
+const NO_LOGIN_PATH: &str = "/run/current-system/sw/bin/nologin";
+
+/// A temporary directory containing the password databases.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Result<Self> {
+        let path =
+            std::env::temp_dir().join(format!("userborn-test-{name}-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Write a config and apply it via the binary.
+    ///
+    /// Returns the exit code.
+    fn apply(&self, config: &serde_json::Value) -> Result<i32> {
+        self.apply_with_args(config, &[])
+    }
+
+    /// Like [`Self::apply`] but passes additional arguments to the binary.
+    fn apply_with_args(&self, config: &serde_json::Value, args: &[&str]) -> Result<i32> {
+        let config_path = self.0.join("config.json");
+        fs::write(&config_path, serde_json::to_string(config)?)?;
+
+        let status = Command::new(env!("CARGO_BIN_EXE_userborn"))
+            .arg("--detailed-exit-code")
+            .args(args)
+            .arg(&config_path)
+            .arg(&self.0)
+            .env("USERBORN_NO_LOGIN_PATH", NO_LOGIN_PATH)
+            .status()
+            .context("Failed to run userborn")?;
+
+        // The secret databases are written without any permissions. Make them readable for the
+        // owner so that the tests also work without root.
+        for name in ["shadow", "gshadow"] {
+            let path = self.0.join(name);
+            if path.exists() {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+
+        status.code().context("userborn was terminated by a signal")
+    }
+
+    fn read(&self, name: &str) -> Result<String> {
+        let path = self.0.join(name);
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+#[allow(clippy::too_many_lines)]
+fn generations() -> Result<()> {
+    let dir = TestDir::new("generations")?;
+
+    // GEN 0: Create users and groups.
+
+    let gen0 = serde_json::json!({
+        "users": [
+            { "name": "root", "uid": 0 },
+            {
+                "isNormal": true,
+                "name": "normalo",
+                "home": "/home/normalo",
+                "shell": "/bin/bash",
+                "hashedPassword": "$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4", // "hello"
+            },
+            {
+                "name": "stale",
+                "initialHashedPassword": "$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2",
+            },
+        ],
+        "groups": [
+            { "name": "wheel", "members": [ "normalo" ] },
+        ],
+    });
+    assert_eq!(dir.apply(&gen0)?, 2);
+
+    expect![[r#"
+        root:x:0:root
+        stale:x:998:stale
+        wheel:x:999:normalo
+        normalo:x:1000:normalo
+    "#]]
+    .assert_eq(&dir.read("group")?);
+    expect![[r#"
+        root:x:0:0:::/run/current-system/sw/bin/nologin
+        stale:x:999:998:::/run/current-system/sw/bin/nologin
+        normalo:x:1000:1000::/home/normalo:/bin/bash
+    "#]]
+    .assert_eq(&dir.read("passwd")?);
+    expect![[r#"
+        root:!*:1::::::
+        stale:$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2:1::::::
+        normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+    "#]]
+    .assert_eq(&dir.read("shadow")?);
+    expect![[r#"
+        root:!::root
+        stale:!::stale
+        wheel:!::normalo
+        normalo:!::normalo
+    "#]]
+    .assert_eq(&dir.read("gshadow")?);
+
+    // Applying the same config again doesn't change anything.
+    assert_eq!(dir.apply(&gen0)?, 0);
+
+    // GEN 1: Update users and groups.
+
+    let gen1 = serde_json::json!({
+        "users": [
+            { "name": "root", "uid": 0, "home": "/root" },
+            {
+                "isNormal": true,
+                "name": "normalo",
+                "description": "Normal User",
+                "shell": "/bin/zsh",
+                // This hashes the same as the existing password.
+                "password": "hello",
+            },
+            { "name": "stale" },
+        ],
+        "groups": [
+            { "name": "wheel", "members": [ "normalo", "stale" ] },
+        ],
+    });
+    assert_eq!(dir.apply(&gen1)?, 2);
+
+    expect![[r#"
+        root:x:0:root
+        stale:x:998:stale
+        wheel:x:999:normalo,stale
+        normalo:x:1000:normalo
+    "#]]
+    .assert_eq(&dir.read("group")?);
+    expect![[r#"
+        root:x:0:0::/root:/run/current-system/sw/bin/nologin
+        stale:x:999:998:::/run/current-system/sw/bin/nologin
+        normalo:x:1000:1000:Normal User:/home/normalo:/bin/zsh
+    "#]]
+    .assert_eq(&dir.read("passwd")?);
+    expect![[r#"
+        root:!*:1::::::
+        stale:$y$j9T$2e5ARUyMfmJ0nW9ZMPFg50$EGgRGQBqq0r/fxRlIRXL86K61o/ESEsIdVZYkyQvyN2:1::::::
+        normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+    "#]]
+    .assert_eq(&dir.read("shadow")?);
+
+    // GEN 2: Lock users that are not in the config anymore.
+
+    let gen2 = serde_json::json!({
+        "users": [
+            { "name": "root", "uid": 0, "home": "/root" },
+            { "isNormal": true, "name": "normalo" },
+        ],
+        "groups": [
+            { "name": "wheel", "members": [ "normalo", "stale" ] },
+        ],
+    });
+    assert_eq!(dir.apply(&gen2)?, 2);
+
+    expect![[r#"
+        root:x:0:root
+        stale:x:998:stale
+        wheel:x:999:normalo,stale
+        normalo:x:1000:normalo
+    "#]]
+    .assert_eq(&dir.read("group")?);
+    expect![[r#"
+        root:x:0:0::/root:/run/current-system/sw/bin/nologin
+        stale:x:999:998:::/run/current-system/sw/bin/nologin
+        normalo:x:1000:1000:Normal User:/home/normalo:/bin/zsh
+    "#]]
+    .assert_eq(&dir.read("passwd")?);
+    expect![[r#"
+        root:!*:1::::::
+        stale:!*:1::::::
+        normalo:$y$j9T$BOO.gstYxWh8Lw.njfytQ/$K4sN06nBh0qFGegFS0hn5YkEOzzrr7woGHlSiUuCqS4:1::::::
+    "#]]
+    .assert_eq(&dir.read("shadow")?);
+
+    Ok(())
+}
+
+#[test]
+fn validate_and_export() -> Result<()> {
+    let dir = TestDir::new("validate-and-export")?;
+
+    assert_eq!(
+        dir.apply(&serde_json::json!({
+            "users": [ { "isNormal": true, "name": "normalo" } ],
+        }))?,
+        2
+    );
+
+    let status = Command::new(env!("CARGO_BIN_EXE_userborn"))
+        .args(["validate", "--strict"])
+        .arg(dir.path())
+        .status()?;
+    assert!(status.success());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_userborn"))
+        .arg("export")
+        .arg(dir.path())
+        .output()?;
+    assert!(output.status.success());
+    let export: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(export["users"][0]["name"], "normalo");
+    assert_eq!(export["groups"][0]["gid"], 1000);
+
+    // Invalid arguments fail.
+    let status = Command::new(env!("CARGO_BIN_EXE_userborn"))
+        .arg("--unknown")
+        .status()?;
+    assert_eq!(status.code(), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn on_change_hook() -> Result<()> {
+    let dir = TestDir::new("on-change")?;
+    let marker = dir.path().join("hook-ran");
+    let hook = format!("echo ran >> {}", marker.display());
+    let config = serde_json::json!({
+        "users": [ { "isNormal": true, "name": "normalo" } ],
+    });
+
+    assert_eq!(dir.apply_with_args(&config, &["--on-change", &hook])?, 2);
+    assert_eq!(dir.read("hook-ran")?, "ran\n");
+
+    // The hook doesn't run if nothing changed.
+    assert_eq!(dir.apply_with_args(&config, &["--on-change", &hook])?, 0);
+    assert_eq!(dir.read("hook-ran")?, "ran\n");
+
+    // A failing hook doesn't undo the changes.
+    let config = serde_json::json!({
+        "users": [ { "isNormal": true, "name": "normalo" }, { "name": "other" } ],
+    });
+    assert_eq!(dir.apply_with_args(&config, &["--on-change", "exit 1"])?, 2);
+    assert!(dir.read("passwd")?.contains("other"));
+
+    Ok(())
+}
+
+#[test]
+fn check_shells() -> Result<()> {
+    let dir = TestDir::new("check-shells")?;
+    let bin = dir.path().join("bin");
+    fs::create_dir_all(dir.path().join("etc"))?;
+    fs::create_dir_all(&bin)?;
+    fs::write(bin.join("sh"), "")?;
+    fs::set_permissions(bin.join("sh"), fs::Permissions::from_mode(0o755))?;
+    fs::write(bin.join("data"), "")?;
+
+    let config_path = dir.path().join("config.json");
+    fs::write(
+        &config_path,
+        serde_json::to_string(&serde_json::json!({
+            "users": [
+                { "isNormal": true, "name": "present", "shell": "/bin/sh" },
+                { "isNormal": true, "name": "missing", "shell": "/bin/missing" },
+                { "isNormal": true, "name": "data", "shell": "/bin/data" },
+            ],
+        }))?,
+    )?;
+    let output = Command::new(env!("CARGO_BIN_EXE_userborn"))
+        .arg("--check-shells")
+        .arg("--root")
+        .arg(dir.path())
+        .arg(&config_path)
+        .arg("/etc")
+        .env("USERBORN_NO_LOGIN_PATH", NO_LOGIN_PATH)
+        .output()?;
+    assert!(output.status.success());
+
+    // The check is only advisory, so the users are created anyway.
+    assert_eq!(dir.read("etc/passwd")?.lines().count(), 3);
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(!stderr.contains("of user present"));
+    assert!(stderr.contains(&format!(
+        "The shell {} of user missing doesn't exist.",
+        bin.join("missing").display()
+    )));
+    assert!(stderr.contains(&format!(
+        "The shell {} of user data is not executable.",
+        bin.join("data").display()
+    )));
+
+    Ok(())
+}
+
+#[test]
+fn dry_run() -> Result<()> {
+    let dir = TestDir::new("dry-run")?;
+    assert_eq!(
+        dir.apply(&serde_json::json!({
+            "users": [ { "isNormal": true, "name": "normalo", "uid": 1000 } ],
+        }))?,
+        2
+    );
+    let passwd = dir.read("passwd")?;
+
+    // Changes are only reported.
+    let config = serde_json::json!({
+        "users": [
+            { "isNormal": true, "name": "normalo", "uid": 1000 },
+            { "isNormal": true, "name": "other" },
+        ],
+    });
+    assert_eq!(dir.apply_with_args(&config, &["--dry-run"])?, 2);
+    assert_eq!(dir.read("passwd")?, passwd);
+
+    // Requesting a UID that is already held by someone else fails.
+    let config = serde_json::json!({
+        "users": [
+            { "isNormal": true, "name": "normalo", "uid": 1000 },
+            { "isNormal": true, "name": "other", "uid": 1000 },
+        ],
+    });
+    assert_eq!(dir.apply_with_args(&config, &["--dry-run"])?, 1);
+    assert_eq!(dir.read("passwd")?, passwd);
+
+    Ok(())
+}
+
+#[test]
+fn check() -> Result<()> {
+    let dir = TestDir::new("check")?;
+    let config = serde_json::json!({
+        "users": [ { "isNormal": true, "name": "normalo", "password": "hello" } ],
+    });
+    let check = || -> Result<(Option<i32>, String)> {
+        fs::write(
+            dir.path().join("config.json"),
+            serde_json::to_string(&config)?,
+        )?;
+        let output = Command::new(env!("CARGO_BIN_EXE_userborn"))
+            .arg("--check")
+            .arg(dir.path().join("config.json"))
+            .arg(dir.path())
+            .env("USERBORN_NO_LOGIN_PATH", NO_LOGIN_PATH)
+            .output()?;
+        Ok((output.status.code(), String::from_utf8(output.stdout)?))
+    };
+
+    let (code, stdout) = check()?;
+    assert_eq!(code, Some(2));
+    assert!(stdout.starts_with("Would change "), "{stdout}");
+    assert!(!dir.path().join("passwd").exists());
+
+    assert_eq!(dir.apply(&config)?, 2);
+    // The plaintext password hashes the same, so shadow matches exactly.
+    assert_eq!(
+        check()?,
+        (Some(0), "The password databases are up to date.\n".into())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_shadow() -> Result<()> {
+    let dir = TestDir::new("no-shadow")?;
+    let config = serde_json::json!({
+        "users": [ { "isNormal": true, "name": "normalo", "password": "hello" } ],
+        "groups": [ { "name": "wheel", "members": [ "normalo" ], "password": "hello" } ],
+    });
+    assert_eq!(dir.apply_with_args(&config, &["--no-shadow"])?, 2);
+
+    expect![[r#"
+        wheel:x:999:normalo
+        normalo:x:1000:normalo
+    "#]]
+    .assert_eq(&dir.read("group")?);
+    assert!(!dir.path().join("shadow").exists());
+    assert!(!dir.path().join("gshadow").exists());
+
+    // Nothing changes when the same config is applied again.
+    assert_eq!(dir.apply_with_args(&config, &["--no-shadow"])?, 0);
+    assert!(!dir.path().join("gshadow").exists());
+
+    Ok(())
+}